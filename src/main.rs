@@ -1,17 +1,19 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::{mpsc, Semaphore};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
-use tracing::{error, info, warn};
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+use tokio_tungstenite::{connect_async, connect_async_tls_with_config, tungstenite::Message};
+use tracing::{debug, error, info, warn};
 
 static RUNNING: AtomicBool = AtomicBool::new(true);
 static TOTAL_REQUESTS: AtomicU64 = AtomicU64::new(0);
+static CONFIG_FINGERPRINT: std::sync::OnceLock<String> = std::sync::OnceLock::new();
 
 #[derive(Parser, Debug)]
 #[command(name = "pin-clientd")]
@@ -26,6 +28,56 @@ struct Args {
 
     #[arg(short = 'n', long = "threads", default_value = "1", help = "Number of concurrent inference threads")]
     threads: usize,
+
+    #[arg(short, long, help = "Config profile to apply (from \"profiles\" or config.<name>.json)")]
+    profile: Option<String>,
+
+    #[arg(long, value_name = "FILE", help = "Log a per-request timing breakdown; with FILE, also write it as a Chrome trace")]
+    profile_requests: Option<Option<PathBuf>>,
+
+    #[arg(long, help = "Exit instead of retrying when the server URL is clearly misconfigured at startup")]
+    fail_fast: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Load-test a node's backend and report throughput and latency percentiles
+    Bench {
+        #[arg(long, help = "Node alias to benchmark [default: first node]")]
+        node: Option<String>,
+
+        #[arg(long, help = "Model to benchmark [default: interviewModel or first discovered model]")]
+        model: Option<String>,
+
+        #[arg(long, default_value = "4", help = "Concurrent in-flight requests")]
+        concurrency: usize,
+
+        #[arg(long, default_value = "30", help = "Test duration in seconds")]
+        duration: u64,
+
+        #[arg(long, default_value = "256", help = "Approximate prompt size in words")]
+        prompt_size: usize,
+    },
+    /// Re-run requests from the audit log against the local backends and diff the results
+    Replay {
+        #[arg(long, help = "Audit log to read [default: auditLog from config]")]
+        log: Option<PathBuf>,
+
+        #[arg(long, help = "Node alias to replay against [default: the node that served each request]")]
+        node: Option<String>,
+
+        #[arg(long, help = "Only replay requests for this model")]
+        model: Option<String>,
+
+        #[arg(long, help = "Only replay requests logged at or after this RFC 3339 time")]
+        since: Option<String>,
+
+        #[arg(long, help = "Only replay requests logged before this RFC 3339 time")]
+        until: Option<String>,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,778 +92,7508 @@ struct NodeConfig {
     price_per_thousand_tokens: f64,
     #[serde(default)]
     interview_model: Option<String>,
+    #[serde(default)]
+    interview_max_tokens: Option<u32>,
+    #[serde(default)]
+    capacity_control: Option<CapacityControlConfig>,
+    #[serde(default)]
+    backend_command: Option<Vec<String>>,
+    #[serde(default)]
+    model_variants: std::collections::HashMap<String, Vec<ModelVariant>>,
+    #[serde(default)]
+    stream: StreamConfig,
+    #[serde(default)]
+    system_prompt: Option<String>,
+    #[serde(default)]
+    system_prompt_templates: std::collections::HashMap<String, String>,
+    #[serde(default)]
+    prompt_templates: std::collections::HashMap<String, PromptTemplate>,
+    #[serde(default)]
+    logprobs: Option<bool>,
+    #[serde(default)]
+    auto_disable: Option<AutoDisableConfig>,
+    #[serde(default)]
+    accept: Option<AcceptConfig>,
+    #[serde(default)]
+    warmup: Option<WarmupConfig>,
+    #[serde(default = "default_true")]
+    structured_output: bool,
+    #[serde(default)]
+    tls_server_name: Option<String>,
+    #[serde(default)]
+    content_type: Option<String>,
+    #[serde(default)]
+    pre_prompt_hook: Option<PromptHookConfig>,
+    #[serde(default)]
+    connection_pool: ConnectionPoolConfig,
+    #[serde(default)]
+    discovery_interval_secs: Option<u64>,
+    #[serde(default)]
+    fallback_model: std::collections::HashMap<String, String>,
+    #[serde(default)]
+    strip_reasoning: Option<ReasoningConfig>,
 }
 
-fn default_price() -> f64 {
-    0.001
-}
+impl NodeConfig {
+    /// OpenAI-compatible servers return logprobs; Ollama only does from
+    /// 0.12.11 on, so it has to be enabled per node.
+    fn supports_logprobs(&self) -> bool {
+        self.logprobs.unwrap_or(self.api_mode == "openai")
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct Config {
-    client_id: String,
-    api_secret: String,
-    nodes: Vec<NodeConfig>,
-    #[serde(default)]
-    payout_address: Option<String>,
-    #[serde(default = "default_server_url")]
-    server_url: String,
-    #[serde(default = "default_reconnect_delay")]
-    reconnect_delay_secs: u64,
-}
+    /// Whether the `accept` filter lets requests for `model` through. Models
+    /// it declines are not advertised either.
+    fn accepts_model(&self, model: &str) -> bool {
+        self.accept.as_ref().is_none_or(|a| a.models.is_empty() || a.models.iter().any(|m| m == model))
+    }
 
-fn default_server_url() -> String {
-    "wss://aiassist.net/api/v1/pin/ws".to_string()
+    /// The URI requests are sent to. With `tlsServerName`, its host is
+    /// replaced by that name, which the HTTP client resolves back to the
+    /// configured address, so TLS verifies the certificate against the name.
+    fn backend_uri(&self) -> String {
+        let Some(ref name) = self.tls_server_name else {
+            return self.inference_uri.clone();
+        };
+        let Ok(mut url) = reqwest::Url::parse(&self.inference_uri) else {
+            return self.inference_uri.clone();
+        };
+        if url.set_host(Some(name)).is_err() {
+            return self.inference_uri.clone();
+        }
+        url.as_str().trim_end_matches('/').to_string()
+    }
+
+    /// The configured address `tlsServerName` resolves to.
+    fn backend_addrs(&self) -> Result<Vec<std::net::SocketAddr>, String> {
+        use std::net::ToSocketAddrs;
+
+        let url = reqwest::Url::parse(&self.inference_uri).map_err(|e| format!("invalid inferenceUri: {}", e))?;
+        let host = url.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+        let port = url.port_or_known_default().unwrap_or(443);
+        (host, port)
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect())
+            .map_err(|e| format!("could not resolve {}: {}", host, e))
+    }
 }
 
-fn default_reconnect_delay() -> u64 {
-    5
+/// Chunk coalescing and size limits for streamed responses. A buffered chunk
+/// is forwarded once it holds `flushTokens` deltas or `bufferBytes` bytes, or
+/// (when set) `flushIntervalMs` has passed since the last flush.
+/// `tokenTimings` adds inter-token latency statistics to the response, and
+/// `includeUsage` asks OpenAI-mode backends for usage at the end of a stream.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct StreamConfig {
+    flush_tokens: usize,
+    flush_interval_ms: u64,
+    buffer_bytes: usize,
+    max_response_bytes: Option<usize>,
+    token_timings: bool,
+    include_usage: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[allow(non_camel_case_types)]
-enum ServerMessage {
-    AUTH_SUCCESS { operator_id: String, node_id: Option<String>, message: String },
-    ERROR { message: String },
-    PING,
-    HEARTBEAT_ACK,
-    MODEL_LIST_ACK,
-    REGISTER_NODE_ACK { node_id: String, alias: String, models: Vec<String>, created: bool, message: String },
-    UPDATE_WALLET_ACK { success: bool, message: String },
-    INFERENCE_REQUEST { request_id: String, payload: InferencePayload },
-    INTERVIEW_REQUEST { interview_id: String, node_id: Option<String>, model: String, prompts: Vec<InterviewPrompt>, timeout_ms: u32 },
-    INTERVIEW_COMPLETE { interview_id: String, node_id: Option<String>, tier: String, accuracy: f32, tokens_per_sec: f32, reason: String },
+impl Default for StreamConfig {
+    fn default() -> Self {
+        StreamConfig {
+            flush_tokens: 1,
+            flush_interval_ms: 0,
+            buffer_bytes: 16 * 1024,
+            max_response_bytes: None,
+            token_timings: false,
+            include_usage: true,
+        }
+    }
 }
 
+/// Bounds for duplicate request detection. Up to `maxInFlight` running
+/// request ids are tracked, and successful responses are kept for
+/// `memoTtlSecs` (at most `memoMaxEntries` of them) so a redelivered request
+/// is answered from the memo instead of running inference twice.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct InterviewPrompt {
-    id: String,
-    prompt: String,
-    max_tokens: u32,
+#[serde(rename_all = "camelCase", default)]
+struct DedupConfig {
+    max_in_flight: usize,
+    memo_ttl_secs: u64,
+    memo_max_entries: usize,
 }
 
-#[derive(Debug, Serialize)]
-struct InterviewResult {
-    #[serde(rename = "type")]
-    msg_type: String,
-    interview_id: String,
-    model: String,
-    results: Vec<PromptResult>,
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig {
+            max_in_flight: 4096,
+            memo_ttl_secs: 300,
+            memo_max_entries: 1024,
+        }
+    }
 }
 
-#[derive(Debug, Serialize)]
-struct PromptResult {
-    prompt_id: String,
-    response: String,
-    ttft_ms: u32,
-    total_ms: u32,
-    tokens_generated: u32,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    error: Option<String>,
+/// Application-level compression of large responses, for uplinks where
+/// permessage-deflate isn't negotiated. A response whose `result` JSON is
+/// over `thresholdBytes` is gzipped and sent as a `COMPRESSED_RESPONSE`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct ResponseCompressionConfig {
+    enabled: bool,
+    threshold_bytes: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct InferencePayload {
-    model: String,
-    messages: Vec<ChatMessage>,
-    #[serde(default)]
-    stream: bool,
+impl Default for ResponseCompressionConfig {
+    fn default() -> Self {
+        ResponseCompressionConfig {
+            enabled: false,
+            threshold_bytes: 32 * 1024,
+        }
+    }
 }
 
+/// Single-flight coalescing of identical concurrent requests: a request
+/// whose node, model, messages and options match one that is already
+/// running waits for that one's result instead of calling the backend.
+/// `usage` decides which responses report the tokens: `all` of them, or
+/// only the `first` (the others report zero).
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct ChatMessage {
-    role: String,
-    content: String,
+#[serde(rename_all = "camelCase", default)]
+struct CoalesceConfig {
+    enabled: bool,
+    usage: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct AuthMessage {
-    #[serde(rename = "type")]
-    msg_type: String,
-    client_id: String,
-    timestamp: String,
-    signature: String,
+impl Default for CoalesceConfig {
+    fn default() -> Self {
+        CoalesceConfig {
+            enabled: false,
+            usage: "all".to_string(),
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ClientMessage {
-    #[serde(rename = "type")]
-    msg_type: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    request_id: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    result: Option<serde_json::Value>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    error: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    models: Option<Vec<String>>,
+/// Substitution of a model the chosen node doesn't serve. The node's
+/// `fallbackModel` entry for the requested model is tried first, then
+/// `defaultModel`; either is only used if the node serves it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct ModelFallbackConfig {
+    enabled: bool,
+    default_model: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
-struct RegisterNodeMessage {
-    #[serde(rename = "type")]
-    msg_type: String,
-    alias: String,
-    models: Vec<String>,
-    capacity: u32,
-    region: String,
-    #[serde(rename = "pricePerThousandTokens")]
-    price_per_thousand_tokens: f64,
-    #[serde(rename = "interviewModel", skip_serializing_if = "Option::is_none")]
-    interview_model: Option<String>,
+/// Periodic expiry check of the certificates of HTTPS backends. A warning
+/// is logged once a certificate is within `warnDays` of expiring.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct CertExpiryConfig {
+    warn_days: u32,
+    interval_secs: u64,
 }
 
-#[derive(Debug, Serialize)]
-struct UpdateWalletMessage {
-    #[serde(rename = "type")]
-    msg_type: String,
-    payout_address: String,
+impl Default for CertExpiryConfig {
+    fn default() -> Self {
+        CertExpiryConfig {
+            warn_days: 14,
+            interval_secs: 6 * 3600,
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct OllamaChatRequest {
-    model: String,
-    messages: Vec<ChatMessage>,
-    stream: Option<bool>,
+/// Removal of chain-of-thought blocks, e.g. `<think>...</think>`, from a
+/// node's completions. `tokenPolicy` decides whether the completion token
+/// count stays as the backend reported it (`reported`) or has an estimate of
+/// the stripped tokens taken off (`estimate`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct ReasoningConfig {
+    tags: Vec<String>,
+    token_policy: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct OllamaChatResponse {
-    model: String,
-    message: ChatMessage,
-    done: bool,
-    #[serde(default)]
-    prompt_eval_count: Option<u32>,
-    #[serde(default)]
-    eval_count: Option<u32>,
+impl Default for ReasoningConfig {
+    fn default() -> Self {
+        ReasoningConfig {
+            tags: vec!["think".to_string()],
+            token_policy: "reported".to_string(),
+        }
+    }
 }
 
+/// Load-based admission control for shared machines. While the 1-minute
+/// load average is above `maxLoadAverage`, or more than `maxMemoryPercent`
+/// of memory is in use, new requests are rejected as `overloaded`. Load is
+/// sampled every `intervalSecs`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct OpenAIChoice {
-    index: u32,
-    message: ChatMessage,
-    finish_reason: Option<String>,
+#[serde(rename_all = "camelCase", default)]
+struct LoadSheddingConfig {
+    max_load_average: Option<f64>,
+    max_memory_percent: Option<f64>,
+    interval_secs: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct OpenAIUsage {
-    prompt_tokens: u32,
-    completion_tokens: u32,
-    total_tokens: u32,
+impl Default for LoadSheddingConfig {
+    fn default() -> Self {
+        LoadSheddingConfig {
+            max_load_average: None,
+            max_memory_percent: None,
+            interval_secs: 5,
+        }
+    }
 }
 
+/// Reconnect behavior by the close code of a server-initiated close. Codes
+/// in `reconnect` reconnect after `reconnectSecs` (e.g. a server restart),
+/// codes in `backoff` after `backoffSecs` (server overloaded), and codes in
+/// `stop` shut the daemon down. Other codes use `reconnectDelaySecs`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct OpenAIResponse {
-    choices: Vec<OpenAIChoice>,
-    #[serde(default)]
-    usage: Option<OpenAIUsage>,
-    model: String,
+#[serde(rename_all = "camelCase", default)]
+struct CloseCodeConfig {
+    reconnect: Vec<u16>,
+    reconnect_secs: u64,
+    backoff: Vec<u16>,
+    backoff_secs: u64,
+    stop: Vec<u16>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct OllamaModelsResponse {
-    models: Vec<OllamaModel>,
+impl Default for CloseCodeConfig {
+    fn default() -> Self {
+        CloseCodeConfig {
+            reconnect: vec![1001, 1012],
+            reconnect_secs: 1,
+            backoff: vec![1013],
+            backoff_secs: 60,
+            stop: vec![1008],
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct OllamaModel {
-    name: String,
+enum CloseAction {
+    Reconnect(u64),
+    Stop,
 }
 
-fn compute_signature(client_id: &str, timestamp: &str, api_secret: &str) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(api_secret.as_bytes());
-    let secret_hash = hex::encode(hasher.finalize());
+impl CloseCodeConfig {
+    fn action(&self, code: u16) -> Option<CloseAction> {
+        if self.stop.contains(&code) {
+            Some(CloseAction::Stop)
+        } else if self.backoff.contains(&code) {
+            Some(CloseAction::Reconnect(self.backoff_secs))
+        } else if self.reconnect.contains(&code) {
+            Some(CloseAction::Reconnect(self.reconnect_secs))
+        } else {
+            None
+        }
+    }
+}
 
-    let mut sig_hasher = Sha256::new();
-    sig_hasher.update(format!("{}{}{}", client_id, timestamp, secret_hash).as_bytes());
-    hex::encode(sig_hasher.finalize())
+/// Handling of a payout address the server rejects. The update is resent
+/// up to `retries` times, `retryDelaySecs` apart. If it is still rejected
+/// after that, the failure is logged as an error and posted to `webhookUrl`,
+/// and with `requireValidWallet` inference requests are refused until an
+/// update is accepted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct WalletConfig {
+    retries: u32,
+    retry_delay_secs: u64,
+    webhook_url: Option<String>,
+    require_valid_wallet: bool,
 }
 
-#[derive(Debug, Deserialize)]
-struct OpenAIModelsResponse {
-    data: Vec<OpenAIModelInfo>,
+impl Default for WalletConfig {
+    fn default() -> Self {
+        WalletConfig {
+            retries: 3,
+            retry_delay_secs: 30,
+            webhook_url: None,
+            require_valid_wallet: false,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct OpenAIModelInfo {
-    id: String,
+/// A `GOING_AWAY` message sent on shutdown, listing the registered nodes so
+/// the server stops routing to them before the socket closes. `reason` is
+/// one of `GOING_AWAY_REASONS`; `expectedReturnSecs` is passed along as a
+/// hint when set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct GoingAwayConfig {
+    reason: String,
+    expected_return_secs: Option<u64>,
 }
 
-async fn get_ollama_models(base_url: &str) -> Result<Vec<String>, String> {
-    let client = reqwest::Client::new();
-    let url = format!("{}/api/tags", base_url.trim_end_matches('/'));
+impl Default for GoingAwayConfig {
+    fn default() -> Self {
+        GoingAwayConfig { reason: "shutdown".to_string(), expected_return_secs: None }
+    }
+}
 
-    let response = client
-        .get(&url)
-        .timeout(Duration::from_secs(10))
-        .send()
-        .await
-        .map_err(|e| format!("Failed to connect to Ollama: {}", e))?;
+/// Periodic `NODE_STATS` reports to the server, every `intervalSecs`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct NodeStatsConfig {
+    interval_secs: u64,
+}
 
-    let data: OllamaModelsResponse = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
+impl Default for NodeStatsConfig {
+    fn default() -> Self {
+        NodeStatsConfig { interval_secs: 60 }
+    }
+}
 
-    Ok(data.models.iter().map(|m| m.name.clone()).collect())
+/// Startup readiness wait: before connecting to the server, poll each
+/// backend's model list every `intervalSecs` until it answers or
+/// `timeoutSecs` have passed since the wait began.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct WaitForBackendConfig {
+    timeout_secs: u64,
+    interval_secs: u64,
 }
 
-async fn get_openai_models(base_url: &str) -> Result<Vec<String>, String> {
-    let client = reqwest::Client::new();
-    let url = format!("{}/v1/models", base_url.trim_end_matches('/'));
+impl Default for WaitForBackendConfig {
+    fn default() -> Self {
+        WaitForBackendConfig {
+            timeout_secs: 120,
+            interval_secs: 2,
+        }
+    }
+}
 
-    let response = client
-        .get(&url)
-        .timeout(Duration::from_secs(10))
-        .send()
-        .await
-        .map_err(|e| format!("Failed to connect to OpenAI-compatible API: {}", e))?;
+/// Limits on the HTTP connections a node's client keeps to its backend.
+/// `maxIdlePerHost` bounds the idle keep-alive pool; `maxConnections` caps
+/// how many requests use the backend at once, so no more connections than
+/// that are ever open. Both are unlimited by default.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct ConnectionPoolConfig {
+    max_idle_per_host: Option<usize>,
+    max_connections: Option<usize>,
+}
 
-    let data: OpenAIModelsResponse = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
+/// External command that rewrites a request's messages before they reach
+/// the backend. It gets the request as JSON on stdin and must print the
+/// replacement JSON on stdout within `timeoutMs`. `onFailure` decides what
+/// happens when it fails: `passthrough` uses the original messages,
+/// `reject` answers the request with an error.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PromptHookConfig {
+    command: Vec<String>,
+    #[serde(default = "default_prompt_hook_timeout_ms")]
+    timeout_ms: u64,
+    #[serde(default = "default_prompt_hook_max_output_bytes")]
+    max_output_bytes: usize,
+    #[serde(default = "default_prompt_hook_on_failure")]
+    on_failure: String,
+}
 
-    Ok(data.data.iter().map(|m| m.id.clone()).collect())
+fn default_prompt_hook_timeout_ms() -> u64 {
+    2000
 }
 
-async fn get_models(base_url: &str, api_mode: &str) -> Result<Vec<String>, String> {
-    match api_mode {
-        "openai" => get_openai_models(base_url).await,
-        _ => get_ollama_models(base_url).await,
-    }
+fn default_prompt_hook_max_output_bytes() -> usize {
+    1024 * 1024
 }
 
-#[derive(Debug, Serialize)]
-struct OpenAIChatRequest {
-    model: String,
-    messages: Vec<ChatMessage>,
+fn default_prompt_hook_on_failure() -> String {
+    "passthrough".to_string()
+}
+
+/// Requests sent to a node's backend before it is first registered. The
+/// first one absorbs the cold start (model load); the median time to first
+/// token of the rest is reported to the server as the steady-state TTFT.
+/// With `incremental`, every model is warmed in turn and advertised as soon
+/// as it is warm, instead of registration waiting for a single model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WarmupConfig {
+    #[serde(default = "default_warmup_requests")]
+    requests: u32,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    incremental: bool,
+}
+
+fn default_warmup_requests() -> u32 {
+    3
+}
+
+/// Which requests a node takes. With `models` set only those requested
+/// models are served, and with `matchRegion` requests tagged for another
+/// region are declined. Untagged requests pass the region check.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct AcceptConfig {
+    models: Vec<String>,
+    match_region: bool,
+}
+
+/// Takes a node out of rotation when too many of its recent requests fail.
+/// Once at least `minRequests` of the last `window` requests are known and
+/// the failed share reaches `failureRate`, the node is deregistered for
+/// `cooldownSecs` (or until re-enabled through the admin endpoint).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AutoDisableConfig {
+    #[serde(default = "default_auto_disable_window")]
+    window: usize,
+    #[serde(default = "default_auto_disable_min_requests")]
+    min_requests: usize,
+    #[serde(default = "default_auto_disable_failure_rate")]
+    failure_rate: f64,
+    #[serde(default = "default_auto_disable_cooldown_secs")]
+    cooldown_secs: u64,
+    #[serde(default)]
+    webhook_url: Option<String>,
+}
+
+fn default_auto_disable_window() -> usize {
+    20
+}
+
+fn default_auto_disable_min_requests() -> usize {
+    10
+}
+
+fn default_auto_disable_failure_rate() -> f64 {
+    0.5
+}
+
+fn default_auto_disable_cooldown_secs() -> u64 {
+    300
+}
+
+/// TLS restrictions applied to the server connection and HTTPS backends.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TlsConfig {
+    #[serde(default = "default_tls_min_version")]
+    min_version: String,
+    #[serde(default)]
+    cipher_suites: Vec<String>,
+}
+
+fn default_tls_min_version() -> String {
+    "1.2".to_string()
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            min_version: default_tls_min_version(),
+            cipher_suites: Vec::new(),
+        }
+    }
+}
+
+/// Restricts which PIN server the daemon will talk to. `allowedHosts` are
+/// checked against `serverUrl` at startup (`*.example.com` matches any
+/// subdomain); `certFingerprints` are SHA-256 hashes of the server's leaf
+/// certificate, checked after the handshake and before AUTH is sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerPinningConfig {
+    #[serde(default)]
+    allowed_hosts: Vec<String>,
+    #[serde(default)]
+    cert_fingerprints: Vec<String>,
+}
+
+impl ServerPinningConfig {
+    fn host_allowed(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.allowed_hosts.is_empty()
+            || self.allowed_hosts.iter().map(|h| h.to_ascii_lowercase()).any(|pattern| match pattern.strip_prefix("*.") {
+                Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.') && sub.len() > 1),
+                None => host == pattern,
+            })
+    }
+
+    fn fingerprint_allowed(&self, fingerprint: &str) -> bool {
+        self.cert_fingerprints.iter().any(|f| normalize_fingerprint(f) == fingerprint)
+    }
+}
+
+/// Accepts fingerprints as printed by `openssl x509 -fingerprint -sha256`
+/// (colon-separated, upper case) as well as plain hex.
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.replace(':', "").to_ascii_lowercase()
+}
+
+/// A local model that serves part of the traffic for a canonical model name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ModelVariant {
+    model: String,
+    weight: u32,
+}
+
+fn default_price() -> f64 {
+    0.001
+}
+
+/// Latency-driven capacity controller for a node. When the observed latency
+/// percentile of any model rises above `latencyHighMs` the advertised capacity
+/// is stepped down (never below `minCapacity`); once it falls under
+/// `latencyLowMs` it is stepped back up towards the configured `capacity`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CapacityControlConfig {
+    #[serde(default = "default_latency_high_ms")]
+    latency_high_ms: u64,
+    #[serde(default = "default_latency_low_ms")]
+    latency_low_ms: u64,
+    #[serde(default = "default_latency_percentile")]
+    percentile: f64,
+    #[serde(default = "default_min_capacity")]
+    min_capacity: u32,
+    #[serde(default = "default_capacity_step")]
+    step: u32,
+    #[serde(default = "default_latency_window")]
+    window: usize,
+}
+
+fn default_latency_high_ms() -> u64 {
+    20000
+}
+
+fn default_latency_low_ms() -> u64 {
+    8000
+}
+
+fn default_latency_percentile() -> f64 {
+    90.0
+}
+
+fn default_min_capacity() -> u32 {
+    1
+}
+
+fn default_capacity_step() -> u32 {
+    1
+}
+
+fn default_latency_window() -> usize {
+    50
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Config {
+    client_id: String,
+    api_secret: String,
+    nodes: Vec<NodeConfig>,
+    #[serde(default)]
+    payout_address: Option<String>,
+    #[serde(default)]
+    wallet: WalletConfig,
+    #[serde(default = "default_server_url")]
+    server_url: String,
+    #[serde(default = "default_reconnect_delay")]
+    reconnect_delay_secs: u64,
+    #[serde(default)]
+    close_codes: Option<CloseCodeConfig>,
+    #[serde(default)]
+    going_away: Option<GoingAwayConfig>,
+    #[serde(default)]
+    startup_delay_secs: u64,
+    #[serde(default)]
+    wait_for_backend: Option<WaitForBackendConfig>,
+    #[serde(default)]
+    stream_responses: bool,
+    #[serde(default = "default_max_task_lifetime")]
+    max_task_lifetime_secs: u64,
+    #[serde(default)]
+    ordered_responses: bool,
+    #[serde(default = "default_reorder_window")]
+    reorder_window: usize,
+    #[serde(default = "default_reorder_timeout_ms")]
+    reorder_timeout_ms: u64,
+    #[serde(default = "default_true")]
+    backend_decompression: bool,
+    #[serde(default)]
+    variant_seed: Option<u64>,
+    #[serde(default)]
+    model_rate_limits: std::collections::HashMap<String, u32>,
+    #[serde(default)]
+    idle_warning_secs: Option<u64>,
+    #[serde(default)]
+    idle_reregister: bool,
+    #[serde(default = "default_max_concurrent_interviews")]
+    max_concurrent_interviews: usize,
+    #[serde(default)]
+    dedup: DedupConfig,
+    #[serde(default)]
+    response_compression: ResponseCompressionConfig,
+    #[serde(default = "default_empty_response_policy")]
+    empty_response_policy: String,
+    #[serde(default)]
+    model_fallback: ModelFallbackConfig,
+    #[serde(default)]
+    coalesce: CoalesceConfig,
+    #[serde(default)]
+    backend_cert_check: Option<CertExpiryConfig>,
+    #[serde(default)]
+    load_shedding: Option<LoadSheddingConfig>,
+    #[serde(default)]
+    audit_log: Option<PathBuf>,
+    #[serde(default)]
+    state_file: Option<PathBuf>,
+    #[serde(default)]
+    report_uptime: bool,
+    #[serde(default)]
+    heartbeat_telemetry: bool,
+    #[serde(default)]
+    node_stats: Option<NodeStatsConfig>,
+    #[serde(default)]
+    tls: Option<TlsConfig>,
+    #[serde(default)]
+    server_pinning: Option<ServerPinningConfig>,
+    #[serde(default)]
+    admin_port: Option<u16>,
+    #[serde(default = "default_admin_bind")]
+    admin_bind: String,
+}
+
+/// Well above the 120s backend request timeout; only a stuck task hits it.
+fn default_max_task_lifetime() -> u64 {
+    600
+}
+
+/// Interviews measure speed, so by default they run one at a time to keep
+/// them from skewing each other's numbers.
+fn default_max_concurrent_interviews() -> usize {
+    1
+}
+
+/// Empty completions are still forwarded by default, but marked so the
+/// server can tell them apart from real answers.
+fn default_empty_response_policy() -> String {
+    "flag".to_string()
+}
+
+/// Far more than one backend can serve at once; a larger value is a typo.
+const MAX_NODE_CAPACITY: u32 = 1024;
+
+const EMPTY_RESPONSE_POLICIES: &[&str] = &["flag", "error", "retry"];
+
+const COALESCE_USAGE_POLICIES: &[&str] = &["all", "first"];
+
+const GOING_AWAY_REASONS: &[&str] = &["shutdown", "maintenance"];
+
+fn default_reorder_window() -> usize {
+    32
+}
+
+fn default_reorder_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_admin_bind() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_server_url() -> String {
+    "wss://aiassist.net/api/v1/pin/ws".to_string()
+}
+
+fn default_reconnect_delay() -> u64 {
+    5
+}
+
+impl Config {
+    fn validate(&self) -> Result<(), String> {
+        if self.nodes.is_empty() {
+            return Err("No nodes configured! Add at least one node to the 'nodes' array.".to_string());
+        }
+
+        if let Some((model, _)) = self.model_rate_limits.iter().find(|(_, &rpm)| rpm == 0) {
+            return Err(format!("modelRateLimits.{} must be at least 1 request/min", model));
+        }
+
+        if self.idle_warning_secs == Some(0) {
+            return Err("idleWarningSecs must be positive".to_string());
+        }
+
+        if self.max_task_lifetime_secs == 0 {
+            return Err("maxTaskLifetimeSecs must be positive".to_string());
+        }
+
+        if self.max_concurrent_interviews == 0 {
+            return Err("maxConcurrentInterviews must be at least 1".to_string());
+        }
+
+        if self.ordered_responses && self.reorder_window == 0 {
+            return Err("reorderWindow must be positive when orderedResponses is enabled".to_string());
+        }
+
+        if let Some(ref ls) = self.load_shedding {
+            if ls.max_load_average.is_none() && ls.max_memory_percent.is_none() {
+                return Err("loadShedding needs maxLoadAverage, maxMemoryPercent or both".to_string());
+            }
+            if ls.max_load_average.is_some_and(|l| !(l.is_finite() && l > 0.0)) {
+                return Err("loadShedding.maxLoadAverage must be a positive number".to_string());
+            }
+            if ls.max_memory_percent.is_some_and(|m| !(m > 0.0 && m <= 100.0)) {
+                return Err("loadShedding.maxMemoryPercent must be in (0, 100]".to_string());
+            }
+            if ls.interval_secs == 0 {
+                return Err("loadShedding.intervalSecs must be at least 1".to_string());
+            }
+        }
+        if self.wallet.require_valid_wallet && self.payout_address.as_ref().is_none_or(|a| a.is_empty()) {
+            return Err("wallet.requireValidWallet needs a payoutAddress".to_string());
+        }
+        if self.node_stats.as_ref().is_some_and(|n| n.interval_secs == 0) {
+            return Err("nodeStats.intervalSecs must be at least 1".to_string());
+        }
+        if self.wait_for_backend.as_ref().is_some_and(|w| w.interval_secs == 0) {
+            return Err("waitForBackend.intervalSecs must be at least 1".to_string());
+        }
+        if self.backend_cert_check.as_ref().is_some_and(|c| c.interval_secs == 0) {
+            return Err("backendCertCheck.intervalSecs must be positive".to_string());
+        }
+
+        if let Some(ref going_away) = self.going_away {
+            if !GOING_AWAY_REASONS.contains(&going_away.reason.as_str()) {
+                return Err(format!("goingAway.reason must be one of {} (got \"{}\")", GOING_AWAY_REASONS.join(", "), going_away.reason));
+            }
+        }
+
+        if !COALESCE_USAGE_POLICIES.contains(&self.coalesce.usage.as_str()) {
+            return Err(format!("coalesce.usage must be one of {} (got \"{}\")", COALESCE_USAGE_POLICIES.join(", "), self.coalesce.usage));
+        }
+
+        if !EMPTY_RESPONSE_POLICIES.contains(&self.empty_response_policy.as_str()) {
+            return Err(format!("emptyResponsePolicy must be one of {} (got \"{}\")", EMPTY_RESPONSE_POLICIES.join(", "), self.empty_response_policy));
+        }
+
+        if let Some(ref pinning) = self.server_pinning {
+            let url = reqwest::Url::parse(&self.server_url).map_err(|e| format!("serverUrl is not a valid URL: {}", e))?;
+            let host = url.host_str().unwrap_or_default();
+            if !pinning.host_allowed(host) {
+                return Err(format!("SECURITY: serverUrl host {} is not in serverPinning.allowedHosts ({}) - refusing to connect", host, pinning.allowed_hosts.join(", ")));
+            }
+            if !pinning.cert_fingerprints.is_empty() && url.scheme() != "wss" {
+                return Err("serverPinning.certFingerprints requires a wss:// serverUrl".to_string());
+            }
+            if let Some(bad) = pinning.cert_fingerprints.iter().find(|f| {
+                let f = normalize_fingerprint(f);
+                f.len() != 64 || !f.chars().all(|c| c.is_ascii_hexdigit())
+            }) {
+                return Err(format!("serverPinning.certFingerprints: {} is not a SHA-256 fingerprint (64 hex digits)", bad));
+            }
+        }
+
+        for node in &self.nodes {
+            if node.capacity > MAX_NODE_CAPACITY {
+                return Err(format!("Node {}: capacity {} is above the maximum of {} concurrent requests", node.alias, node.capacity, MAX_NODE_CAPACITY));
+            }
+            if !node.price_per_thousand_tokens.is_finite() || node.price_per_thousand_tokens < 0.0 {
+                return Err(format!("Node {}: pricePerThousandTokens must be a finite, non-negative number (got {})", node.alias, node.price_per_thousand_tokens));
+            }
+            for (canonical, variants) in &node.model_variants {
+                if variants.is_empty() || variants.iter().all(|v| v.weight == 0) {
+                    return Err(format!("Node {}: modelVariants.{} needs at least one variant with a positive weight", node.alias, canonical));
+                }
+            }
+            if node.stream.flush_tokens == 0 || node.stream.buffer_bytes == 0 {
+                return Err(format!("Node {}: stream.flushTokens and stream.bufferBytes must be positive", node.alias));
+            }
+            for template in node.system_prompt.iter().chain(node.system_prompt_templates.values()) {
+                if let Some(name) = template_variables(template).into_iter().find(|v| !SYSTEM_PROMPT_VARIABLES.contains(v)) {
+                    return Err(format!("Node {}: unknown system prompt variable {{{{{}}}}} (available: {})", node.alias, name, SYSTEM_PROMPT_VARIABLES.join(", ")));
+                }
+            }
+            for (model, template) in &node.prompt_templates {
+                if let Some(name) = template_variables(&template.template).into_iter().find(|v| !PROMPT_TEMPLATE_VARIABLES.contains(v)) {
+                    return Err(format!("Node {}: unknown variable {{{{{}}}}} in promptTemplates.{}.template (available: {})", node.alias, name, model, PROMPT_TEMPLATE_VARIABLES.join(", ")));
+                }
+                if let Some(name) = template_variables(&template.message).into_iter().find(|v| !PROMPT_MESSAGE_VARIABLES.contains(v)) {
+                    return Err(format!("Node {}: unknown variable {{{{{}}}}} in promptTemplates.{}.message (available: {})", node.alias, name, model, PROMPT_MESSAGE_VARIABLES.join(", ")));
+                }
+            }
+            if node.backend_command.as_ref().is_some_and(|c| c.is_empty()) {
+                return Err(format!("Node {}: backendCommand must not be empty", node.alias));
+            }
+            if let Some(ref name) = node.tls_server_name {
+                if !node.inference_uri.starts_with("https://") {
+                    return Err(format!("Node {}: tlsServerName requires an https:// inferenceUri", node.alias));
+                }
+                if !matches!(rustls::pki_types::ServerName::try_from(name.as_str()), Ok(rustls::pki_types::ServerName::DnsName(_))) {
+                    return Err(format!("Node {}: tlsServerName must be a DNS name (got \"{}\")", node.alias, name));
+                }
+                if let Some(other) = self.nodes.iter().find(|n| n.tls_server_name.as_ref() == Some(name) && n.inference_uri != node.inference_uri) {
+                    return Err(format!("Node {}: tlsServerName {} is already used by node {} for a different inferenceUri", node.alias, name, other.alias));
+                }
+            }
+            if let Some(ref content_type) = node.content_type {
+                if reqwest::header::HeaderValue::from_str(content_type).is_err() {
+                    return Err(format!("Node {}: contentType {:?} is not a valid header value", node.alias, content_type));
+                }
+            }
+            if node.discovery_interval_secs == Some(0) {
+                return Err(format!("Node {}: discoveryIntervalSecs must be at least 1", node.alias));
+            }
+            if node.interview_max_tokens == Some(0) {
+                return Err(format!("Node {}: interviewMaxTokens must be at least 1", node.alias));
+            }
+            if node.connection_pool.max_connections == Some(0) {
+                return Err(format!("Node {}: connectionPool.maxConnections must be at least 1", node.alias));
+            }
+            if let Some(ref reasoning) = node.strip_reasoning {
+                if reasoning.tags.is_empty() || reasoning.tags.iter().any(|t| t.is_empty() || t.contains(['<', '>', '/'])) {
+                    return Err(format!("Node {}: stripReasoning.tags must be non-empty tag names without <, > or /", node.alias));
+                }
+                if reasoning.token_policy != "reported" && reasoning.token_policy != "estimate" {
+                    return Err(format!("Node {}: stripReasoning.tokenPolicy must be \"reported\" or \"estimate\" (got \"{}\")", node.alias, reasoning.token_policy));
+                }
+            }
+            if let Some(ref hook) = node.pre_prompt_hook {
+                if hook.command.is_empty() {
+                    return Err(format!("Node {}: prePromptHook.command must not be empty", node.alias));
+                }
+                if hook.timeout_ms == 0 || hook.max_output_bytes == 0 {
+                    return Err(format!("Node {}: prePromptHook.timeoutMs and maxOutputBytes must be positive", node.alias));
+                }
+                if hook.on_failure != "passthrough" && hook.on_failure != "reject" {
+                    return Err(format!("Node {}: prePromptHook.onFailure must be \"passthrough\" or \"reject\" (got \"{}\")", node.alias, hook.on_failure));
+                }
+            }
+            if node.warmup.as_ref().is_some_and(|w| w.requests == 0) {
+                return Err(format!("Node {}: warmup.requests must be at least 1", node.alias));
+            }
+            if let Some(ref ad) = node.auto_disable {
+                if ad.window == 0 || ad.min_requests == 0 || ad.min_requests > ad.window {
+                    return Err(format!("Node {}: autoDisable.minRequests must be between 1 and window ({})", node.alias, ad.window));
+                }
+                if !(ad.failure_rate > 0.0 && ad.failure_rate <= 1.0) {
+                    return Err(format!("Node {}: autoDisable.failureRate must be in (0, 1]", node.alias));
+                }
+            }
+            if let Some(ref cc) = node.capacity_control {
+                if cc.latency_low_ms >= cc.latency_high_ms {
+                    return Err(format!("Node {}: capacityControl.latencyLowMs must be below latencyHighMs", node.alias));
+                }
+                if !(cc.percentile > 0.0 && cc.percentile <= 100.0) {
+                    return Err(format!("Node {}: capacityControl.percentile must be in (0, 100]", node.alias));
+                }
+                if cc.min_capacity == 0 || cc.min_capacity > node.capacity {
+                    return Err(format!("Node {}: capacityControl.minCapacity must be between 1 and capacity ({})", node.alias, node.capacity));
+                }
+                if cc.step == 0 || cc.window == 0 {
+                    return Err(format!("Node {}: capacityControl.step and window must be positive", node.alias));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Rolling window of request latencies (ms) for a single model.
+#[derive(Debug, Default)]
+struct LatencyWindow {
+    samples: std::collections::VecDeque<u64>,
+}
+
+impl LatencyWindow {
+    fn record(&mut self, latency_ms: u64, max_samples: usize) {
+        self.samples.push_back(latency_ms);
+        while self.samples.len() > max_samples {
+            self.samples.pop_front();
+        }
+    }
+
+    fn percentile(&self, pct: f64) -> Option<u64> {
+        let mut sorted: Vec<u64> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        percentile(&sorted, pct)
+    }
+}
+
+/// Nearest-rank percentile of an already sorted slice.
+fn percentile(sorted: &[u64], pct: f64) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = ((pct / 100.0) * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+/// Picks a variant for a request. With a seed the choice is a stable function
+/// of the request id, so replays of the same request land on the same variant.
+fn choose_variant<'a>(variants: &'a [ModelVariant], request_id: &str, seed: Option<u64>) -> &'a str {
+    use std::hash::{BuildHasher, Hasher};
+
+    let roll = match seed {
+        Some(seed) => {
+            let mut hasher = Sha256::new();
+            hasher.update(seed.to_be_bytes());
+            hasher.update(request_id.as_bytes());
+            let digest = hasher.finalize();
+            u64::from_be_bytes(digest[..8].try_into().unwrap())
+        }
+        None => {
+            let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+            hasher.write(request_id.as_bytes());
+            hasher.finish()
+        }
+    };
+
+    let total: u64 = variants.iter().map(|v| v.weight as u64).sum();
+    let mut point = roll % total.max(1);
+    for variant in variants {
+        if point < variant.weight as u64 {
+            return &variant.model;
+        }
+        point -= variant.weight as u64;
+    }
+    &variants[variants.len() - 1].model
+}
+
+/// Canonical names a node can serve through its configured variants, given
+/// the models discovered on its backend.
+fn canonical_models(node: &NodeConfig, discovered: &[String]) -> Vec<String> {
+    let mut canonical: Vec<String> = node
+        .model_variants
+        .iter()
+        .filter(|(name, variants)| {
+            !discovered.contains(name) && variants.iter().any(|v| discovered.contains(&v.model))
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+    canonical.sort();
+    canonical
+}
+
+const SYSTEM_PROMPT_VARIABLES: &[&str] = &["date", "time", "model", "node", "region"];
+const PROMPT_TEMPLATE_VARIABLES: &[&str] = &["system", "messages", "date", "time", "model", "node", "region"];
+const PROMPT_MESSAGE_VARIABLES: &[&str] = &["role", "content"];
+
+/// Renders a request's messages into a single prompt, for backends that
+/// don't apply a chat template themselves. Each non-system message is
+/// formatted with `message` into `{{messages}}`, and the system messages
+/// are joined into `{{system}}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PromptTemplate {
+    template: String,
+    #[serde(default = "default_prompt_message_format")]
+    message: String,
+}
+
+fn default_prompt_message_format() -> String {
+    "{{role}}: {{content}}\n".to_string()
+}
+
+/// Expands `{{name}}` placeholders in a single pass, so substituted values are
+/// never themselves expanded. Unknown names are left as written.
+fn render_template(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find("}}") else {
+            out.push_str(&rest[start..]);
+            return out;
+        };
+        let name = rest[start + 2..start + 2 + len].trim();
+        match vars.iter().find(|(k, _)| *k == name) {
+            Some((_, value)) => out.push_str(value),
+            None => out.push_str(&rest[start..start + 4 + len]),
+        }
+        rest = &rest[start + 4 + len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn template_variables(template: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else { break };
+        names.push(rest[start + 2..start + 2 + len].trim());
+        rest = &rest[start + 4 + len..];
+    }
+    names
+}
+
+/// What a prompt hook gets on stdin.
+#[derive(Debug, Serialize)]
+struct PromptHookInput<'a> {
+    request_id: &'a str,
+    node: &'a str,
+    model: &'a str,
+    messages: &'a [ChatMessage],
+}
+
+/// What a prompt hook prints: the messages to send instead, or a reason to
+/// refuse the request.
+#[derive(Debug, Deserialize)]
+struct PromptHookOutput {
+    #[serde(default)]
+    messages: Option<Vec<ChatMessage>>,
+    #[serde(default)]
+    reject: Option<String>,
+}
+
+enum PromptHookError {
+    /// The hook refused the request; never overridden by `onFailure`.
+    Rejected(String),
+    Failed(String),
+}
+
+async fn run_prompt_hook(hook: &PromptHookConfig, input: &PromptHookInput<'_>) -> Result<Vec<ChatMessage>, PromptHookError> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let stdin_json = serde_json::to_vec(input).map_err(|e| PromptHookError::Failed(format!("failed to encode input: {}", e)))?;
+    let mut child = tokio::process::Command::new(&hook.command[0])
+        .args(&hook.command[1..])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| PromptHookError::Failed(format!("failed to start '{}': {}", hook.command.join(" "), e)))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let stdout = child.stdout.take().expect("stdout is piped");
+
+    let run = async {
+        // A hook that never reads its input must not block us, so the write
+        // happens alongside reading the output.
+        let write = async move {
+            let _ = stdin.write_all(&stdin_json).await;
+        };
+        let mut output = Vec::new();
+        let mut limited = stdout.take(hook.max_output_bytes as u64 + 1);
+        let read = limited.read_to_end(&mut output);
+        let (_, read) = tokio::join!(write, read);
+        read.map_err(|e| format!("failed to read output: {}", e))?;
+        if output.len() > hook.max_output_bytes {
+            // Stop here; the child is killed when dropped.
+            return Err(format!("output exceeds {} bytes", hook.max_output_bytes));
+        }
+        let status = child.wait().await.map_err(|e| format!("wait failed: {}", e))?;
+        Ok::<_, String>((status, output))
+    };
+    let (status, output) = tokio::time::timeout(Duration::from_millis(hook.timeout_ms), run)
+        .await
+        .map_err(|_| PromptHookError::Failed(format!("timed out after {}ms", hook.timeout_ms)))?
+        .map_err(PromptHookError::Failed)?;
+
+    if !status.success() {
+        return Err(PromptHookError::Failed(format!("exited with {}", status)));
+    }
+    let output: PromptHookOutput = serde_json::from_slice(&output).map_err(|e| PromptHookError::Failed(format!("invalid output: {}", e)))?;
+    if let Some(reason) = output.reject {
+        return Err(PromptHookError::Rejected(reason));
+    }
+    match output.messages {
+        Some(messages) if !messages.is_empty() => Ok(messages),
+        _ => Err(PromptHookError::Failed("output has no messages".to_string())),
+    }
+}
+
+/// Values of the variables available to every template.
+fn template_context(node: &NodeConfig, requested_model: &str) -> Vec<(&'static str, String)> {
+    let now = chrono::Utc::now();
+    vec![
+        ("date", now.format("%Y-%m-%d").to_string()),
+        ("time", now.format("%H:%M UTC").to_string()),
+        ("model", requested_model.to_string()),
+        ("node", node.alias.clone()),
+        ("region", node.region.clone()),
+    ]
+}
+
+/// The system prompt for a request: the template for the requested model,
+/// then for the variant it was mapped to, then the node's `systemPrompt`.
+fn system_prompt_for(node: &NodeConfig, requested_model: &str, model: &str) -> Option<String> {
+    let template = node
+        .system_prompt_templates
+        .get(requested_model)
+        .or_else(|| node.system_prompt_templates.get(model))
+        .or(node.system_prompt.as_ref())?;
+    let context = template_context(node, requested_model);
+    let vars: Vec<(&str, &str)> = context.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    Some(render_template(template, &vars))
+}
+
+/// Puts the node's system prompt ahead of the client's own system message,
+/// in the same message, so backends that only honour one system message
+/// still see both. Without a client system message it becomes the first
+/// message.
+fn add_system_prompt(messages: &mut Vec<ChatMessage>, system_prompt: String) {
+    match messages.iter_mut().find(|m| m.role == "system") {
+        Some(client) => client.content = format!("{}\n\n{}", system_prompt, client.content),
+        None => messages.insert(0, ChatMessage { role: "system".to_string(), content: system_prompt }),
+    }
+}
+
+/// The prompt template for the requested model, or else for the variant it
+/// was mapped to.
+fn prompt_template_for<'a>(node: &'a NodeConfig, requested_model: &str, model: &str) -> Option<&'a PromptTemplate> {
+    node.prompt_templates.get(requested_model).or_else(|| node.prompt_templates.get(model))
+}
+
+/// Replaces the messages with a single user message holding the rendered
+/// prompt, so the backend counts the tokens of the prompt it actually runs.
+fn apply_prompt_template(template: &PromptTemplate, messages: &[ChatMessage], context: &[(&'static str, String)]) -> Vec<ChatMessage> {
+    let system = messages
+        .iter()
+        .filter(|m| m.role == "system")
+        .map(|m| m.content.as_str())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let turns: String = messages
+        .iter()
+        .filter(|m| m.role != "system")
+        .map(|m| render_template(&template.message, &[("role", &m.role), ("content", &m.content)]))
+        .collect();
+    let mut vars: Vec<(&str, &str)> = context.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    vars.push(("system", &system));
+    vars.push(("messages", &turns));
+    vec![ChatMessage { role: "user".to_string(), content: render_template(&template.template, &vars) }]
+}
+
+const VARIANT_REPORT_INTERVAL: Duration = Duration::from_secs(300);
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(300);
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const MODEL_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Minimum number of samples a model needs before it influences capacity.
+const CAPACITY_CONTROL_MIN_SAMPLES: usize = 5;
+const CAPACITY_CONTROL_INTERVAL: Duration = Duration::from_secs(15);
+const DEFAULT_LATENCY_WINDOW: usize = 50;
+
+/// Runtime state for a configured node, shared between the connection loop
+/// and the spawned inference tasks.
+#[derive(Debug)]
+struct NodeState {
+    models: Vec<String>,
+    effective_capacity: u32,
+    /// One window per model the backend has answered for, each holding at
+    /// most `latencyWindow` samples, so this doesn't need a `BoundedCache`.
+    latency: std::collections::HashMap<String, LatencyWindow>,
+    registered: bool,
+    backend_reachable: bool,
+    rejection: Option<String>,
+    /// Set while the node's `REGISTER_NODE` on this connection has been
+    /// neither acknowledged nor rejected.
+    awaiting_ack: bool,
+    /// `None` unless the daemon supervises this node's backend process.
+    backend_up: Option<bool>,
+    /// Recent request outcomes (`true` = failed), newest last.
+    outcomes: std::collections::VecDeque<bool>,
+    /// Set while the node is auto-disabled for a high failure rate.
+    disabled_until: Option<std::time::Instant>,
+    /// Warmup measurement, kept until the backend restarts.
+    warmup: Option<WarmupResult>,
+    /// Backend models last seen by runtime discovery, sorted.
+    discovered_models: Option<Vec<String>>,
+    /// Backend models found at the last registration, before any filtering.
+    backend_models: Vec<String>,
+    /// Models warmed by incremental warmup, kept until the backend restarts.
+    warm_models: std::collections::HashSet<String>,
+    /// Set while a warmup task is running for the node.
+    warming: bool,
+    /// Set when a full warmup task has finished and the node still has to
+    /// be registered with its result.
+    warmup_finished: bool,
+    /// Requests since the last `NODE_STATS` report.
+    period: NodePeriodStats,
+    /// Requests admitted and not yet answered.
+    in_flight: u32,
+    /// Requests answered, and how many of them failed, since the last
+    /// heartbeat.
+    beat_requests: u64,
+    beat_errors: u64,
+}
+
+/// Request totals for one node over a `NODE_STATS` reporting period.
+#[derive(Debug, Default)]
+struct NodePeriodStats {
+    requests: u64,
+    errors: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    latencies_ms: Vec<u64>,
+}
+
+impl NodePeriodStats {
+    fn report(&self, alias: &str) -> NodeStatsEntry {
+        let mut sorted = self.latencies_ms.clone();
+        sorted.sort_unstable();
+        NodeStatsEntry {
+            alias: alias.to_string(),
+            requests: self.requests,
+            errors: self.errors,
+            error_rate: (self.requests > 0).then(|| self.errors as f64 / self.requests as f64),
+            prompt_tokens: self.prompt_tokens,
+            completion_tokens: self.completion_tokens,
+            latency_avg_ms: (!sorted.is_empty()).then(|| sorted.iter().sum::<u64>() / sorted.len() as u64),
+            latency_p50_ms: percentile(&sorted, 50.0),
+            latency_p95_ms: percentile(&sorted, 95.0),
+            latency_p99_ms: percentile(&sorted, 99.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct WarmupResult {
+    cold_ttft_ms: u64,
+    ready_ttft_ms: u64,
+}
+
+impl NodeState {
+    /// The backend models that may be advertised. With incremental warmup
+    /// only warm ones are, in backend order.
+    fn advertisable(&self, node: &NodeConfig, models: &[String]) -> Vec<String> {
+        match node.warmup {
+            Some(ref warmup) if warmup.incremental => models.iter().filter(|m| self.warm_models.contains(*m)).cloned().collect(),
+            _ => models.to_vec(),
+        }
+    }
+
+    fn failure_rate(&self) -> Option<f64> {
+        if self.outcomes.is_empty() {
+            return None;
+        }
+        Some(self.outcomes.iter().filter(|&&failed| failed).count() as f64 / self.outcomes.len() as f64)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionState {
+    Disconnected,
+    Connected,
+    Authenticated,
+}
+
+impl ConnectionState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionState::Disconnected => "disconnected",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Authenticated => "authenticated",
+        }
+    }
+}
+
+/// Counters exported on the admin `/metrics` endpoint in Prometheus text
+/// format.
+#[derive(Debug, Default)]
+struct Metrics {
+    rate_limited: std::sync::Mutex<std::collections::BTreeMap<String, u64>>,
+    serialization_failures: AtomicU64,
+    deduped_in_flight: AtomicU64,
+    memo_hits: AtomicU64,
+    pre_auth_messages: AtomicU64,
+    empty_responses: std::sync::Mutex<std::collections::BTreeMap<String, u64>>,
+    prompt_hook_failures: std::sync::Mutex<std::collections::BTreeMap<String, u64>>,
+    model_fallbacks: std::sync::Mutex<std::collections::BTreeMap<String, u64>>,
+    not_accepted: std::sync::Mutex<std::collections::BTreeMap<String, u64>>,
+    overload_rejections: AtomicU64,
+    coalesced_requests: AtomicU64,
+    compressed_responses: AtomicU64,
+    compression_saved_bytes: AtomicU64,
+    cert_expiry_days: std::sync::Mutex<std::collections::BTreeMap<String, f64>>,
+}
+
+impl Metrics {
+    fn inc(map: &std::sync::Mutex<std::collections::BTreeMap<String, u64>>, key: &str) {
+        *map.lock().unwrap().entry(key.to_string()).or_insert(0) += 1;
+    }
+
+    fn inc_empty_response(&self, node: &str) {
+        Self::inc(&self.empty_responses, node);
+    }
+
+    fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n{} {}\n", name, help, name, name, value));
+    }
+
+    fn write_labeled(out: &mut String, name: &str, help: &str, label: &str, map: &std::sync::Mutex<std::collections::BTreeMap<String, u64>>) {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n", name, help, name));
+        for (key, value) in map.lock().unwrap().iter() {
+            out.push_str(&format!("{}{{{}=\"{}\"}} {}\n", name, label, key.replace('\\', "\\\\").replace('"', "\\\""), value));
+        }
+    }
+
+    fn write_gauges(out: &mut String, name: &str, help: &str, label: &str, map: &std::sync::Mutex<std::collections::BTreeMap<String, f64>>) {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n", name, help, name));
+        for (key, value) in map.lock().unwrap().iter() {
+            out.push_str(&format!("{}{{{}=\"{}\"}} {:.2}\n", name, label, key.replace('\\', "\\\\").replace('"', "\\\""), value));
+        }
+    }
+
+    /// Entry count and evictions for each named cache.
+    fn write_caches(out: &mut String, caches: &[(&str, CacheStats)]) {
+        out.push_str("# HELP pin_cache_entries Entries held in each bounded cache.\n# TYPE pin_cache_entries gauge\n");
+        for (name, stats) in caches {
+            out.push_str(&format!("pin_cache_entries{{cache=\"{}\"}} {}\n", name, stats.entries));
+        }
+        out.push_str("# HELP pin_cache_evictions_total Entries removed from each bounded cache, by reason.\n# TYPE pin_cache_evictions_total counter\n");
+        for (name, stats) in caches {
+            out.push_str(&format!("pin_cache_evictions_total{{cache=\"{}\",reason=\"capacity\"}} {}\n", name, stats.evicted));
+            out.push_str(&format!("pin_cache_evictions_total{{cache=\"{}\",reason=\"expired\"}} {}\n", name, stats.expired));
+        }
+    }
+
+    fn render(&self, caches: &[(&str, CacheStats)]) -> String {
+        let mut out = String::new();
+        Self::write_counter(&mut out, "pin_requests_total", "Inference requests received.", TOTAL_REQUESTS.load(Ordering::SeqCst));
+        Self::write_labeled(&mut out, "pin_rate_limited_total", "Requests rejected by the per-model rate limit.", "model", &self.rate_limited);
+        Self::write_counter(&mut out, "pin_serialization_failures_total", "Responses that could not be encoded as JSON.", self.serialization_failures.load(Ordering::SeqCst));
+        Self::write_counter(&mut out, "pin_dedup_in_flight_total", "Duplicate requests dropped while the original was still running.", self.deduped_in_flight.load(Ordering::SeqCst));
+        Self::write_counter(&mut out, "pin_dedup_memo_hits_total", "Duplicate requests answered from the response memo.", self.memo_hits.load(Ordering::SeqCst));
+        Self::write_counter(&mut out, "pin_pre_auth_messages_total", "Server messages rejected or ignored because they arrived before AUTH_SUCCESS.", self.pre_auth_messages.load(Ordering::SeqCst));
+        Self::write_labeled(&mut out, "pin_empty_responses_total", "Empty completions returned by a backend, including retried ones.", "node", &self.empty_responses);
+        Self::write_labeled(&mut out, "pin_prompt_hook_failures_total", "Prompt hook runs that failed (timeout, bad exit or bad output).", "node", &self.prompt_hook_failures);
+        Self::write_labeled(&mut out, "pin_model_fallbacks_total", "Requests served with a fallback model because the requested one was unavailable.", "node", &self.model_fallbacks);
+        Self::write_labeled(&mut out, "pin_not_accepted_total", "Requests declined by the node's accept filter.", "node", &self.not_accepted);
+        Self::write_counter(&mut out, "pin_overload_rejections_total", "Requests rejected because the system was overloaded.", self.overload_rejections.load(Ordering::SeqCst));
+        Self::write_counter(&mut out, "pin_coalesced_requests_total", "Requests answered with the result of an identical request that was already running.", self.coalesced_requests.load(Ordering::SeqCst));
+        Self::write_counter(&mut out, "pin_compressed_responses_total", "Responses sent gzipped as COMPRESSED_RESPONSE.", self.compressed_responses.load(Ordering::SeqCst));
+        Self::write_counter(&mut out, "pin_compression_saved_bytes_total", "Bytes saved by response compression.", self.compression_saved_bytes.load(Ordering::SeqCst));
+        Self::write_gauges(&mut out, "pin_backend_cert_expiry_days", "Days until the backend's TLS certificate expires (negative once expired).", "node", &self.cert_expiry_days);
+        Self::write_caches(&mut out, caches);
+        out
+    }
+}
+
+/// Token bucket per model: holds up to `rpm` tokens and refills at `rpm`
+/// per minute, so short bursts are allowed but the average rate is capped.
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: std::time::Instant,
+}
+
+#[derive(Debug, Default)]
+struct RateLimiter {
+    /// Only models listed in `modelRateLimits` get a bucket, so the map is
+    /// bounded by the config.
+    buckets: std::sync::Mutex<std::collections::HashMap<String, TokenBucket>>,
+}
+
+impl RateLimiter {
+    fn try_acquire(&self, model: &str, rpm: u32) -> bool {
+        let capacity = rpm as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(model.to_string()).or_insert_with(|| TokenBucket {
+            tokens: capacity,
+            last_refill: std::time::Instant::now(),
+        });
+
+        let now = std::time::Instant::now();
+        let refill = now.duration_since(bucket.last_refill).as_secs_f64() * capacity / 60.0;
+        bucket.tokens = (bucket.tokens + refill).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// One served inference request, as written to the `auditLog` JSONL file.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuditRecord {
+    timestamp: String,
+    request_id: String,
+    node: String,
+    model: String,
+    backend_model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    options: GenerationOptions,
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    prompt_tokens: u32,
+    #[serde(default)]
+    completion_tokens: u32,
+    latency_ms: u64,
+}
+
+impl AuditRecord {
+    #[allow(clippy::too_many_arguments)]
+    fn new(request_id: &str, node: &str, model: &str, backend_model: &str, messages: Vec<ChatMessage>, options: &GenerationOptions, result: &Result<OpenAIResponse, String>, latency_ms: u64) -> Self {
+        let (content, error, usage) = match result {
+            Ok(resp) => (resp.choices.first().map(|c| c.message.content.clone()), None, resp.usage.as_ref()),
+            Err(e) => (None, Some(e.clone()), None),
+        };
+        AuditRecord {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            request_id: request_id.to_string(),
+            node: node.to_string(),
+            model: model.to_string(),
+            backend_model: backend_model.to_string(),
+            messages,
+            options: options.clone(),
+            content,
+            error,
+            prompt_tokens: usage.map(|u| u.prompt_tokens).unwrap_or(0),
+            completion_tokens: usage.map(|u| u.completion_tokens).unwrap_or(0),
+            latency_ms,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct AuditLog {
+    file: Arc<std::sync::Mutex<std::fs::File>>,
+}
+
+impl AuditLog {
+    fn open(path: &std::path::Path) -> Result<Self, String> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open audit log {}: {}", path.display(), e))?;
+        Ok(AuditLog { file: Arc::new(std::sync::Mutex::new(file)) })
+    }
+
+    /// Writes the record on the blocking pool, so a slow disk doesn't stall
+    /// the runtime thread the request finished on.
+    fn append(&self, record: &AuditRecord) {
+        let line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("[AUDIT] Failed to encode record for {}: {}", record.request_id, e);
+                return;
+            }
+        };
+        let file = self.file.clone();
+        let request_id = record.request_id.clone();
+        tokio::task::spawn_blocking(move || {
+            use std::io::Write;
+            if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+                warn!("[AUDIT] Failed to write record for {}: {}", request_id, e);
+            }
+        });
+    }
+}
+
+/// What `stateFile` records about a daemon run. `stoppedAt` is only set on
+/// a clean shutdown, so a run that is still marked as started when the next
+/// one begins must have crashed or been killed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct PersistedState {
+    started_at: Option<String>,
+    stopped_at: Option<String>,
+    consecutive_crashes: u32,
+}
+
+/// Why this daemon run started, as far as `stateFile` can tell.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RestartInfo {
+    reason: String,
+    previous_started_at: Option<String>,
+    previous_stopped_at: Option<String>,
+    consecutive_crashes: u32,
+}
+
+impl Default for RestartInfo {
+    fn default() -> Self {
+        RestartInfo { reason: "unknown".to_string(), previous_started_at: None, previous_stopped_at: None, consecutive_crashes: 0 }
+    }
+}
+
+#[derive(Debug)]
+struct StateFile {
+    path: PathBuf,
+    started_at: String,
+    consecutive_crashes: u32,
+}
+
+impl StateFile {
+    /// Reads the previous run's record to work out why this run started,
+    /// then marks this run as started.
+    fn open(path: &std::path::Path, started_at: String) -> Result<(Self, RestartInfo), String> {
+        let previous: Option<PersistedState> = match std::fs::read_to_string(path) {
+            Ok(text) => Some(serde_json::from_str(&text).map_err(|e| format!("Invalid state file {}: {}", path.display(), e))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(format!("Failed to read state file {}: {}", path.display(), e)),
+        };
+        let restart = match previous {
+            None => RestartInfo { reason: "first_start".to_string(), ..RestartInfo::default() },
+            Some(prev) => {
+                let crashed = prev.stopped_at.is_none();
+                RestartInfo {
+                    reason: if crashed { "crash" } else { "graceful_restart" }.to_string(),
+                    previous_started_at: prev.started_at,
+                    previous_stopped_at: prev.stopped_at,
+                    consecutive_crashes: if crashed { prev.consecutive_crashes + 1 } else { 0 },
+                }
+            }
+        };
+        let file = StateFile { path: path.to_path_buf(), started_at, consecutive_crashes: restart.consecutive_crashes };
+        file.write(None)?;
+        Ok((file, restart))
+    }
+
+    /// Records a clean shutdown, so the next run doesn't count as a crash.
+    fn mark_stopped(&self) {
+        if let Err(e) = self.write(Some(chrono::Utc::now().to_rfc3339())) {
+            warn!("{}", e);
+        }
+    }
+
+    fn write(&self, stopped_at: Option<String>) -> Result<(), String> {
+        let state = PersistedState {
+            started_at: Some(self.started_at.clone()),
+            stopped_at,
+            consecutive_crashes: self.consecutive_crashes,
+        };
+        let json = serde_json::to_string_pretty(&state).map_err(|e| e.to_string())?;
+        // Written beside the target and renamed over it, so a crash mid-write
+        // never leaves a truncated file.
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, json)
+            .and_then(|_| std::fs::rename(&tmp, &self.path))
+            .map_err(|e| format!("Failed to write state file {}: {}", self.path.display(), e))
+    }
+}
+
+/// Stage boundaries of one inference request, recorded with
+/// `--profile-requests`. Each mark ends the stage it is named after.
+struct RequestTimeline {
+    received: std::time::Instant,
+    marks: Vec<(&'static str, std::time::Instant)>,
+}
+
+impl RequestTimeline {
+    fn new() -> Self {
+        RequestTimeline { received: std::time::Instant::now(), marks: Vec::new() }
+    }
+
+    fn mark(&mut self, stage: &'static str) {
+        self.mark_at(stage, std::time::Instant::now());
+    }
+
+    fn mark_at(&mut self, stage: &'static str, at: std::time::Instant) {
+        self.marks.push((stage, at));
+    }
+
+    /// `(stage, start, end)` for each recorded stage, in order.
+    fn stages(&self) -> Vec<(&'static str, std::time::Instant, std::time::Instant)> {
+        let mut start = self.received;
+        self.marks
+            .iter()
+            .map(|&(stage, end)| {
+                let span = (stage, start, end);
+                start = end;
+                span
+            })
+            .collect()
+    }
+}
+
+/// Emits request timelines as `[PROFILE]` log lines and, optionally, as
+/// Chrome trace events (viewable in Perfetto or `chrome://tracing`), one
+/// track per request.
+#[derive(Debug)]
+struct RequestProfiler {
+    epoch: std::time::Instant,
+    trace: Option<std::sync::Mutex<std::fs::File>>,
+}
+
+impl RequestProfiler {
+    fn new(trace_path: Option<&std::path::Path>) -> Result<Self, String> {
+        use std::io::Write;
+        let trace = match trace_path {
+            Some(path) => {
+                let mut file = std::fs::File::create(path).map_err(|e| format!("Failed to create profile trace {}: {}", path.display(), e))?;
+                // The trace format allows the closing bracket to be missing,
+                // so events can be appended as they happen.
+                writeln!(file, "[").map_err(|e| format!("Failed to write profile trace {}: {}", path.display(), e))?;
+                Some(std::sync::Mutex::new(file))
+            }
+            None => None,
+        };
+        Ok(RequestProfiler { epoch: std::time::Instant::now(), trace })
+    }
+
+    fn record(&self, count: u64, request_id: &str, timeline: &RequestTimeline) {
+        use std::io::Write;
+        let stages = timeline.stages();
+        let Some(&(_, _, end)) = stages.last() else { return };
+        let ms = |from: std::time::Instant, to: std::time::Instant| to.duration_since(from).as_secs_f64() * 1000.0;
+        let breakdown: Vec<String> = stages.iter().map(|&(stage, from, to)| format!("{} {:.2}ms", stage, ms(from, to))).collect();
+        info!("[PROFILE] #{} {}: {} | total {:.2}ms", count, request_id, breakdown.join(" | "), ms(timeline.received, end));
+
+        let Some(ref trace) = self.trace else { return };
+        let us = |at: std::time::Instant| at.duration_since(self.epoch).as_micros() as u64;
+        let event = |name: &str, from: std::time::Instant, to: std::time::Instant| {
+            serde_json::json!({
+                "name": name,
+                "cat": "request",
+                "ph": "X",
+                "ts": us(from),
+                "dur": us(to).saturating_sub(us(from)),
+                "pid": 1,
+                "tid": count,
+                "args": { "request_id": request_id },
+            })
+        };
+        let mut out = format!("{},\n", event(request_id, timeline.received, end));
+        for &(stage, from, to) in &stages {
+            out.push_str(&format!("{},\n", event(stage, from, to)));
+        }
+        if let Err(e) = trace.lock().unwrap().write_all(out.as_bytes()) {
+            warn!("[PROFILE] Failed to write trace for {}: {}", request_id, e);
+        }
+    }
+}
+
+enum DedupOutcome {
+    New,
+    InFlight,
+    Memoized(String),
+}
+
+/// Tracks request ids that are running or recently answered so a request the
+/// server delivers twice is only executed (and billed) once.
+#[derive(Debug, Default)]
+struct RequestDedup {
+    in_flight: std::sync::Mutex<std::collections::HashSet<String>>,
+    memo: std::sync::Mutex<BoundedCache<String>>,
+}
+
+/// Size and eviction totals of a `BoundedCache`, for `/metrics`.
+#[derive(Debug, Clone, Copy, Default)]
+struct CacheStats {
+    entries: usize,
+    evicted: u64,
+    expired: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry<V> {
+    value: V,
+    used: u64,
+    expires: (std::time::Instant, u64),
+}
+
+/// A string-keyed cache bounded by entry count and age. Reading an entry
+/// makes it the most recently used, and inserting into a full cache evicts
+/// the least recently used one. Expired entries are dropped lazily from the
+/// front of the expiry order whenever the cache is touched, so there is no
+/// background scan; every step is a map lookup.
+#[derive(Debug)]
+struct BoundedCache<V> {
+    entries: std::collections::HashMap<String, CacheEntry<V>>,
+    /// Keys by last use, oldest first.
+    recency: std::collections::BTreeMap<u64, String>,
+    /// Keys by expiry time, soonest first. The stamp keeps equal times apart.
+    expiry: std::collections::BTreeMap<(std::time::Instant, u64), String>,
+    next_stamp: u64,
+    evicted: u64,
+    expired: u64,
+}
+
+impl<V> Default for BoundedCache<V> {
+    fn default() -> Self {
+        BoundedCache {
+            entries: std::collections::HashMap::new(),
+            recency: std::collections::BTreeMap::new(),
+            expiry: std::collections::BTreeMap::new(),
+            next_stamp: 0,
+            evicted: 0,
+            expired: 0,
+        }
+    }
+}
+
+impl<V> BoundedCache<V> {
+    fn stamp(&mut self) -> u64 {
+        self.next_stamp += 1;
+        self.next_stamp
+    }
+
+    fn remove(&mut self, key: &str) -> Option<CacheEntry<V>> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.used);
+        self.expiry.remove(&entry.expires);
+        Some(entry)
+    }
+
+    fn expire(&mut self, now: std::time::Instant) {
+        while let Some(((expires, _), key)) = self.expiry.first_key_value() {
+            if *expires > now {
+                break;
+            }
+            let key = key.clone();
+            self.remove(&key);
+            self.expired += 1;
+        }
+    }
+
+    fn get(&mut self, key: &str, now: std::time::Instant) -> Option<&V> {
+        self.expire(now);
+        let used = self.stamp();
+        let entry = self.entries.get_mut(key)?;
+        self.recency.remove(&entry.used);
+        self.recency.insert(used, key.to_string());
+        entry.used = used;
+        Some(&entry.value)
+    }
+
+    /// Stores `value` for `ttl`, replacing any entry under `key`, then evicts
+    /// least recently used entries down to `max_entries`.
+    fn insert(&mut self, key: &str, value: V, ttl: Duration, max_entries: usize, now: std::time::Instant) {
+        self.expire(now);
+        self.remove(key);
+        let used = self.stamp();
+        let expires = (now + ttl, used);
+        self.recency.insert(used, key.to_string());
+        self.expiry.insert(expires, key.to_string());
+        self.entries.insert(key.to_string(), CacheEntry { value, used, expires });
+        while self.entries.len() > max_entries {
+            let Some((_, oldest)) = self.recency.pop_first() else { break };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.expiry.remove(&entry.expires);
+            }
+            self.evicted += 1;
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats { entries: self.entries.len(), evicted: self.evicted, expired: self.expired }
+    }
+}
+
+impl RequestDedup {
+    /// Claims `request_id` for execution unless it is already running or has
+    /// a memoized response. When the in-flight set is full the request runs
+    /// untracked rather than being refused.
+    fn begin(&self, request_id: &str, limits: &DedupConfig) -> DedupOutcome {
+        {
+            if let Some(json) = self.memo.lock().unwrap().get(request_id, std::time::Instant::now()) {
+                return DedupOutcome::Memoized(json.clone());
+            }
+        }
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.contains(request_id) {
+            return DedupOutcome::InFlight;
+        }
+        if in_flight.len() < limits.max_in_flight {
+            in_flight.insert(request_id.to_string());
+        } else {
+            warn!("In-flight dedup set full ({}), not tracking {}", limits.max_in_flight, request_id);
+        }
+        DedupOutcome::New
+    }
+
+    /// Releases `request_id`, memoizing `response` when given.
+    fn finish(&self, request_id: &str, response: Option<&str>, limits: &DedupConfig) {
+        self.in_flight.lock().unwrap().remove(request_id);
+
+        let Some(json) = response else { return };
+        if limits.memo_ttl_secs == 0 || limits.memo_max_entries == 0 {
+            return;
+        }
+        let ttl = Duration::from_secs(limits.memo_ttl_secs);
+        self.memo.lock().unwrap().insert(request_id, json.to_string(), ttl, limits.memo_max_entries, std::time::Instant::now());
+    }
+
+    /// Responses from a previous connection can no longer reach the server,
+    /// so a redelivery after reconnecting must be allowed to run again.
+    fn clear_in_flight(&self) {
+        self.in_flight.lock().unwrap().clear();
+    }
+
+    fn sizes(&self) -> (usize, usize) {
+        (self.in_flight.lock().unwrap().len(), self.memo.lock().unwrap().entries.len())
+    }
+
+    fn memo_stats(&self) -> CacheStats {
+        self.memo.lock().unwrap().stats()
+    }
+}
+
+type FlightResult = Result<OpenAIResponse, String>;
+type Flights = Arc<std::sync::Mutex<std::collections::HashMap<u64, Vec<tokio::sync::oneshot::Sender<FlightResult>>>>>;
+
+enum Flight {
+    Leader(FlightLeader),
+    Follower(tokio::sync::oneshot::Receiver<FlightResult>),
+}
+
+/// Identical requests that are running, keyed by a hash of what is sent to
+/// the backend, with the requests waiting for each one's result.
+#[derive(Debug, Default)]
+struct RequestCoalescer {
+    flights: Flights,
+}
+
+impl RequestCoalescer {
+    /// Leads a new flight for `key`, or follows the one already running.
+    fn join(&self, key: u64) -> Flight {
+        let mut flights = self.flights.lock().unwrap();
+        match flights.get_mut(&key) {
+            Some(waiters) => {
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                waiters.push(tx);
+                Flight::Follower(rx)
+            }
+            None => {
+                flights.insert(key, Vec::new());
+                Flight::Leader(FlightLeader { flights: self.flights.clone(), key: Some(key) })
+            }
+        }
+    }
+}
+
+/// The request that calls the backend for a flight. If it is dropped before
+/// finishing (e.g. it hit the task lifetime), its followers are released to
+/// run on their own.
+struct FlightLeader {
+    flights: Flights,
+    key: Option<u64>,
+}
+
+impl FlightLeader {
+    fn finish(mut self, result: &FlightResult) {
+        let Some(key) = self.key.take() else { return };
+        let waiters = self.flights.lock().unwrap().remove(&key).unwrap_or_default();
+        for waiter in waiters {
+            let _ = waiter.send(result.clone());
+        }
+    }
+}
+
+impl Drop for FlightLeader {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.flights.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+/// Identifies requests that would send the backend the same thing.
+fn coalesce_key(alias: &str, requested_model: &str, model: &str, messages: &[ChatMessage], options: &GenerationOptions, include_reasoning: bool) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let request = serde_json::json!([alias, requested_model, model, messages, options, include_reasoning]);
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    request.to_string().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Counters for one server connection, summarized when it ends.
+#[derive(Debug)]
+struct ConnectionStats {
+    started: std::time::Instant,
+    started_at: chrono::DateTime<chrono::Utc>,
+    requests: AtomicU64,
+    errors: AtomicU64,
+    tokens: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    end_reason: std::sync::Mutex<Option<String>>,
+    close_code: std::sync::Mutex<Option<u16>>,
+}
+
+impl ConnectionStats {
+    fn new() -> Self {
+        ConnectionStats {
+            started: std::time::Instant::now(),
+            started_at: chrono::Utc::now(),
+            requests: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            tokens: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            end_reason: std::sync::Mutex::new(None),
+            close_code: std::sync::Mutex::new(None),
+        }
+    }
+
+    fn set_end_reason(&self, reason: impl Into<String>) {
+        *self.end_reason.lock().unwrap() = Some(reason.into());
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConnectionSummary {
+    started_at: String,
+    duration_secs: u64,
+    requests: u64,
+    errors: u64,
+    tokens: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    close_code: Option<u16>,
+}
+
+/// Summaries of the most recent connections kept for `/status`.
+const CONNECTION_HISTORY_LEN: usize = 20;
+
+/// Process-wide daemon state. Outlives individual connections so the admin
+/// endpoints and latency history survive reconnects.
+#[derive(Debug)]
+struct DaemonState {
+    connection: std::sync::Mutex<ConnectionState>,
+    nodes: std::sync::Mutex<std::collections::HashMap<String, NodeState>>,
+    /// Requests per (canonical model, variant) for weighted variant routing.
+    variant_counts: std::sync::Mutex<std::collections::BTreeMap<(String, String), u64>>,
+    last_request: std::sync::Mutex<Option<std::time::Instant>>,
+    rate_limiter: RateLimiter,
+    dedup: RequestDedup,
+    coalescer: RequestCoalescer,
+    audit: Option<AuditLog>,
+    profiler: Option<RequestProfiler>,
+    metrics: Metrics,
+    connection_stats: std::sync::Mutex<Option<Arc<ConnectionStats>>>,
+    connection_history: std::sync::Mutex<std::collections::VecDeque<ConnectionSummary>>,
+    started: std::time::Instant,
+    started_at: chrono::DateTime<chrono::Utc>,
+    restart: RestartInfo,
+    state_file: Option<StateFile>,
+    /// Why the system counts as overloaded, while load shedding is active.
+    overload: std::sync::Mutex<Option<String>>,
+    wallet: std::sync::Mutex<WalletStatus>,
+}
+
+/// Outcome of the payout address update on the current connection.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WalletStatus {
+    /// `unconfigured`, `pending`, `retrying`, `accepted` or `rejected`.
+    status: &'static str,
+    attempts: u32,
+    message: Option<String>,
+}
+
+impl DaemonState {
+    fn new(config: &Config) -> Self {
+        DaemonState {
+            connection: std::sync::Mutex::new(ConnectionState::Disconnected),
+            nodes: std::sync::Mutex::new(
+                config
+                    .nodes
+                    .iter()
+                    .map(|n| {
+                        (n.alias.clone(), NodeState {
+                            models: Vec::new(),
+                            effective_capacity: n.capacity,
+                            latency: std::collections::HashMap::new(),
+                            registered: false,
+                            backend_reachable: false,
+                            rejection: None,
+                            awaiting_ack: false,
+                            backend_up: n.backend_command.as_ref().map(|_| false),
+                            outcomes: std::collections::VecDeque::new(),
+                            disabled_until: None,
+                            warmup: None,
+                            discovered_models: None,
+                            backend_models: Vec::new(),
+                            warm_models: std::collections::HashSet::new(),
+                            warming: false,
+                            warmup_finished: false,
+                            period: NodePeriodStats::default(),
+                            in_flight: 0,
+                            beat_requests: 0,
+                            beat_errors: 0,
+                        })
+                    })
+                    .collect(),
+            ),
+            variant_counts: std::sync::Mutex::new(std::collections::BTreeMap::new()),
+            last_request: std::sync::Mutex::new(None),
+            rate_limiter: RateLimiter::default(),
+            dedup: RequestDedup::default(),
+            coalescer: RequestCoalescer::default(),
+            audit: None,
+            profiler: None,
+            metrics: Metrics::default(),
+            connection_stats: std::sync::Mutex::new(None),
+            connection_history: std::sync::Mutex::new(std::collections::VecDeque::new()),
+            started: std::time::Instant::now(),
+            started_at: chrono::Utc::now(),
+            restart: RestartInfo::default(),
+            state_file: None,
+            overload: std::sync::Mutex::new(None),
+            wallet: std::sync::Mutex::new(WalletStatus {
+                status: if config.payout_address.as_ref().is_some_and(|a| !a.is_empty()) { "pending" } else { "unconfigured" },
+                attempts: 0,
+                message: None,
+            }),
+        }
+    }
+
+    fn start_request(&self, alias: &str) {
+        if let Some(state) = self.nodes.lock().unwrap().get_mut(alias) {
+            state.in_flight += 1;
+        }
+    }
+
+    /// Counts an answered request towards the next heartbeat and, with
+    /// `nodeStats`, the node's next `NODE_STATS`.
+    fn finish_request(&self, alias: &str, failed: bool, node_stats: bool) {
+        if let Some(state) = self.nodes.lock().unwrap().get_mut(alias) {
+            state.in_flight = state.in_flight.saturating_sub(1);
+            state.beat_requests += 1;
+            state.beat_errors += failed as u64;
+            if node_stats {
+                state.period.requests += 1;
+                state.period.errors += failed as u64;
+            }
+        }
+    }
+
+    /// Each node's activity since the last heartbeat, in config order,
+    /// starting the count over.
+    fn take_heartbeat_telemetry(&self, config: &Config) -> Vec<HeartbeatNode> {
+        let mut nodes = self.nodes.lock().unwrap();
+        config
+            .nodes
+            .iter()
+            .filter_map(|n| {
+                let state = nodes.get_mut(&n.alias)?;
+                Some(HeartbeatNode {
+                    alias: n.alias.clone(),
+                    in_flight: state.in_flight,
+                    requests: std::mem::take(&mut state.beat_requests),
+                    errors: std::mem::take(&mut state.beat_errors),
+                })
+            })
+            .collect()
+    }
+
+    /// Aliases of the nodes registered on the current connection, in config
+    /// order.
+    fn registered_aliases(&self, config: &Config) -> Vec<String> {
+        let nodes = self.nodes.lock().unwrap();
+        config
+            .nodes
+            .iter()
+            .filter(|n| nodes.get(&n.alias).is_some_and(|s| s.registered))
+            .map(|n| n.alias.clone())
+            .collect()
+    }
+
+    /// Adds a successful request's tokens and latency to the node's next
+    /// `NODE_STATS`.
+    fn add_period_usage(&self, alias: &str, prompt_tokens: u32, completion_tokens: u32, latency_ms: u64) {
+        if let Some(state) = self.nodes.lock().unwrap().get_mut(alias) {
+            state.period.prompt_tokens += prompt_tokens as u64;
+            state.period.completion_tokens += completion_tokens as u64;
+            state.period.latencies_ms.push(latency_ms);
+        }
+    }
+
+    /// Each node's stats for the period that just ended, in config order,
+    /// starting a new period.
+    fn take_period_stats(&self, config: &Config) -> Vec<NodeStatsEntry> {
+        let mut nodes = self.nodes.lock().unwrap();
+        config
+            .nodes
+            .iter()
+            .filter_map(|n| {
+                let state = nodes.get_mut(&n.alias)?;
+                Some(std::mem::take(&mut state.period).report(&n.alias))
+            })
+            .collect()
+    }
+
+    fn uptime_secs(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    /// How long the current server connection has been up.
+    fn connection_uptime_secs(&self) -> Option<u64> {
+        self.connection_stats.lock().unwrap().as_ref().map(|s| s.started.elapsed().as_secs())
+    }
+
+    fn begin_connection(&self) -> Arc<ConnectionStats> {
+        let stats = Arc::new(ConnectionStats::new());
+        *self.connection_stats.lock().unwrap() = Some(stats.clone());
+        stats
+    }
+
+    /// Closes out the current connection's stats and adds them to the
+    /// history. `None` if the connection was never established.
+    fn end_connection(&self, error: Option<String>) -> Option<ConnectionSummary> {
+        let stats = self.connection_stats.lock().unwrap().take()?;
+        let reason = error
+            .or_else(|| stats.end_reason.lock().unwrap().clone())
+            .unwrap_or_else(|| "shutdown".to_string());
+        let summary = ConnectionSummary {
+            started_at: stats.started_at.to_rfc3339(),
+            duration_secs: stats.started.elapsed().as_secs(),
+            requests: stats.requests.load(Ordering::SeqCst),
+            errors: stats.errors.load(Ordering::SeqCst),
+            tokens: stats.tokens.load(Ordering::SeqCst),
+            bytes_sent: stats.bytes_sent.load(Ordering::SeqCst),
+            bytes_received: stats.bytes_received.load(Ordering::SeqCst),
+            reason,
+            close_code: *stats.close_code.lock().unwrap(),
+        };
+        let mut history = self.connection_history.lock().unwrap();
+        if history.len() == CONNECTION_HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(summary.clone());
+        Some(summary)
+    }
+
+    /// One-line request and dedup totals for the periodic and shutdown logs.
+    fn summary(&self) -> String {
+        let (in_flight, memoized) = self.dedup.sizes();
+        format!(
+            "Total requests: {}, deduped in-flight: {}, served from memo: {} (tracking {} in flight, {} memoized)",
+            TOTAL_REQUESTS.load(Ordering::SeqCst),
+            self.metrics.deduped_in_flight.load(Ordering::SeqCst),
+            self.metrics.memo_hits.load(Ordering::SeqCst),
+            in_flight,
+            memoized
+        )
+    }
+
+    fn touch_last_request(&self) {
+        *self.last_request.lock().unwrap() = Some(std::time::Instant::now());
+    }
+
+    fn last_request(&self) -> Option<std::time::Instant> {
+        *self.last_request.lock().unwrap()
+    }
+
+    fn record_variant(&self, canonical: &str, variant: &str) {
+        *self
+            .variant_counts
+            .lock()
+            .unwrap()
+            .entry((canonical.to_string(), variant.to_string()))
+            .or_insert(0) += 1;
+    }
+
+    /// Per canonical model, the request count and share of each variant.
+    fn variant_split(&self) -> std::collections::BTreeMap<String, Vec<(String, u64, f64)>> {
+        let counts = self.variant_counts.lock().unwrap();
+        let mut split: std::collections::BTreeMap<String, Vec<(String, u64, f64)>> = std::collections::BTreeMap::new();
+        for ((canonical, variant), count) in counts.iter() {
+            split.entry(canonical.clone()).or_default().push((variant.clone(), *count, 0.0));
+        }
+        for variants in split.values_mut() {
+            let total: u64 = variants.iter().map(|v| v.1).sum();
+            for v in variants.iter_mut() {
+                v.2 = v.1 as f64 * 100.0 / total.max(1) as f64;
+            }
+        }
+        split
+    }
+
+    fn set_connection(&self, state: ConnectionState) {
+        *self.connection.lock().unwrap() = state;
+    }
+
+    fn connection(&self) -> ConnectionState {
+        *self.connection.lock().unwrap()
+    }
+
+    /// Resets per-connection node state at the start of a new connection.
+    fn reset_nodes(&self, config: &Config) {
+        let mut nodes = self.nodes.lock().unwrap();
+        for node in &config.nodes {
+            if let Some(state) = nodes.get_mut(&node.alias) {
+                state.models.clear();
+                state.effective_capacity = node.capacity;
+                state.registered = false;
+                state.rejection = None;
+                state.awaiting_ack = false;
+            }
+        }
+    }
+
+    /// Maps an alias reported by the server back to a configured node.
+    fn resolve_alias<'a>(nodes: &'a mut std::collections::HashMap<String, NodeState>, server_alias: &str) -> Option<&'a mut NodeState> {
+        let alias = configured_alias(nodes.keys(), server_alias)?;
+        nodes.get_mut(&alias)
+    }
+
+    fn backend_up(&self, alias: &str) -> Option<bool> {
+        self.nodes.lock().unwrap().get(alias).and_then(|n| n.backend_up)
+    }
+
+    fn set_backend_up(&self, alias: &str, up: bool) {
+        if let Some(state) = self.nodes.lock().unwrap().get_mut(alias) {
+            state.backend_up = Some(up);
+            if !up {
+                // A restarted backend starts cold again.
+                state.warmup = None;
+                state.warm_models.clear();
+            }
+        }
+    }
+
+    fn warmup(&self, alias: &str) -> Option<WarmupResult> {
+        self.nodes.lock().unwrap().get(alias).and_then(|n| n.warmup)
+    }
+
+    /// Marks incremental warmup as running for a node; false if it already is.
+    fn start_warming(&self, alias: &str) -> bool {
+        match self.nodes.lock().unwrap().get_mut(alias) {
+            Some(state) if !state.warming => {
+                state.warming = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn finish_warming(&self, alias: &str, result: Option<WarmupResult>) {
+        if let Some(state) = self.nodes.lock().unwrap().get_mut(alias) {
+            state.warming = false;
+            state.warmup_finished = true;
+            state.warmup = result;
+        }
+    }
+
+    /// Takes the flag set by a finished full warmup, with the backend models
+    /// to register the node with.
+    fn take_finished_warmup(&self, alias: &str) -> Option<Vec<String>> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let state = nodes.get_mut(alias).filter(|s| s.warmup_finished)?;
+        state.warmup_finished = false;
+        Some(state.backend_models.clone())
+    }
+
+    /// The next model incremental warmup should warm, preferring `first`.
+    /// When every model is warm, the warmup is marked finished in the same
+    /// step, so a model discovered meanwhile starts a new one.
+    fn next_cold_model(&self, alias: &str, first: Option<&String>) -> Option<String> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let state = nodes.get_mut(alias)?;
+        let backend = state.discovered_models.as_ref().unwrap_or(&state.backend_models);
+        let next = first
+            .filter(|m| backend.contains(m))
+            .into_iter()
+            .chain(backend.iter())
+            .find(|m| !state.warm_models.contains(*m))
+            .cloned();
+        if next.is_none() || !RUNNING.load(Ordering::SeqCst) {
+            state.warming = false;
+            return None;
+        }
+        next
+    }
+
+    fn mark_warm(&self, alias: &str, model: &str, result: Option<WarmupResult>) -> usize {
+        let mut nodes = self.nodes.lock().unwrap();
+        let Some(state) = nodes.get_mut(alias) else { return 0 };
+        state.warm_models.insert(model.to_string());
+        if state.warmup.is_none() {
+            state.warmup = result;
+        }
+        state.warm_models.len()
+    }
+
+    fn set_discovered_models(&self, alias: &str, models: Vec<String>) {
+        if let Some(state) = self.nodes.lock().unwrap().get_mut(alias) {
+            state.discovered_models = Some(models);
+        }
+    }
+
+    /// The backend's latest model list: what runtime discovery last agreed
+    /// on, or what registration listed.
+    fn backend_catalog(&self, alias: &str) -> Option<Vec<String>> {
+        let nodes = self.nodes.lock().unwrap();
+        let state = nodes.get(alias)?;
+        Some(state.discovered_models.clone().unwrap_or_else(|| state.backend_models.clone()))
+    }
+
+    /// Models to add to and remove from a registered node's advertised list:
+    /// ones runtime discovery found on or missing from its backend, and ones
+    /// incremental warmup has finished warming since it was last registered.
+    fn model_changes(&self, node: &NodeConfig) -> Option<(Vec<String>, Vec<String>)> {
+        let nodes = self.nodes.lock().unwrap();
+        let state = nodes.get(&node.alias).filter(|s| s.registered)?;
+        let backend = state.discovered_models.as_ref().unwrap_or(&state.backend_models);
+        let mut current = state.advertisable(node, backend);
+        current.extend(canonical_models(node, &current));
+        current.retain(|m| node.accepts_model(m));
+        let added: Vec<String> = current.iter().filter(|m| !state.models.contains(m)).cloned().collect();
+        let removed: Vec<String> = state.models.iter().filter(|m| !current.contains(m)).cloned().collect();
+        (!added.is_empty() || !removed.is_empty()).then_some((added, removed))
+    }
+
+    fn mark_registered(&self, ack_alias: &str) {
+        let mut nodes = self.nodes.lock().unwrap();
+        if let Some(state) = Self::resolve_alias(&mut nodes, ack_alias) {
+            state.registered = true;
+            state.rejection = None;
+            state.awaiting_ack = false;
+        }
+    }
+
+    fn mark_rejected(&self, alias: &str, reason: &str) {
+        let mut nodes = self.nodes.lock().unwrap();
+        if let Some(state) = Self::resolve_alias(&mut nodes, alias) {
+            state.registered = false;
+            state.rejection = Some(reason.to_string());
+            state.awaiting_ack = false;
+        }
+    }
+
+    /// The registrations to send again for a repeated `AUTH_SUCCESS`: those
+    /// the server never answered. Nodes still in a full warmup haven't been
+    /// registered yet, and rejected ones stay rejected.
+    fn unacknowledged_registrations(&self, config: &Config, paused: &std::collections::HashSet<String>) -> Vec<RegisterNodeMessage> {
+        let states = self.nodes.lock().unwrap();
+        config
+            .nodes
+            .iter()
+            .filter(|n| !paused.contains(&n.alias))
+            .filter_map(|n| {
+                let state = states.get(&n.alias).filter(|s| s.awaiting_ack)?;
+                Some(RegisterNodeMessage::new(n, state.models.clone(), state.effective_capacity).with_warmup(state.warmup))
+            })
+            .collect()
+    }
+
+    fn status_json(&self) -> serde_json::Value {
+        let nodes = self.nodes.lock().unwrap();
+        let mut aliases: Vec<&String> = nodes.keys().collect();
+        aliases.sort();
+        let nodes: Vec<serde_json::Value> = aliases
+            .into_iter()
+            .map(|alias| {
+                let n = &nodes[alias];
+                serde_json::json!({
+                    "alias": alias,
+                    "registered": n.registered,
+                    "backendReachable": n.backend_reachable,
+                    "models": n.models,
+                    "capacity": n.effective_capacity,
+                    "rejection": n.rejection,
+                    "backendUp": n.backend_up,
+                    "failureRate": n.failure_rate(),
+                    "disabled": n.disabled_until.is_some_and(|until| std::time::Instant::now() < until),
+                    "inFlight": n.in_flight,
+                })
+            })
+            .collect();
+
+        let variants: serde_json::Map<String, serde_json::Value> = self
+            .variant_split()
+            .into_iter()
+            .map(|(canonical, variants)| {
+                let counts: serde_json::Map<String, serde_json::Value> = variants
+                    .into_iter()
+                    .map(|(variant, count, _)| (variant, serde_json::json!(count)))
+                    .collect();
+                (canonical, serde_json::Value::Object(counts))
+            })
+            .collect();
+
+        serde_json::json!({
+            "connection": self.connection().as_str(),
+            "configFingerprint": CONFIG_FINGERPRINT.get(),
+            "totalRequests": TOTAL_REQUESTS.load(Ordering::SeqCst),
+            "secsSinceLastRequest": self.last_request().map(|t| t.elapsed().as_secs()),
+            "startedAt": self.started_at.to_rfc3339(),
+            "uptimeSecs": self.uptime_secs(),
+            "lastRestart": self.restart,
+            "connectionUptimeSecs": self.connection_uptime_secs(),
+            "lastDisconnectReason": self.connection_history.lock().unwrap().back().map(|c| c.reason.clone()),
+            "overloaded": self.overloaded(),
+            "wallet": self.wallet_status(),
+            "nodes": nodes,
+            "variants": variants,
+            "connections": *self.connection_history.lock().unwrap(),
+        })
+    }
+
+    fn is_ready(&self) -> bool {
+        self.connection() == ConnectionState::Authenticated
+            && self
+                .nodes
+                .lock()
+                .unwrap()
+                .values()
+                .any(|n| n.registered && n.backend_reachable)
+    }
+}
+
+impl DaemonState {
+    /// Records a request outcome for `alias`. Returns the failure rate when
+    /// it just crossed the threshold and the node was disabled.
+    fn record_outcome(&self, alias: &str, failed: bool, cfg: &AutoDisableConfig) -> Option<f64> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let state = nodes.get_mut(alias)?;
+        state.outcomes.push_back(failed);
+        while state.outcomes.len() > cfg.window {
+            state.outcomes.pop_front();
+        }
+        if state.disabled_until.is_some() || state.outcomes.len() < cfg.min_requests {
+            return None;
+        }
+        let rate = state.failure_rate()?;
+        if rate < cfg.failure_rate {
+            return None;
+        }
+        state.disabled_until = Some(std::time::Instant::now() + Duration::from_secs(cfg.cooldown_secs));
+        Some(rate)
+    }
+
+    fn overloaded(&self) -> Option<String> {
+        self.overload.lock().unwrap().clone()
+    }
+
+    /// Counts a wallet update being sent, starting over on a new connection.
+    /// Returns the attempt number.
+    fn start_wallet_update(&self, first: bool) -> u32 {
+        let mut wallet = self.wallet.lock().unwrap();
+        wallet.attempts = if first { 1 } else { wallet.attempts + 1 };
+        if wallet.status != "rejected" {
+            wallet.status = "pending";
+        }
+        wallet.attempts
+    }
+
+    fn set_wallet_status(&self, status: &'static str, message: String) {
+        let mut wallet = self.wallet.lock().unwrap();
+        wallet.status = status;
+        wallet.message = Some(message);
+    }
+
+    fn wallet_status(&self) -> WalletStatus {
+        self.wallet.lock().unwrap().clone()
+    }
+
+    fn node_disabled(&self, alias: &str) -> bool {
+        self.nodes
+            .lock()
+            .unwrap()
+            .get(alias)
+            .and_then(|n| n.disabled_until)
+            .is_some_and(|until| std::time::Instant::now() < until)
+    }
+
+    /// Clears an auto-disable, whether its cooldown ran out or an operator
+    /// re-enabled the node. Returns false if the node wasn't disabled.
+    fn enable_node(&self, alias: &str) -> bool {
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get_mut(alias) {
+            Some(state) if state.disabled_until.is_some() => {
+                state.disabled_until = None;
+                state.outcomes.clear();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Fire-and-forget alert for auto-disable state changes.
+fn send_node_webhook(http: &reqwest::Client, url: &str, event: &str, alias: &str, failure_rate: Option<f64>) {
+    let request = http
+        .post(url)
+        .timeout(Duration::from_secs(10))
+        .json(&serde_json::json!({
+            "event": event,
+            "node": alias,
+            "failureRate": failure_rate,
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+        }));
+    let alias = alias.to_string();
+    tokio::spawn(async move {
+        match request.send().await {
+            Ok(resp) if !resp.status().is_success() => warn!("[HEALTH] Webhook for {} returned {}", alias, resp.status()),
+            Err(e) => warn!("[HEALTH] Webhook for {} failed: {}", alias, e),
+            _ => {}
+        }
+    });
+}
+
+fn send_wallet_webhook(http: &reqwest::Client, url: &str, attempts: u32, message: &str) {
+    let request = http
+        .post(url)
+        .timeout(Duration::from_secs(10))
+        .json(&serde_json::json!({
+            "event": "wallet_rejected",
+            "attempts": attempts,
+            "message": message,
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+        }));
+    tokio::spawn(async move {
+        match request.send().await {
+            Ok(resp) if !resp.status().is_success() => warn!("[WALLET] Webhook returned {}", resp.status()),
+            Err(e) => warn!("[WALLET] Webhook failed: {}", e),
+            _ => {}
+        }
+    });
+}
+
+fn record_latency(state: &DaemonState, alias: &str, model: &str, latency_ms: u64, window: usize) {
+    let mut states = state.nodes.lock().unwrap();
+    if let Some(state) = states.get_mut(alias) {
+        state
+            .latency
+            .entry(model.to_string())
+            .or_default()
+            .record(latency_ms, window);
+    }
+}
+
+/// Computes the next advertised capacity for a node, or `None` if it should
+/// stay where it is.
+fn next_capacity(node: &NodeConfig, cc: &CapacityControlConfig, state: &NodeState) -> Option<u32> {
+    let worst = state
+        .latency
+        .values()
+        .filter(|w| w.samples.len() >= CAPACITY_CONTROL_MIN_SAMPLES)
+        .filter_map(|w| w.percentile(cc.percentile))
+        .max()?;
+
+    let current = state.effective_capacity;
+    let next = if worst > cc.latency_high_ms {
+        current.saturating_sub(cc.step).max(cc.min_capacity)
+    } else if worst < cc.latency_low_ms {
+        current.saturating_add(cc.step).min(node.capacity)
+    } else {
+        current
+    };
+
+    (next != current).then_some(next)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+enum ServerMessage {
+    AUTH_SUCCESS { operator_id: String, node_id: Option<String>, message: String },
+    ERROR { message: String },
+    PING,
+    HEARTBEAT_ACK,
+    MODEL_LIST_ACK,
+    REGISTER_NODE_ACK { node_id: String, alias: String, models: Vec<String>, created: bool, message: String },
+    REGISTER_NODE_REJECTED { alias: String, reason: String },
+    UPDATE_WALLET_ACK { success: bool, message: String },
+    INFERENCE_REQUEST {
+        request_id: String,
+        payload: InferencePayload,
+        #[serde(default)]
+        metadata: Option<serde_json::Value>,
+    },
+    INTERVIEW_REQUEST { interview_id: String, node_id: Option<String>, model: String, prompts: Vec<InterviewPrompt>, timeout_ms: u32 },
+    INTERVIEW_COMPLETE { interview_id: String, node_id: Option<String>, tier: String, accuracy: f32, tokens_per_sec: f32, reason: String },
+}
+
+impl ServerMessage {
+    fn name(&self) -> &'static str {
+        match self {
+            ServerMessage::AUTH_SUCCESS { .. } => "AUTH_SUCCESS",
+            ServerMessage::ERROR { .. } => "ERROR",
+            ServerMessage::PING => "PING",
+            ServerMessage::HEARTBEAT_ACK => "HEARTBEAT_ACK",
+            ServerMessage::MODEL_LIST_ACK => "MODEL_LIST_ACK",
+            ServerMessage::REGISTER_NODE_ACK { .. } => "REGISTER_NODE_ACK",
+            ServerMessage::REGISTER_NODE_REJECTED { .. } => "REGISTER_NODE_REJECTED",
+            ServerMessage::UPDATE_WALLET_ACK { .. } => "UPDATE_WALLET_ACK",
+            ServerMessage::INFERENCE_REQUEST { .. } => "INFERENCE_REQUEST",
+            ServerMessage::INTERVIEW_REQUEST { .. } => "INTERVIEW_REQUEST",
+            ServerMessage::INTERVIEW_COMPLETE { .. } => "INTERVIEW_COMPLETE",
+        }
+    }
+
+    /// Messages that only make sense once AUTH_SUCCESS has been handled.
+    fn requires_auth(&self) -> bool {
+        !matches!(self, ServerMessage::AUTH_SUCCESS { .. } | ServerMessage::ERROR { .. } | ServerMessage::PING | ServerMessage::HEARTBEAT_ACK)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct InterviewPrompt {
+    id: String,
+    prompt: String,
+    max_tokens: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct InterviewResult {
+    #[serde(rename = "type")]
+    msg_type: String,
+    interview_id: String,
+    model: String,
+    results: Vec<PromptResult>,
+}
+
+#[derive(Debug, Serialize)]
+struct PromptResult {
+    prompt_id: String,
+    response: String,
+    ttft_ms: u32,
+    total_ms: u32,
+    tokens_generated: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct InferencePayload {
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    stream: bool,
+    #[serde(default)]
+    logprobs: Option<bool>,
+    #[serde(default)]
+    top_logprobs: Option<u32>,
+    #[serde(default)]
+    response_format: Option<serde_json::Value>,
+    #[serde(default)]
+    include_reasoning: bool,
+    #[serde(default)]
+    region: Option<String>,
+}
+
+impl InferencePayload {
+    fn generation_options(&self) -> GenerationOptions {
+        GenerationOptions {
+            logprobs: self.logprobs,
+            top_logprobs: self.top_logprobs,
+            response_format: self.response_format.clone(),
+            max_tokens: None,
+        }
+    }
+}
+
+/// Upper bound on `top_logprobs` accepted by both the OpenAI API and Ollama.
+const MAX_TOP_LOGPROBS: u32 = 20;
+
+/// Optional request parameters forwarded to the backend as given.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct GenerationOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    logprobs: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    top_logprobs: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response_format: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+}
+
+impl GenerationOptions {
+    fn wants_logprobs(&self) -> bool {
+        self.logprobs == Some(true) || self.top_logprobs.is_some()
+    }
+
+    /// Ollama takes structured output as `format`: `"json"` for JSON mode or
+    /// the schema itself, and the output limit as `options.num_predict`.
+    /// Returns the options with `response_format` and `max_tokens` moved
+    /// into those forms.
+    fn for_ollama(&self) -> (GenerationOptions, Option<serde_json::Value>, Option<serde_json::Value>) {
+        let mut options = self.clone();
+        let format = options.response_format.take().and_then(|rf| match rf["type"].as_str() {
+            Some("json_object") => Some(serde_json::json!("json")),
+            Some("json_schema") => Some(rf["json_schema"]["schema"].clone()),
+            _ => None,
+        });
+        let model_options = options.max_tokens.take().map(|n| serde_json::json!({ "num_predict": n }));
+        (options, format, model_options)
+    }
+}
+
+/// Checks `response_format` has one of the OpenAI shapes: `{"type": "text"}`,
+/// `{"type": "json_object"}` or `{"type": "json_schema", "json_schema": {"schema": {...}}}`.
+fn validate_response_format(rf: &serde_json::Value) -> Result<(), String> {
+    match rf["type"].as_str() {
+        Some("text") | Some("json_object") => Ok(()),
+        Some("json_schema") if rf["json_schema"]["schema"].is_object() => Ok(()),
+        Some("json_schema") => Err("invalid_request: response_format json_schema needs a json_schema.schema object".to_string()),
+        Some(other) => Err(format!("invalid_request: unknown response_format type '{}'", other)),
+        None => Err("invalid_request: response_format must be an object with a type".to_string()),
+    }
+}
+
+/// Declines requests outside the node's `accept` filter, so the server can
+/// route them to another node instead.
+fn check_acceptance(node: &NodeConfig, payload: &InferencePayload) -> Result<(), String> {
+    let Some(ref accept) = node.accept else { return Ok(()) };
+    if !node.accepts_model(&payload.model) {
+        return Err(format!("not_accepted: node {} does not serve model {}", node.alias, payload.model));
+    }
+    if let Some(region) = payload.region.as_deref().filter(|r| accept.match_region && !r.eq_ignore_ascii_case(&node.region)) {
+        return Err(format!("not_accepted: node {} is in region {}, not {}", node.alias, node.region, region));
+    }
+    Ok(())
+}
+
+/// Rejects options the node's backend can't honour, so a client never gets a
+/// response silently missing what it asked for.
+fn check_backend_support(node: &NodeConfig, options: &GenerationOptions) -> Result<(), String> {
+    if options.wants_logprobs() && !node.supports_logprobs() {
+        return Err(format!("unsupported: logprobs are not enabled on node {} ({} backend)", node.alias, node.api_mode));
+    }
+    if let Some(n) = options.top_logprobs.filter(|&n| n > MAX_TOP_LOGPROBS) {
+        return Err(format!("invalid_request: top_logprobs must be at most {} (got {})", MAX_TOP_LOGPROBS, n));
+    }
+    if let Some(ref rf) = options.response_format {
+        validate_response_format(rf)?;
+        if !node.structured_output && rf["type"] != "text" {
+            return Err(format!("unsupported: structured output (response_format) is disabled on node {}", node.alias));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChatMessage {
+    role: String,
+    content: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AuthMessage {
+    #[serde(rename = "type")]
+    msg_type: String,
+    client_id: String,
+    timestamp: String,
+    signature: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ClientMessage {
+    #[serde(rename = "type")]
+    msg_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// The category of `error` as a stable code, e.g. `NOT_ACCEPTED`, so the
+    /// server can act on it without parsing the message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    models: Option<Vec<String>>,
+    /// Opaque data from the request, echoed back verbatim for correlation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Value>,
+}
+
+impl ClientMessage {
+    fn inference_error(request_id: String, error: String, metadata: Option<serde_json::Value>) -> Self {
+        ClientMessage {
+            msg_type: "INFERENCE_ERROR".to_string(),
+            request_id: Some(request_id),
+            result: None,
+            code: error_code(&error),
+            error: Some(error),
+            models: None,
+            metadata,
+        }
+    }
+
+    /// The `INFERENCE_RESPONSE` carrying `result`. A result that can't be
+    /// encoded as JSON is counted and answered with an `INFERENCE_ERROR`
+    /// instead, so the request isn't left unanswered.
+    fn inference_response<T: Serialize>(request_id: String, result: &T, metadata: Option<serde_json::Value>, metrics: &Metrics) -> Self {
+        match serde_json::to_value(result) {
+            Ok(value) => ClientMessage {
+                msg_type: "INFERENCE_RESPONSE".to_string(),
+                request_id: Some(request_id),
+                result: Some(value),
+                error: None,
+                code: None,
+                models: None,
+                metadata,
+            },
+            Err(e) => {
+                metrics.serialization_failures.fetch_add(1, Ordering::SeqCst);
+                error!("Failed to serialize response for {}: {}", request_id, e);
+                Self::inference_error(request_id, format!("Failed to serialize response: {}", e), metadata)
+            }
+        }
+    }
+}
+
+/// The code for an error that starts with a `snake_case:` category, such as
+/// `not_accepted: ...`. Errors passed through from a backend have none.
+fn error_code(error: &str) -> Option<String> {
+    let (category, _) = error.split_once(':')?;
+    let is_category = !category.is_empty() && category.bytes().all(|b| b.is_ascii_lowercase() || b == b'_');
+    is_category.then(|| category.to_ascii_uppercase())
+}
+
+/// An `INFERENCE_RESPONSE` whose `result` JSON has been gzipped and
+/// base64-encoded into `data`; the server inflates it back.
+#[derive(Debug, Serialize)]
+struct CompressedResponseMessage {
+    #[serde(rename = "type")]
+    msg_type: &'static str,
+    request_id: String,
+    compressed: bool,
+    encoding: &'static str,
+    original_size: usize,
+    data: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Value>,
+}
+
+/// Returns the `COMPRESSED_RESPONSE` frame for a successful response whose
+/// result exceeds the threshold, or `None` to send the response unchanged.
+fn compress_response(response: &ClientMessage, limits: &ResponseCompressionConfig) -> Option<String> {
+    use base64::Engine;
+    use std::io::Write;
+
+    if !limits.enabled || response.msg_type != "INFERENCE_RESPONSE" {
+        return None;
+    }
+    let raw = serde_json::to_vec(response.result.as_ref()?).ok()?;
+    if raw.len() <= limits.threshold_bytes {
+        return None;
+    }
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&raw).ok()?;
+    let data = base64::engine::general_purpose::STANDARD.encode(encoder.finish().ok()?);
+    if data.len() >= raw.len() {
+        return None;
+    }
+    serde_json::to_string(&CompressedResponseMessage {
+        msg_type: "COMPRESSED_RESPONSE",
+        request_id: response.request_id.clone()?,
+        compressed: true,
+        encoding: "gzip",
+        original_size: raw.len(),
+        data,
+        metadata: response.metadata.clone(),
+    })
+    .ok()
+}
+
+/// Puts a redelivered request's metadata on a memoized response, which
+/// still carries the metadata of the request that produced it.
+fn with_metadata(json: String, metadata: &Option<serde_json::Value>) -> String {
+    let Ok(serde_json::Value::Object(mut message)) = serde_json::from_str::<serde_json::Value>(&json) else {
+        return json;
+    };
+    match metadata {
+        Some(metadata) => message.insert("metadata".to_string(), metadata.clone()),
+        None => message.remove("metadata"),
+    };
+    serde_json::Value::Object(message).to_string()
+}
+
+#[derive(Debug, Serialize)]
+struct RegisterNodeMessage {
+    #[serde(rename = "type")]
+    msg_type: String,
+    alias: String,
+    models: Vec<String>,
+    capacity: u32,
+    region: String,
+    #[serde(rename = "pricePerThousandTokens")]
+    price_per_thousand_tokens: f64,
+    #[serde(rename = "interviewModel", skip_serializing_if = "Option::is_none")]
+    interview_model: Option<String>,
+    #[serde(rename = "configFingerprint", skip_serializing_if = "Option::is_none")]
+    config_fingerprint: Option<String>,
+    #[serde(rename = "coldTtftMs", skip_serializing_if = "Option::is_none")]
+    cold_ttft_ms: Option<u64>,
+    #[serde(rename = "readyTtftMs", skip_serializing_if = "Option::is_none")]
+    ready_ttft_ms: Option<u64>,
+}
+
+impl RegisterNodeMessage {
+    fn new(node: &NodeConfig, models: Vec<String>, capacity: u32) -> Self {
+        RegisterNodeMessage {
+            msg_type: "REGISTER_NODE".to_string(),
+            alias: node.alias.clone(),
+            models,
+            capacity,
+            region: node.region.clone(),
+            price_per_thousand_tokens: node.price_per_thousand_tokens,
+            interview_model: node.interview_model.clone(),
+            config_fingerprint: CONFIG_FINGERPRINT.get().cloned(),
+            cold_ttft_ms: None,
+            ready_ttft_ms: None,
+        }
+    }
+
+    fn with_warmup(mut self, warmup: Option<WarmupResult>) -> Self {
+        if let Some(w) = warmup {
+            self.cold_ttft_ms = Some(w.cold_ttft_ms);
+            self.ready_ttft_ms = Some(w.ready_ttft_ms);
+        }
+        self
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct HeartbeatMessage {
+    #[serde(rename = "type")]
+    msg_type: String,
+    #[serde(rename = "configFingerprint", skip_serializing_if = "Option::is_none")]
+    config_fingerprint: Option<String>,
+    #[serde(rename = "uptimeSecs", skip_serializing_if = "Option::is_none")]
+    uptime_secs: Option<u64>,
+    #[serde(rename = "connectionUptimeSecs", skip_serializing_if = "Option::is_none")]
+    connection_uptime_secs: Option<u64>,
+    #[serde(rename = "restartReason", skip_serializing_if = "Option::is_none")]
+    restart_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nodes: Option<Vec<HeartbeatNode>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HeartbeatNode {
+    alias: String,
+    in_flight: u32,
+    requests: u64,
+    errors: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GoingAwayMessage {
+    #[serde(rename = "type")]
+    msg_type: String,
+    nodes: Vec<String>,
+    reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected_return_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct NodeStatsMessage {
+    #[serde(rename = "type")]
+    msg_type: String,
+    #[serde(rename = "intervalSecs")]
+    interval_secs: u64,
+    nodes: Vec<NodeStatsEntry>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NodeStatsEntry {
+    alias: String,
+    requests: u64,
+    errors: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_rate: Option<f64>,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_avg_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_p50_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_p95_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_p99_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct UpdateWalletMessage {
+    #[serde(rename = "type")]
+    msg_type: String,
+    payout_address: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<serde_json::Value>,
+    #[serde(rename = "options", skip_serializing_if = "Option::is_none")]
+    model_options: Option<serde_json::Value>,
+    #[serde(flatten)]
+    options: GenerationOptions,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaChatResponse {
+    model: String,
+    message: ChatMessage,
+    done: bool,
+    #[serde(default)]
+    prompt_eval_count: Option<u32>,
+    #[serde(default)]
+    eval_count: Option<u32>,
+    #[serde(default)]
+    logprobs: Option<Vec<serde_json::Value>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenAIChoice {
+    index: u32,
+    message: ChatMessage,
+    finish_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    logprobs: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenAIUsage {
+    prompt_tokens: u32,
+    completion_tokens: u32,
+    total_tokens: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenAIResponse {
+    choices: Vec<OpenAIChoice>,
+    #[serde(default)]
+    usage: Option<OpenAIUsage>,
+    model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    empty_response: Option<bool>,
+    /// The requested model, when `model` is a fallback served in its place.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fallback_from: Option<String>,
+    /// Reasoning stripped by `stripReasoning`, for requests that ask for it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reasoning: Option<String>,
+    /// Set when the response is the result of an identical request that
+    /// was already running.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    coalesced: Option<bool>,
+    /// Inter-token latency of a streamed generation, with `tokenTimings`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token_timings: Option<TokenTimings>,
+    /// `INFERENCE_CHUNK`s sent before a streamed response, so the server can
+    /// tell whether it has them all.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chunks: Option<u32>,
+}
+
+/// Inter-token latency of a streamed generation: the number of gaps
+/// between backend deltas, and their mean, 95th percentile and maximum.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct TokenTimings {
+    gaps: u64,
+    mean_ms: f64,
+    p95_ms: f64,
+    max_ms: f64,
+}
+
+impl OpenAIResponse {
+    /// No choices, or only whitespace in every choice's content.
+    fn is_empty(&self) -> bool {
+        self.choices.iter().all(|c| c.message.content.trim().is_empty())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaModelsResponse {
+    models: Vec<OllamaModel>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaModel {
+    name: String,
+}
+
+/// Short, stable hash of the daemon version and the effective config (after
+/// profiles), with the API secret redacted. JSON object keys serialize in
+/// sorted order, so it doesn't depend on how the config file is laid out.
+fn config_fingerprint(config: &Config) -> String {
+    let mut value = serde_json::to_value(config).unwrap_or(serde_json::Value::Null);
+    if let Some(root) = value.as_object_mut() {
+        root.insert("apiSecret".to_string(), serde_json::json!("<redacted>"));
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    hasher.update(b"\n");
+    hasher.update(value.to_string().as_bytes());
+    hex::encode(hasher.finalize())[..16].to_string()
+}
+
+fn compute_signature(client_id: &str, timestamp: &str, api_secret: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(api_secret.as_bytes());
+    let secret_hash = hex::encode(hasher.finalize());
+
+    let mut sig_hasher = Sha256::new();
+    sig_hasher.update(format!("{}{}{}", client_id, timestamp, secret_hash).as_bytes());
+    hex::encode(sig_hasher.finalize())
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIModelsResponse {
+    data: Vec<OpenAIModelInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIModelInfo {
+    id: String,
+}
+
+/// HTTP clients for talking to backends. Each node gets its own client, and
+/// so its own connection pool, built from its `connectionPool` settings;
+/// `shared` serves everything that isn't tied to a node, such as webhooks.
+struct BackendClients {
+    shared: BackendClient,
+    nodes: std::collections::HashMap<String, BackendClient>,
+}
+
+impl BackendClients {
+    fn node(&self, alias: &str) -> &BackendClient {
+        self.nodes.get(alias).unwrap_or(&self.shared)
+    }
+}
+
+#[derive(Clone)]
+struct BackendClient {
+    http: BackendHttp,
+    connections: Option<Arc<tokio::sync::Semaphore>>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl BackendClient {
+    /// Waits for a free backend connection when `maxConnections` is set. The
+    /// returned guard counts the request as in flight until it is dropped.
+    async fn acquire(&self) -> BackendSlot {
+        let permit = match self.connections {
+            Some(ref sem) => Some(sem.clone().acquire_owned().await.expect("semaphore closed")),
+            None => None,
+        };
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        BackendSlot { _permit: permit, in_flight: self.in_flight.clone() }
+    }
+}
+
+struct BackendSlot {
+    _permit: Option<tokio::sync::OwnedSemaphorePermit>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl BackendSlot {
+    /// Other requests using the same backend right now.
+    fn others_in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst).saturating_sub(1)
+    }
+}
+
+impl Drop for BackendSlot {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Content type of backend request bodies unless a node sets `contentType`.
+const DEFAULT_CONTENT_TYPE: &str = "application/json";
+
+/// A backend's HTTP client and the `Content-Type` its request bodies are
+/// sent with. The header is set on each POST, so GETs don't carry it.
+#[derive(Clone)]
+struct BackendHttp {
+    client: reqwest::Client,
+    content_type: reqwest::header::HeaderValue,
+}
+
+impl BackendHttp {
+    fn get(&self, url: &str) -> reqwest::RequestBuilder {
+        self.client.get(url)
+    }
+
+    /// A POST with `body` encoded as JSON and labelled with the node's
+    /// `contentType`.
+    fn post_json<T: Serialize>(&self, url: &str, body: &T) -> Result<reqwest::RequestBuilder, String> {
+        let body = serde_json::to_vec(body).map_err(|e| format!("failed to encode request: {}", e))?;
+        Ok(self.client.post(url).header(reqwest::header::CONTENT_TYPE, self.content_type.clone()).body(body))
+    }
+}
+
+fn http_client_builder(config: &Config, tls: Option<&rustls::ClientConfig>) -> reqwest::ClientBuilder {
+    let mut builder = reqwest::Client::builder()
+        .gzip(config.backend_decompression)
+        .deflate(config.backend_decompression);
+    if let Some(tls) = tls {
+        builder = builder.use_preconfigured_tls(tls.clone());
+    }
+    builder
+}
+
+/// Builds the backend clients. Built once so connections are pooled across
+/// requests; compressed responses are decoded transparently unless
+/// `backendDecompression` is turned off for a misbehaving backend.
+fn build_http_client(config: &Config, tls: Option<&rustls::ClientConfig>) -> Result<BackendClients, String> {
+    let shared = BackendClient {
+        http: BackendHttp {
+            client: http_client_builder(config, tls).build().map_err(|e| e.to_string())?,
+            content_type: reqwest::header::HeaderValue::from_static(DEFAULT_CONTENT_TYPE),
+        },
+        connections: None,
+        in_flight: Arc::new(AtomicUsize::new(0)),
+    };
+    let mut nodes = std::collections::HashMap::new();
+    for node in &config.nodes {
+        let content_type = node.content_type.as_deref().unwrap_or(DEFAULT_CONTENT_TYPE);
+        debug!("[BACKEND] {} sends backend requests as Content-Type: {}", node.alias, content_type);
+        let content_type = reqwest::header::HeaderValue::from_str(content_type).map_err(|e| format!("node {} contentType: {}", node.alias, e))?;
+        let mut builder = http_client_builder(config, tls);
+        if let Some(ref name) = node.tls_server_name {
+            let addrs = node.backend_addrs().map_err(|e| format!("node {} tlsServerName: {}", node.alias, e))?;
+            builder = builder.resolve_to_addrs(name, &addrs);
+        }
+        let pool = &node.connection_pool;
+        if let Some(idle) = pool.max_idle_per_host.or(pool.max_connections) {
+            builder = builder.pool_max_idle_per_host(idle.min(pool.max_connections.unwrap_or(usize::MAX)));
+        }
+        let opened = Arc::new(AtomicU64::new(0));
+        let alias = node.alias.clone();
+        builder = builder.connector_layer(tower::util::MapResponseLayer::new(move |conn| {
+            let total = opened.fetch_add(1, Ordering::SeqCst) + 1;
+            debug!("[POOL] {} opened a backend connection ({} so far)", alias, total);
+            conn
+        }));
+        let client = BackendClient {
+            http: BackendHttp { client: builder.build().map_err(|e| format!("node {}: {}", node.alias, e))?, content_type },
+            connections: pool.max_connections.map(|n| Arc::new(tokio::sync::Semaphore::new(n))),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        };
+        nodes.insert(node.alias.clone(), client);
+    }
+    Ok(BackendClients { shared, nodes })
+}
+
+/// Formats a failed backend request. The backend refusing or dropping the
+/// connection gets its own `backend_unreachable` category.
+fn backend_request_error(api: &str, e: &reqwest::Error) -> String {
+    let mut source = std::error::Error::source(e);
+    let mut dropped = false;
+    while let Some(err) = source {
+        if let Some(io) = err.downcast_ref::<std::io::Error>() {
+            dropped |= matches!(io.kind(), std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted);
+        }
+        source = err.source();
+    }
+    if e.is_connect() || dropped {
+        format!("backend_unreachable: {} request failed: {}", api, e)
+    } else {
+        format!("{} request failed: {}", api, e)
+    }
+}
+
+/// rustls names TLS 1.3 suites `TLS13_*`; the IANA `TLS_*` spelling is
+/// accepted too.
+fn cipher_suite_matches(suite: &rustls::SupportedCipherSuite, name: &str) -> bool {
+    let rustls_name = format!("{:?}", suite.suite());
+    rustls_name == name || rustls_name.replacen("TLS13_", "TLS_", 1) == name
+}
+
+/// Builds the rustls client config used for both the server connection and
+/// HTTPS backends when `tls` restrictions are configured. Certificates are
+/// verified against the system trust store, as with the default TLS stack.
+fn build_tls_config(tls: &TlsConfig) -> Result<rustls::ClientConfig, String> {
+    let versions: &[&'static rustls::SupportedProtocolVersion] = match tls.min_version.as_str() {
+        "1.2" => &[&rustls::version::TLS13, &rustls::version::TLS12],
+        "1.3" => &[&rustls::version::TLS13],
+        other => return Err(format!("tls.minVersion must be \"1.2\" or \"1.3\" (got \"{}\")", other)),
+    };
+
+    let mut provider = rustls::crypto::ring::default_provider();
+    if !tls.cipher_suites.is_empty() {
+        if let Some(unknown) = tls.cipher_suites.iter().find(|name| !provider.cipher_suites.iter().any(|s| cipher_suite_matches(s, name))) {
+            let supported: Vec<String> = provider.cipher_suites.iter().map(|s| format!("{:?}", s.suite())).collect();
+            return Err(format!("tls.cipherSuites: unknown cipher suite {} (supported: {})", unknown, supported.join(", ")));
+        }
+        provider.cipher_suites.retain(|s| tls.cipher_suites.iter().any(|name| cipher_suite_matches(s, name)));
+    }
+
+    let native = rustls_native_certs::load_native_certs();
+    for e in &native.errors {
+        warn!("Failed to load a system root certificate: {}", e);
+    }
+    let mut roots = rustls::RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(native.certs);
+    if added == 0 {
+        return Err("no system root certificates could be loaded".to_string());
+    }
+
+    Ok(rustls::ClientConfig::builder_with_provider(Arc::new(provider))
+        .with_protocol_versions(versions)
+        .map_err(|e| format!("no allowed cipher suite works with TLS {} or later: {}", tls.min_version, e))?
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+async fn get_ollama_models(client: &BackendHttp, base_url: &str) -> Result<Vec<String>, String> {
+    let url = format!("{}/api/tags", base_url.trim_end_matches('/'));
+
+    let response = client
+        .get(&url)
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await
+        .map_err(|e| format!("Failed to connect to Ollama: {}", e))?;
+
+    let data: OllamaModelsResponse = response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse response: {}", e))?;
+
+    Ok(data.models.iter().map(|m| m.name.clone()).collect())
+}
+
+async fn get_openai_models(client: &BackendHttp, base_url: &str) -> Result<Vec<String>, String> {
+    let url = format!("{}/v1/models", base_url.trim_end_matches('/'));
+
+    let response = client
+        .get(&url)
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await
+        .map_err(|e| format!("Failed to connect to OpenAI-compatible API: {}", e))?;
+
+    let data: OpenAIModelsResponse = response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse response: {}", e))?;
+
+    Ok(data.data.iter().map(|m| m.id.clone()).collect())
+}
+
+async fn get_models(client: &BackendHttp, base_url: &str, api_mode: &str) -> Result<Vec<String>, String> {
+    match api_mode {
+        "openai" => get_openai_models(client, base_url).await,
+        _ => get_ollama_models(client, base_url).await,
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct OpenAIChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
     #[serde(skip_serializing_if = "Option::is_none")]
     stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<serde_json::Value>,
+    #[serde(flatten)]
+    options: GenerationOptions,
+}
+
+async fn chat_completion_ollama(
+    client: &BackendHttp,
+    base_url: &str,
+    model: &str,
+    messages: Vec<ChatMessage>,
+    options: &GenerationOptions,
+) -> Result<OpenAIResponse, String> {
+    let url = format!("{}/api/chat", base_url.trim_end_matches('/'));
+
+    let (options, format, model_options) = options.for_ollama();
+    let request = OllamaChatRequest {
+        model: model.to_string(),
+        messages,
+        stream: Some(false),
+        format,
+        model_options,
+        options,
+    };
+
+    let response = client
+        .post_json(&url, &request)?
+        .timeout(Duration::from_secs(120))
+        .send()
+        .await
+        .map_err(|e| backend_request_error("Ollama", &e))?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!("Ollama error {}: {}", status, body));
+    }
+
+    let ollama_resp: OllamaChatResponse = response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse Ollama response: {}", e))?;
+
+    let prompt_tokens = ollama_resp.prompt_eval_count.unwrap_or(0);
+    let completion_tokens = ollama_resp.eval_count.unwrap_or(0);
+
+    Ok(OpenAIResponse {
+        model: ollama_resp.model,
+        choices: vec![OpenAIChoice {
+            index: 0,
+            message: ollama_resp.message,
+            finish_reason: Some("stop".to_string()),
+            logprobs: ollama_resp.logprobs.map(|tokens| serde_json::json!({ "content": tokens })),
+        }],
+        usage: Some(OpenAIUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }),
+        empty_response: None,
+        fallback_from: None,
+        reasoning: None,
+        coalesced: None,
+        token_timings: None,
+        chunks: None,
+    })
+}
+
+async fn chat_completion_openai(
+    client: &BackendHttp,
+    base_url: &str,
+    model: &str,
+    messages: Vec<ChatMessage>,
+    options: &GenerationOptions,
+) -> Result<OpenAIResponse, String> {
+    let url = format!("{}/v1/chat/completions", base_url.trim_end_matches('/'));
+
+    let request = OpenAIChatRequest {
+        model: model.to_string(),
+        messages,
+        stream: Some(false),
+        stream_options: None,
+        options: options.clone(),
+    };
+
+    let response = client
+        .post_json(&url, &request)?
+        .timeout(Duration::from_secs(120))
+        .send()
+        .await
+        .map_err(|e| backend_request_error("OpenAI", &e))?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!("OpenAI error {}: {}", status, body));
+    }
+
+    response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse OpenAI response: {}", e))
+}
+
+async fn chat_completion(
+    client: &BackendHttp,
+    base_url: &str,
+    model: &str,
+    messages: Vec<ChatMessage>,
+    options: &GenerationOptions,
+    api_mode: &str,
+) -> Result<OpenAIResponse, String> {
+    match api_mode {
+        "openai" => chat_completion_openai(client, base_url, model, messages, options).await,
+        _ => chat_completion_ollama(client, base_url, model, messages, options).await,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIStreamChunk {
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    choices: Vec<OpenAIStreamChoice>,
+    #[serde(default)]
+    usage: Option<OpenAIUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIStreamChoice {
+    #[serde(default)]
+    delta: OpenAIDelta,
+    #[serde(default)]
+    finish_reason: Option<String>,
+    #[serde(default)]
+    logprobs: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct OpenAIDelta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+/// Bound on queued outbound messages. Once full, inference tasks wait for the
+/// writer before producing more, which throttles streaming backends.
+const RESPONSE_CHANNEL_CAPACITY: usize = 64;
+
+/// Message queued for the connection writer. `seq` is set on the final
+/// message of an inference request so it can be released in request order
+/// when `orderedResponses` is enabled.
+#[derive(Debug)]
+struct Outbound {
+    seq: Option<u64>,
+    json: String,
+}
+
+impl Outbound {
+    fn unordered(json: String) -> Self {
+        Outbound { seq: None, json }
+    }
+}
+
+/// Releases final inference responses in the order their requests arrived.
+/// A response is held back while an earlier request is still running, until
+/// either the window fills up or the head request has blocked for longer than
+/// the timeout; the slow request is then skipped and sent whenever it lands.
+struct ReorderBuffer {
+    enabled: bool,
+    window: usize,
+    timeout: Duration,
+    next_seq: u64,
+    pending: std::collections::BTreeMap<u64, String>,
+    blocked_since: Option<tokio::time::Instant>,
+}
+
+impl ReorderBuffer {
+    fn new(config: &Config) -> Self {
+        ReorderBuffer {
+            enabled: config.ordered_responses,
+            window: config.reorder_window,
+            timeout: Duration::from_millis(config.reorder_timeout_ms),
+            next_seq: 0,
+            pending: std::collections::BTreeMap::new(),
+            blocked_since: None,
+        }
+    }
+
+    fn accept(&mut self, out: Outbound) -> Vec<String> {
+        match out.seq {
+            Some(seq) if self.enabled && seq >= self.next_seq => {
+                self.pending.insert(seq, out.json);
+                if self.pending.len() > self.window {
+                    self.skip_head("reorder window full");
+                }
+                self.drain()
+            }
+            _ => vec![out.json],
+        }
+    }
+
+    fn deadline(&self) -> Option<tokio::time::Instant> {
+        self.blocked_since.map(|t| t + self.timeout)
+    }
+
+    fn expire(&mut self) -> Vec<String> {
+        self.skip_head("reorder timeout");
+        self.drain()
+    }
+
+    fn skip_head(&mut self, reason: &str) {
+        if let Some(&first) = self.pending.keys().next() {
+            warn!("[ORDER] {} - releasing responses past request(s) {}..{} still in flight", reason, self.next_seq, first);
+            self.next_seq = first;
+        }
+    }
+
+    fn drain(&mut self) -> Vec<String> {
+        let mut ready = Vec::new();
+        while let Some(json) = self.pending.remove(&self.next_seq) {
+            ready.push(json);
+            self.next_seq += 1;
+        }
+
+        if self.pending.is_empty() {
+            self.blocked_since = None;
+        } else if !ready.is_empty() || self.blocked_since.is_none() {
+            self.blocked_since = Some(tokio::time::Instant::now());
+        }
+        ready
+    }
+}
+
+/// Forwards streamed deltas to the server as `INFERENCE_CHUNK` messages.
+/// Each chunk is accepted by the bounded response channel before the next one
+/// is read from the backend, so a slow server link slows backend consumption
+/// rather than buffering the generation in memory.
+///
+/// Chunks carry `index`, counting from 0 for each request. One forwarder
+/// serves a request for its whole life (retries included), and the channel
+/// and the writer keep its order, so a request's chunks always arrive in
+/// index order and before its final response. Chunks of different requests
+/// may interleave.
+///
+/// Deltas are coalesced according to the node's `stream` settings and the
+/// total response is capped at `maxResponseBytes`; once the cap is hit the
+/// caller stops reading and the response finishes with `length`.
+struct ChunkForwarder {
+    request_id: String,
+    metadata: Option<serde_json::Value>,
+    tx: mpsc::Sender<Outbound>,
+    index: u32,
+    limits: StreamConfig,
+    pending: String,
+    pending_deltas: usize,
+    last_flush: std::time::Instant,
+    content: String,
+    truncated: bool,
+    first_token: Option<std::time::Instant>,
+    reasoning: Option<ReasoningFilter>,
+    gaps: Option<TokenGaps>,
+}
+
+impl ChunkForwarder {
+    fn new(request_id: String, metadata: Option<serde_json::Value>, tx: mpsc::Sender<Outbound>, limits: StreamConfig) -> Self {
+        ChunkForwarder {
+            request_id,
+            metadata,
+            tx,
+            index: 0,
+            pending: String::new(),
+            pending_deltas: 0,
+            last_flush: std::time::Instant::now(),
+            content: String::new(),
+            truncated: false,
+            first_token: None,
+            reasoning: None,
+            gaps: limits.token_timings.then(TokenGaps::new),
+            limits,
+        }
+    }
+
+    /// Strips reasoning blocks from the deltas before they are forwarded.
+    fn with_reasoning_filter(mut self, config: Option<&ReasoningConfig>) -> Self {
+        self.reasoning = config.map(ReasoningFilter::new);
+        self
+    }
+
+    /// The reasoning stripped so far, if there was any.
+    fn take_reasoning(&mut self) -> Option<String> {
+        self.reasoning.as_mut().map(|r| std::mem::take(&mut r.reasoning)).filter(|r| !r.is_empty())
+    }
+
+    /// Inter-token latency of the generation so far, with `tokenTimings`.
+    fn token_timings(&self) -> Option<TokenTimings> {
+        self.gaps.as_ref().and_then(TokenGaps::summary)
+    }
+
+    /// Buffers a delta and flushes when the cadence says so. Returns `false`
+    /// once the response size cap has been reached.
+    async fn forward(&mut self, delta: &str) -> Result<bool, String> {
+        if !delta.is_empty() {
+            let now = std::time::Instant::now();
+            self.first_token.get_or_insert(now);
+            if let Some(ref mut gaps) = self.gaps {
+                gaps.record(now);
+            }
+        }
+        let visible;
+        let mut delta = match self.reasoning {
+            Some(ref mut filter) => {
+                visible = filter.push(delta);
+                visible.as_str()
+            }
+            None => delta,
+        };
+        if let Some(max) = self.limits.max_response_bytes {
+            let remaining = max.saturating_sub(self.content.len());
+            if delta.len() > remaining {
+                let mut cut = remaining;
+                while !delta.is_char_boundary(cut) {
+                    cut -= 1;
+                }
+                delta = &delta[..cut];
+                self.truncated = true;
+            }
+        }
+
+        if !delta.is_empty() {
+            self.content.push_str(delta);
+            self.pending.push_str(delta);
+            self.pending_deltas += 1;
+        }
+
+        if self.pending_deltas >= self.limits.flush_tokens
+            || self.pending.len() >= self.limits.buffer_bytes
+            || (self.limits.flush_interval_ms > 0
+                && self.last_flush.elapsed() >= Duration::from_millis(self.limits.flush_interval_ms))
+        {
+            self.flush().await?;
+        }
+        Ok(!self.truncated)
+    }
+
+    async fn flush(&mut self) -> Result<(), String> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let content = std::mem::take(&mut self.pending);
+        let chunk = ClientMessage {
+            msg_type: "INFERENCE_CHUNK".to_string(),
+            request_id: Some(self.request_id.clone()),
+            result: Some(serde_json::json!({ "index": self.index, "content": content })),
+            error: None,
+            code: None,
+            models: None,
+            metadata: self.metadata.clone(),
+        };
+        let json = serde_json::to_string(&chunk).map_err(|e| format!("Failed to encode chunk: {}", e))?;
+        self.tx
+            .send(Outbound::unordered(json))
+            .await
+            .map_err(|_| "Response channel closed".to_string())?;
+        self.index += 1;
+        self.pending_deltas = 0;
+        self.last_flush = std::time::Instant::now();
+        Ok(())
+    }
+
+    /// Flushes anything still buffered and hands back the full content and
+    /// whether it was truncated by the size cap.
+    async fn finish(&mut self) -> Result<(String, bool), String> {
+        if let Some(rest) = self.reasoning.as_mut().map(|f| f.finish()).filter(|r| !r.is_empty()) {
+            self.content.push_str(&rest);
+            self.pending.push_str(&rest);
+        }
+        self.flush().await?;
+        if self.truncated {
+            warn!("Streamed response for {} truncated at {} bytes", self.request_id, self.content.len());
+        }
+        Ok((std::mem::take(&mut self.content), self.truncated))
+    }
+}
+
+const MAX_TOKEN_GAP_SAMPLES: usize = 1024;
+
+/// Gaps between consecutive backend deltas of a stream. Count, mean and
+/// max are exact; the percentile comes from a sample that keeps every
+/// `stride`-th gap and halves itself (doubling the stride) when full, so
+/// memory stays bounded however long the generation runs.
+#[derive(Debug)]
+struct TokenGaps {
+    last: Option<std::time::Instant>,
+    count: u64,
+    total_us: u64,
+    max_us: u64,
+    samples: Vec<u64>,
+    stride: u64,
+}
+
+impl TokenGaps {
+    fn new() -> Self {
+        TokenGaps { last: None, count: 0, total_us: 0, max_us: 0, samples: Vec::new(), stride: 1 }
+    }
+
+    fn record(&mut self, now: std::time::Instant) {
+        let Some(last) = self.last.replace(now) else { return };
+        let gap = now.duration_since(last).as_micros() as u64;
+        self.count += 1;
+        self.total_us += gap;
+        self.max_us = self.max_us.max(gap);
+        if self.count.is_multiple_of(self.stride) {
+            self.samples.push(gap);
+            if self.samples.len() >= MAX_TOKEN_GAP_SAMPLES {
+                self.samples = self.samples.iter().skip(1).step_by(2).copied().collect();
+                self.stride *= 2;
+            }
+        }
+    }
+
+    fn summary(&self) -> Option<TokenTimings> {
+        if self.count == 0 {
+            return None;
+        }
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+        let ms = |us: u64| (us as f64 / 100.0).round() / 10.0;
+        Some(TokenTimings {
+            gaps: self.count,
+            mean_ms: ms(self.total_us / self.count),
+            p95_ms: ms(percentile(&sorted, 95.0).unwrap_or(self.max_us)),
+            max_ms: ms(self.max_us),
+        })
+    }
+}
+
+/// Separates reasoning blocks from visible text in a stream of deltas. A
+/// tag may be split across deltas, so text that could be the start of one
+/// is held back until the next delta shows whether it is.
+struct ReasoningFilter {
+    /// Opening and closing tag of each configured block.
+    tags: Vec<(String, String)>,
+    /// The block currently being stripped, as an index into `tags`.
+    inside: Option<usize>,
+    held: String,
+    /// Leading whitespace after a block is dropped, as models usually put
+    /// a blank line between their reasoning and the answer.
+    after_block: bool,
+    reasoning: String,
+}
+
+impl ReasoningFilter {
+    fn new(config: &ReasoningConfig) -> Self {
+        ReasoningFilter {
+            tags: config.tags.iter().map(|t| (format!("<{}>", t), format!("</{}>", t))).collect(),
+            inside: None,
+            held: String::new(),
+            after_block: false,
+            reasoning: String::new(),
+        }
+    }
+
+    /// Length of the longest end of `text` that is a proper prefix of `tag`.
+    fn partial_tag(text: &str, tag: &str) -> usize {
+        tag.char_indices()
+            .skip(1)
+            .map(|(i, _)| i)
+            .filter(|&i| text.ends_with(&tag[..i]))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Takes the next delta and returns the part of it that is visible.
+    fn push(&mut self, delta: &str) -> String {
+        let mut input = std::mem::take(&mut self.held);
+        input.push_str(delta);
+        let mut visible = String::new();
+        let mut rest = input.as_str();
+        loop {
+            match self.inside {
+                None => {
+                    let next = self.tags.iter().enumerate().filter_map(|(i, (open, _))| rest.find(open.as_str()).map(|pos| (pos, i))).min();
+                    if let Some((pos, i)) = next {
+                        self.emit(&mut visible, &rest[..pos]);
+                        rest = &rest[pos + self.tags[i].0.len()..];
+                        self.inside = Some(i);
+                        continue;
+                    }
+                    let keep = self.tags.iter().map(|(open, _)| Self::partial_tag(rest, open)).max().unwrap_or(0);
+                    self.emit(&mut visible, &rest[..rest.len() - keep]);
+                    self.held = rest[rest.len() - keep..].to_string();
+                }
+                Some(i) => {
+                    let close = &self.tags[i].1;
+                    if let Some(pos) = rest.find(close.as_str()) {
+                        self.reasoning.push_str(&rest[..pos]);
+                        rest = &rest[pos + close.len()..];
+                        self.inside = None;
+                        self.after_block = true;
+                        continue;
+                    }
+                    let keep = Self::partial_tag(rest, close);
+                    self.reasoning.push_str(&rest[..rest.len() - keep]);
+                    self.held = rest[rest.len() - keep..].to_string();
+                }
+            }
+            return visible;
+        }
+    }
+
+    fn emit(&mut self, visible: &mut String, text: &str) {
+        let text = if self.after_block { text.trim_start() } else { text };
+        if !text.is_empty() {
+            self.after_block = false;
+            visible.push_str(text);
+        }
+    }
+
+    /// Ends the stream. Text held back as a possible tag turns out to be
+    /// visible, or reasoning if a block was never closed.
+    fn finish(&mut self) -> String {
+        let held = std::mem::take(&mut self.held);
+        let mut visible = String::new();
+        match self.inside.take() {
+            Some(_) => self.reasoning.push_str(&held),
+            None => self.emit(&mut visible, &held),
+        }
+        visible
+    }
+}
+
+/// Strips reasoning blocks from a complete response, returning what was
+/// stripped.
+fn strip_reasoning(resp: &mut OpenAIResponse, config: &ReasoningConfig) -> Option<String> {
+    let mut filter = ReasoningFilter::new(config);
+    for choice in &mut resp.choices {
+        let mut visible = filter.push(&choice.message.content);
+        visible.push_str(&filter.finish());
+        choice.message.content = visible;
+    }
+    Some(filter.reasoning).filter(|r| !r.is_empty())
+}
+
+/// Applies `stripReasoning`'s token policy and, when the request asked for
+/// it, attaches the stripped reasoning to the response.
+fn apply_stripped_reasoning(resp: &mut OpenAIResponse, config: &ReasoningConfig, reasoning: String, include: bool) {
+    if config.token_policy == "estimate" {
+        if let Some(ref mut usage) = resp.usage {
+            // Roughly four characters per token for English text.
+            let estimate = (reasoning.chars().count() as u32).div_ceil(4);
+            usage.completion_tokens = usage.completion_tokens.saturating_sub(estimate);
+            usage.total_tokens = usage.prompt_tokens + usage.completion_tokens;
+        }
+    }
+    if include {
+        resp.reasoning = Some(reasoning);
+    }
+}
+
+/// Splits a chunked response body into lines without holding more than one
+/// partial line in memory.
+#[derive(Default)]
+struct LineBuffer {
+    buf: Vec<u8>,
+}
+
+impl LineBuffer {
+    fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn next_line(&mut self) -> Option<String> {
+        let pos = self.buf.iter().position(|&b| b == b'\n')?;
+        let line: Vec<u8> = self.buf.drain(..=pos).collect();
+        Some(String::from_utf8_lossy(&line).trim().to_string())
+    }
+
+    fn remainder(&mut self) -> Option<String> {
+        let line = String::from_utf8_lossy(&std::mem::take(&mut self.buf)).trim().to_string();
+        (!line.is_empty()).then_some(line)
+    }
+}
+
+/// How long a streamed backend response may go without sending anything.
+/// Streams have no overall limit, since a long generation isn't a stuck one;
+/// `maxTaskLifetimeSecs` still bounds the whole request.
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Sends a streaming backend request, giving up if the response doesn't
+/// start within `STREAM_IDLE_TIMEOUT`.
+async fn send_stream_request(request: reqwest::RequestBuilder, api: &str) -> Result<reqwest::Response, String> {
+    match tokio::time::timeout(STREAM_IDLE_TIMEOUT, request.send()).await {
+        Ok(response) => response.map_err(|e| backend_request_error(api, &e)),
+        Err(_) => Err(format!("{} backend did not respond within {}s", api, STREAM_IDLE_TIMEOUT.as_secs())),
+    }
+}
+
+/// The next piece of a streamed response body, or an error once the backend
+/// has been silent for `STREAM_IDLE_TIMEOUT`. Time spent waiting for the
+/// server to take earlier chunks doesn't count, as nothing is read meanwhile.
+async fn next_stream_bytes<S, B>(body: &mut S, api: &str) -> Option<Result<B, String>>
+where
+    S: futures_util::Stream<Item = reqwest::Result<B>> + Unpin,
+{
+    match tokio::time::timeout(STREAM_IDLE_TIMEOUT, body.next()).await {
+        Ok(next) => next.map(|bytes| bytes.map_err(|e| format!("{} stream failed: {}", api, e))),
+        Err(_) => Some(Err(format!("{} stream stalled: no data for {}s", api, STREAM_IDLE_TIMEOUT.as_secs()))),
+    }
+}
+
+async fn chat_completion_ollama_stream(
+    client: &BackendHttp,
+    base_url: &str,
+    model: &str,
+    messages: Vec<ChatMessage>,
+    options: &GenerationOptions,
+    forwarder: &mut ChunkForwarder,
+) -> Result<OpenAIResponse, String> {
+    let url = format!("{}/api/chat", base_url.trim_end_matches('/'));
+
+    let (options, format, model_options) = options.for_ollama();
+    let request = OllamaChatRequest {
+        model: model.to_string(),
+        messages,
+        stream: Some(true),
+        format,
+        model_options,
+        options,
+    };
+
+    let response = send_stream_request(client.post_json(&url, &request)?, "Ollama").await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!("Ollama error {}: {}", status, body));
+    }
+
+    let mut body = response.bytes_stream();
+    let mut lines = LineBuffer::default();
+    let mut resp_model = model.to_string();
+    let mut prompt_tokens = 0;
+    let mut completion_tokens = 0;
+    let mut finished = false;
+    let mut logprob_tokens: Option<Vec<serde_json::Value>> = None;
+
+    while !finished {
+        let line = match lines.next_line() {
+            Some(line) => line,
+            None => match next_stream_bytes(&mut body, "Ollama").await {
+                Some(bytes) => {
+                    let bytes = bytes?;
+                    lines.push(&bytes);
+                    continue;
+                }
+                None => match lines.remainder() {
+                    Some(line) => line,
+                    None => break,
+                },
+            },
+        };
+        if line.is_empty() {
+            continue;
+        }
+
+        let chunk: OllamaChatResponse = serde_json::from_str(&line)
+            .map_err(|e| format!("Failed to parse Ollama stream chunk: {}", e))?;
+        resp_model = chunk.model;
+        if let Some(tokens) = chunk.logprobs {
+            logprob_tokens.get_or_insert_with(Vec::new).extend(tokens);
+        }
+        if !forwarder.forward(&chunk.message.content).await? {
+            break;
+        }
+        if chunk.done {
+            prompt_tokens = chunk.prompt_eval_count.unwrap_or(0);
+            completion_tokens = chunk.eval_count.unwrap_or(0);
+            finished = true;
+        }
+    }
+
+    let (content, truncated) = forwarder.finish().await?;
+    Ok(OpenAIResponse {
+        model: resp_model,
+        choices: vec![OpenAIChoice {
+            index: 0,
+            message: ChatMessage {
+                role: "assistant".to_string(),
+                content,
+            },
+            finish_reason: Some(if truncated { "length" } else { "stop" }.to_string()),
+            logprobs: logprob_tokens.map(|tokens| serde_json::json!({ "content": tokens })),
+        }],
+        usage: Some(OpenAIUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }),
+        empty_response: None,
+        fallback_from: None,
+        reasoning: None,
+        coalesced: None,
+        token_timings: None,
+        chunks: None,
+    })
+}
+
+async fn chat_completion_openai_stream(
+    client: &BackendHttp,
+    base_url: &str,
+    model: &str,
+    messages: Vec<ChatMessage>,
+    options: &GenerationOptions,
+    forwarder: &mut ChunkForwarder,
+) -> Result<OpenAIResponse, String> {
+    let url = format!("{}/v1/chat/completions", base_url.trim_end_matches('/'));
+
+    let request = OpenAIChatRequest {
+        model: model.to_string(),
+        messages,
+        stream: Some(true),
+        // Streamed responses only report usage when asked, in a final chunk
+        // with no choices. Some backends reject the field outright.
+        stream_options: forwarder.limits.include_usage.then(|| serde_json::json!({ "include_usage": true })),
+        options: options.clone(),
+    };
+
+    let response = send_stream_request(client.post_json(&url, &request)?, "OpenAI").await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!("OpenAI error {}: {}", status, body));
+    }
+
+    let mut body = response.bytes_stream();
+    let mut lines = LineBuffer::default();
+    let mut resp_model = model.to_string();
+    let mut finish_reason = None;
+    let mut usage = None;
+    // Per-token logprobs arrive spread over the chunks; the final response
+    // carries them all in the non-streaming shape.
+    let mut logprob_tokens: Option<Vec<serde_json::Value>> = None;
+
+    'stream: loop {
+        let line = match lines.next_line() {
+            Some(line) => line,
+            None => match next_stream_bytes(&mut body, "OpenAI").await {
+                Some(bytes) => {
+                    let bytes = bytes?;
+                    lines.push(&bytes);
+                    continue;
+                }
+                None => match lines.remainder() {
+                    Some(line) => line,
+                    None => break,
+                },
+            },
+        };
+
+        let Some(data) = line.strip_prefix("data:").map(str::trim) else { continue };
+        if data == "[DONE]" {
+            break;
+        }
+
+        let chunk: OpenAIStreamChunk = serde_json::from_str(data)
+            .map_err(|e| format!("Failed to parse OpenAI stream chunk: {}", e))?;
+        if let Some(m) = chunk.model {
+            resp_model = m;
+        }
+        if chunk.usage.is_some() {
+            usage = chunk.usage;
+        }
+        for choice in chunk.choices {
+            if choice.finish_reason.is_some() {
+                finish_reason = choice.finish_reason;
+            }
+            if let Some(serde_json::Value::Object(mut logprobs)) = choice.logprobs {
+                let tokens = logprob_tokens.get_or_insert_with(Vec::new);
+                if let Some(serde_json::Value::Array(content)) = logprobs.remove("content") {
+                    tokens.extend(content);
+                }
+            }
+            if let Some(delta) = choice.delta.content {
+                if !forwarder.forward(&delta).await? {
+                    break 'stream;
+                }
+            }
+        }
+    }
+
+    let (content, truncated) = forwarder.finish().await?;
+    if truncated {
+        finish_reason = Some("length".to_string());
+    }
+    if usage.is_none() && forwarder.limits.include_usage {
+        debug!("[BACKEND] OpenAI stream for {} ended without a usage chunk", resp_model);
+    }
+    Ok(OpenAIResponse {
+        model: resp_model,
+        choices: vec![OpenAIChoice {
+            index: 0,
+            message: ChatMessage {
+                role: "assistant".to_string(),
+                content,
+            },
+            finish_reason,
+            logprobs: logprob_tokens.map(|tokens| serde_json::json!({ "content": tokens })),
+        }],
+        usage,
+        empty_response: None,
+        fallback_from: None,
+        reasoning: None,
+        coalesced: None,
+        token_timings: None,
+        chunks: None,
+    })
+}
+
+async fn chat_completion_stream(
+    client: &BackendHttp,
+    base_url: &str,
+    model: &str,
+    messages: Vec<ChatMessage>,
+    options: &GenerationOptions,
+    api_mode: &str,
+    forwarder: &mut ChunkForwarder,
+) -> Result<OpenAIResponse, String> {
+    match api_mode {
+        "openai" => chat_completion_openai_stream(client, base_url, model, messages, options, forwarder).await,
+        _ => chat_completion_ollama_stream(client, base_url, model, messages, options, forwarder).await,
+    }
+}
+
+/// Applies a node's `interviewMaxTokens`, including to prompts that came
+/// without a limit.
+fn cap_interview_prompts(prompts: &mut [InterviewPrompt], cap: u32) {
+    for prompt in prompts.iter_mut().filter(|p| p.max_tokens == 0 || p.max_tokens > cap) {
+        info!("[INTERVIEW] Capping prompt {} at {} tokens (requested {})", prompt.id, cap, prompt.max_tokens);
+        prompt.max_tokens = cap;
+    }
+}
+
+async fn run_interview_prompt(
+    client: &BackendHttp,
+    base_url: &str,
+    model: &str,
+    prompt: &InterviewPrompt,
+    api_mode: &str,
+) -> PromptResult {
+    let start = std::time::Instant::now();
+    
+    let messages = vec![ChatMessage {
+        role: "user".to_string(),
+        content: prompt.prompt.clone(),
+    }];
+    // A max_tokens of 0 means the server set no limit.
+    let options = GenerationOptions { max_tokens: (prompt.max_tokens > 0).then_some(prompt.max_tokens), ..Default::default() };
+    
+    let result = chat_completion(client, base_url, model, messages, &options, api_mode).await;
+    let total_ms = start.elapsed().as_millis() as u32;
+    
+    match result {
+        Ok(resp) => {
+            let content = resp.choices.first()
+                .map(|c| c.message.content.clone())
+                .unwrap_or_default();
+            let tokens = resp.usage.as_ref()
+                .map(|u| u.completion_tokens)
+                .unwrap_or(0);
+            
+            PromptResult {
+                prompt_id: prompt.id.clone(),
+                response: content,
+                ttft_ms: total_ms / 2,  // Approximate TTFT
+                total_ms,
+                tokens_generated: tokens,
+                error: None,
+            }
+        }
+        Err(e) => PromptResult {
+            prompt_id: prompt.id.clone(),
+            response: String::new(),
+            ttft_ms: 0,
+            total_ms,
+            tokens_generated: 0,
+            error: Some(e),
+        },
+    }
+}
+
+/// Runs the prompts in order. With a `timeout`, the whole interview must
+/// finish within it: the prompt running at the deadline and any after it are
+/// reported as timed out, so a stuck backend can't hold the interview slot.
+async fn execute_interview(
+    client: &BackendHttp,
+    base_url: &str,
+    interview_id: &str,
+    model: &str,
+    prompts: Vec<InterviewPrompt>,
+    api_mode: &str,
+    timeout: Option<Duration>,
+) -> InterviewResult {
+    info!("[INTERVIEW] Starting interview {} with {} prompts on model {} ({})", 
+        interview_id, prompts.len(), model, api_mode);
+    
+    let deadline = timeout.map(|t| tokio::time::Instant::now() + t);
+    let mut results = Vec::new();
+    
+    for (i, prompt) in prompts.iter().enumerate() {
+        info!("[INTERVIEW] Running prompt {}/{}: {}", i + 1, prompts.len(), prompt.id);
+        let started = std::time::Instant::now();
+        let run = run_interview_prompt(client, base_url, model, prompt, api_mode);
+        let result = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, run).await {
+                Ok(result) => result,
+                Err(_) => PromptResult {
+                    prompt_id: prompt.id.clone(),
+                    response: String::new(),
+                    ttft_ms: 0,
+                    total_ms: started.elapsed().as_millis() as u32,
+                    tokens_generated: 0,
+                    error: Some("Interview timed out".to_string()),
+                },
+            },
+            None => run.await,
+        };
+        
+        if result.error.is_some() {
+            warn!("[INTERVIEW] Prompt {} failed: {:?}", prompt.id, result.error);
+        } else {
+            info!("[INTERVIEW] Prompt {} completed: {} tokens in {}ms", 
+                prompt.id, result.tokens_generated, result.total_ms);
+        }
+        
+        results.push(result);
+    }
+    
+    info!("[INTERVIEW] Interview {} complete with {} results", interview_id, results.len());
+    
+    InterviewResult {
+        msg_type: "INTERVIEW_RESULT".to_string(),
+        interview_id: interview_id.to_string(),
+        model: model.to_string(),
+        results,
+    }
+}
+
+/// The write half of the server connection. Counts the bytes sent for the
+/// connection summary.
+struct WsWrite {
+    inner: futures_util::stream::SplitSink<
+        tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
+        Message,
+    >,
+    stats: Arc<ConnectionStats>,
+}
+
+impl futures_util::Sink<Message> for WsWrite {
+    type Error = tokio_tungstenite::tungstenite::Error;
+
+    fn poll_ready(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        std::pin::Pin::new(&mut self.get_mut().inner).poll_ready(cx)
+    }
+
+    fn start_send(self: std::pin::Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        this.stats.bytes_sent.fetch_add(item.len() as u64, Ordering::SeqCst);
+        std::pin::Pin::new(&mut this.inner).start_send(item)
+    }
+
+    fn poll_flush(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        std::pin::Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        std::pin::Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+async fn send_json<T: Serialize>(write: &mut WsWrite, msg: &T) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    write.send(Message::Text(serde_json::to_string(msg)?)).await?;
+    Ok(())
+}
+
+/// Time until the backend starts streaming a response. Both APIs send the
+/// first body bytes once the first token is generated, and the response is
+/// dropped right after, so little generation is wasted.
+async fn measure_ttft(client: &BackendHttp, base_url: &str, model: &str, api_mode: &str) -> Result<u64, String> {
+    let messages = [serde_json::json!({ "role": "user", "content": "Reply with one word: ready?" })];
+    let (url, body) = match api_mode {
+        "openai" => (
+            format!("{}/v1/chat/completions", base_url.trim_end_matches('/')),
+            serde_json::json!({ "model": model, "messages": messages, "stream": true, "max_tokens": 8 }),
+        ),
+        _ => (
+            format!("{}/api/chat", base_url.trim_end_matches('/')),
+            serde_json::json!({ "model": model, "messages": messages, "stream": true, "options": { "num_predict": 8 } }),
+        ),
+    };
+
+    let start = std::time::Instant::now();
+    let response = client
+        .post_json(&url, &body)?
+        .timeout(Duration::from_secs(300))
+        .send()
+        .await
+        .map_err(|e| format!("request failed: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("backend returned {}", response.status()));
+    }
+    match response.bytes_stream().next().await {
+        Some(Ok(_)) => Ok(start.elapsed().as_millis() as u64),
+        Some(Err(e)) => Err(format!("stream failed: {}", e)),
+        None => Err("empty response".to_string()),
+    }
+}
+
+fn warmup_model<'a>(node: &'a NodeConfig, warmup: &'a WarmupConfig, models: &'a [String]) -> Option<&'a String> {
+    warmup.model.as_ref().or(node.interview_model.as_ref()).or(models.first())
+}
+
+async fn warmup_node(http: &BackendHttp, node: &NodeConfig, warmup: &WarmupConfig, models: &[String]) -> Result<WarmupResult, String> {
+    let model = warmup_model(node, warmup, models).ok_or("no model to warm up")?;
+    info!("[WARMUP] Warming up {} with {} request(s) on {}", node.alias, warmup.requests, model);
+    measure_warmup(http, node, warmup, model).await
+}
+
+async fn measure_warmup(http: &BackendHttp, node: &NodeConfig, warmup: &WarmupConfig, model: &str) -> Result<WarmupResult, String> {
+    let mut samples = Vec::new();
+    for _ in 0..warmup.requests {
+        samples.push(measure_ttft(http, &node.backend_uri(), model, &node.api_mode).await?);
+    }
+    let cold_ttft_ms = samples[0];
+    let mut steady: Vec<u64> = if samples.len() > 1 { samples[1..].to_vec() } else { samples.clone() };
+    steady.sort_unstable();
+    let ready_ttft_ms = percentile(&steady, 50.0).unwrap_or(cold_ttft_ms);
+    Ok(WarmupResult { cold_ttft_ms, ready_ttft_ms })
+}
+
+/// Runs a node's warmup and records the result, then flags the node for
+/// the connection loop to register. A failed warmup is logged and the node
+/// is registered without the measurement.
+async fn warm_node(node: NodeConfig, warmup: WarmupConfig, http: BackendHttp, daemon: Arc<DaemonState>, models: Vec<String>) {
+    let result = match warmup_node(&http, &node, &warmup, &models).await {
+        Ok(result) => {
+            info!("[WARMUP] {} cold TTFT {}ms, steady-state TTFT {}ms", node.alias, result.cold_ttft_ms, result.ready_ttft_ms);
+            Some(result)
+        }
+        Err(e) => {
+            warn!("[WARMUP] {} warmup failed: {}", node.alias, e);
+            None
+        }
+    };
+    daemon.finish_warming(&node.alias, result);
+}
+
+/// Warms a node's models one at a time, starting with the warmup model.
+/// Each model is marked warm once done, or once its warmup fails, and the
+/// connection loop then re-registers the node to advertise it.
+async fn warm_models_incrementally(node: NodeConfig, warmup: WarmupConfig, http: BackendHttp, daemon: Arc<DaemonState>) {
+    let first = warmup_model(&node, &warmup, &[]).cloned();
+    while let Some(model) = daemon.next_cold_model(&node.alias, first.as_ref()) {
+        info!("[WARMUP] Warming up {} with {} request(s) on {}", node.alias, warmup.requests, model);
+        let result = match measure_warmup(&http, &node, &warmup, &model).await {
+            Ok(result) => {
+                info!("[WARMUP] {} {} cold TTFT {}ms, steady-state TTFT {}ms",
+                    node.alias, model, result.cold_ttft_ms, result.ready_ttft_ms);
+                Some(result)
+            }
+            Err(e) => {
+                warn!("[WARMUP] {} warmup of {} failed: {} - advertising it anyway", node.alias, model, e);
+                None
+            }
+        };
+        let warm = daemon.mark_warm(&node.alias, &model, result);
+        info!("[WARMUP] {} is ready to serve {} ({} model(s) warm)", node.alias, model, warm);
+    }
+}
+
+async fn update_wallet(write: &mut WsWrite, payout_addr: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    info!("Updating payout wallet: {}...{}", &payout_addr[..6.min(payout_addr.len())], &payout_addr[payout_addr.len().saturating_sub(4)..]);
+    let wallet_msg = UpdateWalletMessage {
+        msg_type: "UPDATE_WALLET".to_string(),
+        payout_address: payout_addr.to_string(),
+    };
+    if let Err(e) = write.send(Message::Text(serde_json::to_string(&wallet_msg)?)).await {
+        error!("Failed to update wallet: {}", e);
+    }
+    Ok(())
+}
+
+/// Discovers a node's models and sends its `REGISTER_NODE`. Discovery
+/// failures are logged and the node is registered without models.
+async fn register_node(
+    write: &mut WsWrite,
+    http: &BackendHttp,
+    node_config: &NodeConfig,
+    daemon: &Arc<DaemonState>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    info!("Registering node: {} (region: {}, capacity: {}, endpoint: {}, mode: {})", 
+        node_config.alias, node_config.region, node_config.capacity, 
+        node_config.inference_uri, node_config.api_mode);
+    
+    let listed = get_models(http, &node_config.backend_uri(), &node_config.api_mode).await;
+    register_node_models(write, http, node_config, daemon, listed).await
+}
+
+/// Sends a node's `REGISTER_NODE` for an already-listed model catalog, so a
+/// refresh driven by runtime discovery doesn't list the backend a second time.
+async fn register_node_models(
+    write: &mut WsWrite,
+    http: &BackendHttp,
+    node_config: &NodeConfig,
+    daemon: &Arc<DaemonState>,
+    listed: Result<Vec<String>, String>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (models, reachable) = match listed {
+        Ok(m) => (m, true),
+        Err(e) => {
+            error!("Failed to get models for {} ({}): {}", node_config.alias, node_config.api_mode, e);
+            (vec![], false)
+        }
+    };
+    
+    if models.is_empty() {
+        warn!("No models found for node {} - check endpoint {}", node_config.alias, node_config.inference_uri);
+    } else {
+        info!("Node {} has {} models: {:?}", node_config.alias, models.len(), models);
+    }
+
+    if let Some(state) = daemon.nodes.lock().unwrap().get_mut(&node_config.alias) {
+        state.backend_models = models.clone();
+    }
+    if let Some(warmup) = node_config.warmup.as_ref().filter(|w| reachable && w.incremental) {
+        if daemon.start_warming(&node_config.alias) {
+            tokio::spawn(warm_models_incrementally(node_config.clone(), warmup.clone(), http.clone(), daemon.clone()));
+        }
+    } else if reachable && daemon.warmup(&node_config.alias).is_none() {
+        if let Some(ref warmup) = node_config.warmup {
+            // Warmup can take minutes, so it runs in its own task and the
+            // connection loop registers the node once it finishes.
+            if daemon.start_warming(&node_config.alias) {
+                tokio::spawn(warm_node(node_config.clone(), warmup.clone(), http.clone(), daemon.clone(), models));
+            }
+            info!("[WARMUP] {} will register once its warmup finishes", node_config.alias);
+            return Ok(());
+        }
+    }
+    send_node_registration(write, node_config, daemon, models, reachable).await
+}
+
+/// Sends a node's `REGISTER_NODE` for its backend models, applying
+/// incremental warmup and model variants.
+async fn send_node_registration(
+    write: &mut WsWrite,
+    node_config: &NodeConfig,
+    daemon: &Arc<DaemonState>,
+    models: Vec<String>,
+    reachable: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut models = models;
+    if node_config.warmup.as_ref().is_some_and(|w| w.incremental) {
+        let total = models.len();
+        models = daemon.nodes.lock().unwrap().get(&node_config.alias).map(|s| s.advertisable(node_config, &models)).unwrap_or_default();
+        if models.len() < total {
+            info!("[WARMUP] Node {} advertises {} of {} models until the rest are warm", node_config.alias, models.len(), total);
+        }
+    }
+    let canonical = canonical_models(node_config, &models);
+    if !canonical.is_empty() {
+        info!("Node {} advertises variant-backed models: {:?}", node_config.alias, canonical);
+        models.extend(canonical);
+    }
+    models.retain(|m| node_config.accepts_model(m));
+    
+    let (capacity, warmup) = {
+        let mut states = daemon.nodes.lock().unwrap();
+        let state = states.get_mut(&node_config.alias).unwrap();
+        state.models = models.clone();
+        state.backend_reachable = reachable;
+        state.awaiting_ack = true;
+        (state.effective_capacity, state.warmup)
+    };
+    let register_msg = RegisterNodeMessage::new(node_config, models, capacity).with_warmup(warmup);
+    
+    if let Err(e) = write.send(Message::Text(serde_json::to_string(&register_msg)?)).await {
+        error!("Failed to register node {}: {}", node_config.alias, e);
+    }
+    Ok(())
+}
+
+const BACKEND_RESTART_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const BACKEND_RESTART_MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A backend that stayed up this long is considered healthy again and the
+/// restart backoff is reset.
+const BACKEND_STABLE_AFTER: Duration = Duration::from_secs(60);
+const BACKEND_READY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const SUPERVISOR_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Exit status after the server closed with a terminal close code, so a
+/// service manager can be told not to restart the daemon.
+const CLOSED_BY_SERVER_EXIT_CODE: i32 = 3;
+
+async fn wait_for_shutdown() {
+    while RUNNING.load(Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+/// Captures the server's leaf certificate and then fails the handshake on
+/// purpose, so the certificate can be read even when it has expired or
+/// doesn't verify, without the connection ever being trusted.
+#[derive(Debug)]
+struct CertCapture {
+    provider: Arc<rustls::crypto::CryptoProvider>,
+    leaf: std::sync::Mutex<Option<Vec<u8>>>,
+}
+
+impl rustls::client::danger::ServerCertVerifier for CertCapture {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        *self.leaf.lock().unwrap() = Some(end_entity.to_vec());
+        Err(rustls::Error::General("certificate captured".to_string()))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Fetches the leaf certificate (DER) a node's HTTPS backend presents.
+/// Blocking; run it with `spawn_blocking`.
+fn fetch_backend_certificate(node: &NodeConfig) -> Result<Vec<u8>, String> {
+    let url = reqwest::Url::parse(&node.inference_uri).map_err(|e| format!("invalid inferenceUri: {}", e))?;
+    let host = url.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+    let name = node.tls_server_name.as_deref().unwrap_or(host).to_string();
+    let server_name = rustls::pki_types::ServerName::try_from(name).map_err(|e| format!("invalid server name: {}", e))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let capture = Arc::new(CertCapture { provider: provider.clone(), leaf: std::sync::Mutex::new(None) });
+    let tls = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .dangerous()
+        .with_custom_certificate_verifier(capture.clone())
+        .with_no_client_auth();
+    let mut conn = rustls::ClientConnection::new(Arc::new(tls), server_name).map_err(|e| e.to_string())?;
+
+    let addr = *node.backend_addrs()?.first().ok_or("no address")?;
+    let mut sock = std::net::TcpStream::connect_timeout(&addr, Duration::from_secs(10)).map_err(|e| format!("connect to {}: {}", addr, e))?;
+    sock.set_read_timeout(Some(Duration::from_secs(10))).map_err(|e| e.to_string())?;
+    sock.set_write_timeout(Some(Duration::from_secs(10))).map_err(|e| e.to_string())?;
+    let mut handshake_error = None;
+    while conn.is_handshaking() {
+        if let Err(e) = conn.complete_io(&mut sock) {
+            handshake_error = Some(e);
+            break;
+        }
+    }
+
+    let leaf = capture.leaf.lock().unwrap().take();
+    leaf.ok_or_else(|| match handshake_error {
+        Some(e) => format!("TLS handshake failed: {}", e),
+        None => "no certificate received".to_string(),
+    })
+}
+
+/// Days from now until the certificate's notAfter, negative once expired.
+fn cert_days_left(der: &[u8]) -> Result<(f64, String), String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).map_err(|e| format!("unparseable certificate: {}", e))?;
+    let not_after = cert.validity().not_after;
+    let secs_left = not_after.timestamp() - chrono::Utc::now().timestamp();
+    Ok((secs_left as f64 / 86400.0, not_after.to_string()))
+}
+
+/// Checks the certificate of every HTTPS backend at startup and then every
+/// `intervalSecs`, warning when one is within `warnDays` of expiry.
+async fn monitor_backend_certs(nodes: Vec<NodeConfig>, check: CertExpiryConfig, daemon: Arc<DaemonState>) {
+    let nodes: Vec<NodeConfig> = nodes.into_iter().filter(|n| n.inference_uri.starts_with("https://")).collect();
+    if nodes.is_empty() {
+        info!("[CERT] No HTTPS backends - skipping certificate expiry checks");
+        return;
+    }
+
+    while RUNNING.load(Ordering::SeqCst) {
+        for node in &nodes {
+            let target = node.clone();
+            let result = tokio::task::spawn_blocking(move || fetch_backend_certificate(&target).and_then(|der| cert_days_left(&der)))
+                .await
+                .unwrap_or_else(|e| Err(e.to_string()));
+            match result {
+                Ok((days, not_after)) => {
+                    daemon.metrics.cert_expiry_days.lock().unwrap().insert(node.alias.clone(), days);
+                    if days < 0.0 {
+                        error!("[CERT] Certificate of {} backend {} EXPIRED on {} - requests to it will fail", node.alias, node.inference_uri, not_after);
+                    } else if days <= check.warn_days as f64 {
+                        warn!("[CERT] Certificate of {} backend {} expires in {:.1} days ({}) - renew it", node.alias, node.inference_uri, days, not_after);
+                    } else {
+                        info!("[CERT] Certificate of {} backend valid for {:.0} more days (until {})", node.alias, days, not_after);
+                    }
+                }
+                Err(e) => warn!("[CERT] Could not check certificate of {} backend {}: {}", node.alias, node.inference_uri, e),
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(check.interval_secs)).await;
+    }
+}
+
+/// Holds off the first registration until every backend lists its models,
+/// so a daemon started together with its backends doesn't register them
+/// empty. Gives up on a backend once `timeoutSecs` have passed overall.
+async fn wait_for_backends(config: &Config, http: &BackendClients, wait: &WaitForBackendConfig) {
+    let started = std::time::Instant::now();
+    let timeout = Duration::from_secs(wait.timeout_secs);
+
+    for node in &config.nodes {
+        let http = &http.node(&node.alias).http;
+        loop {
+            match get_models(http, &node.backend_uri(), &node.api_mode).await {
+                Ok(_) => {
+                    info!("[STARTUP] {} backend ready after {:.1}s", node.alias, started.elapsed().as_secs_f64());
+                    break;
+                }
+                Err(e) if started.elapsed() >= timeout => {
+                    warn!("[STARTUP] {} backend not ready after {}s - registering anyway: {}", node.alias, wait.timeout_secs, e);
+                    break;
+                }
+                Err(e) => debug!("[STARTUP] Waiting for {} backend: {}", node.alias, e),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(wait.interval_secs)) => {}
+                _ = wait_for_shutdown() => return,
+            }
+        }
+    }
+}
+
+/// Polls a node's backend for its models every `discoveryIntervalSecs`. A
+/// new list is only published once two polls in a row agree, so a model
+/// that is still being pulled or briefly missing doesn't cause churn; the
+/// connection loop re-registers the node when it changes.
+async fn discover_models(node: NodeConfig, http: BackendHttp, daemon: Arc<DaemonState>) {
+    let interval = Duration::from_secs(node.discovery_interval_secs.unwrap_or_default());
+    let mut previous: Option<Vec<String>> = None;
+
+    while RUNNING.load(Ordering::SeqCst) {
+        tokio::time::sleep(interval).await;
+        match get_models(&http, &node.backend_uri(), &node.api_mode).await {
+            Ok(mut models) => {
+                models.sort();
+                models.dedup();
+                if previous.as_ref() == Some(&models) {
+                    daemon.set_discovered_models(&node.alias, models.clone());
+                }
+                previous = Some(models);
+            }
+            Err(e) => {
+                warn!("[DISCOVERY] Could not list models of {}: {}", node.alias, e);
+                previous = None;
+            }
+        }
+    }
+}
+
+fn read_load_average() -> Result<f64, String> {
+    let text = std::fs::read_to_string("/proc/loadavg").map_err(|e| format!("cannot read /proc/loadavg: {}", e))?;
+    text.split_whitespace()
+        .next()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| "unexpected /proc/loadavg format".to_string())
+}
+
+/// Share of memory in use, counting reclaimable page cache as free.
+fn read_memory_used_percent() -> Result<f64, String> {
+    let text = std::fs::read_to_string("/proc/meminfo").map_err(|e| format!("cannot read /proc/meminfo: {}", e))?;
+    let field = |name: &str| -> Option<f64> {
+        text.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|rest| rest.split_whitespace().next()?.parse().ok())
+    };
+    match (field("MemTotal"), field("MemAvailable")) {
+        (Some(total), Some(available)) if total > 0.0 => Ok(100.0 * (total - available) / total),
+        _ => Err("MemTotal or MemAvailable missing from /proc/meminfo".to_string()),
+    }
+}
+
+/// Describes which threshold the system is over, if any.
+fn overload_reason(limits: &LoadSheddingConfig) -> Result<Option<String>, String> {
+    if let Some(max) = limits.max_load_average {
+        let load = read_load_average()?;
+        if load > max {
+            return Ok(Some(format!("system load average {:.2} is above {:.2}", load, max)));
+        }
+    }
+    if let Some(max) = limits.max_memory_percent {
+        let used = read_memory_used_percent()?;
+        if used > max {
+            return Ok(Some(format!("memory use {:.1}% is above {:.1}%", used, max)));
+        }
+    }
+    Ok(None)
+}
+
+/// Samples system load for `loadShedding` and flags the daemon as
+/// overloaded while a threshold is exceeded. Stops shedding for good if the
+/// load can't be read, e.g. on a system without `/proc`.
+async fn monitor_system_load(limits: LoadSheddingConfig, daemon: Arc<DaemonState>) {
+    while RUNNING.load(Ordering::SeqCst) {
+        let reason = match overload_reason(&limits) {
+            Ok(reason) => reason,
+            Err(e) => {
+                error!("[LOAD] Load shedding disabled: {}", e);
+                *daemon.overload.lock().unwrap() = None;
+                return;
+            }
+        };
+        let was_overloaded = std::mem::replace(&mut *daemon.overload.lock().unwrap(), reason.clone()).is_some();
+        match (was_overloaded, reason) {
+            (false, Some(reason)) => warn!("[LOAD] Overloaded - rejecting new requests: {}", reason),
+            (true, None) => info!("[LOAD] Load has subsided - accepting requests again"),
+            _ => {}
+        }
+        tokio::time::sleep(Duration::from_secs(limits.interval_secs)).await;
+    }
+}
+
+/// Polls a freshly started backend until it lists its models.
+async fn wait_until_backend_ready(node: &NodeConfig, http: &BackendHttp) {
+    loop {
+        match get_models(http, &node.backend_uri(), &node.api_mode).await {
+            Ok(_) => return,
+            Err(e) => debug!("[BACKEND] Waiting for {} backend: {}", node.alias, e),
+        }
+        tokio::time::sleep(BACKEND_READY_POLL_INTERVAL).await;
+    }
+}
+
+/// Keeps a node's `backendCommand` running, restarting it with exponential
+/// backoff whenever it exits. The backend only counts as up once it answers
+/// a model listing. The child is killed on daemon shutdown.
+async fn supervise_backend(node: NodeConfig, http: BackendHttp, daemon: Arc<DaemonState>) {
+    let command = node.backend_command.clone().unwrap_or_default();
+    let mut backoff = BACKEND_RESTART_MIN_BACKOFF;
+
+    while RUNNING.load(Ordering::SeqCst) {
+        let started = std::time::Instant::now();
+        match tokio::process::Command::new(&command[0])
+            .args(&command[1..])
+            .kill_on_drop(true)
+            .spawn()
+        {
+            Ok(mut child) => {
+                info!("[BACKEND] Started '{}' for {} (pid {})", command.join(" "), node.alias, child.id().unwrap_or(0));
+                let readiness = wait_until_backend_ready(&node, &http);
+                tokio::pin!(readiness);
+                let mut ready = false;
+
+                loop {
+                    tokio::select! {
+                        _ = &mut readiness, if !ready => {
+                            info!("[BACKEND] {} backend ready after {:.1}s", node.alias, started.elapsed().as_secs_f64());
+                            daemon.set_backend_up(&node.alias, true);
+                            ready = true;
+                        }
+                        status = child.wait() => {
+                            daemon.set_backend_up(&node.alias, false);
+                            match status {
+                                Ok(status) => warn!("[BACKEND] {} backend exited: {}", node.alias, status),
+                                Err(e) => error!("[BACKEND] {} backend wait failed: {}", node.alias, e),
+                            }
+                            break;
+                        }
+                        _ = wait_for_shutdown() => {
+                            info!("[BACKEND] Stopping {} backend", node.alias);
+                            let _ = child.kill().await;
+                            daemon.set_backend_up(&node.alias, false);
+                            return;
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                daemon.set_backend_up(&node.alias, false);
+                error!("[BACKEND] Failed to start '{}' for {}: {}", command.join(" "), node.alias, e);
+            }
+        }
+
+        if started.elapsed() >= BACKEND_STABLE_AFTER {
+            backoff = BACKEND_RESTART_MIN_BACKOFF;
+        }
+        info!("[BACKEND] Restarting {} backend in {}s", node.alias, backoff.as_secs());
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = wait_for_shutdown() => return,
+        }
+        backoff = (backoff * 2).min(BACKEND_RESTART_MAX_BACKOFF);
+    }
+}
+
+/// The configured alias the server means by `server_alias`. The server may
+/// suffix duplicate aliases, so a configured prefix is accepted too, but only
+/// when exactly one alias matches: with `gpu` and `gpu2` configured, `gpu2x`
+/// could belong to either and is left unresolved.
+fn configured_alias<'a>(aliases: impl Iterator<Item = &'a String>, server_alias: &str) -> Option<String> {
+    let mut prefixes = Vec::new();
+    for alias in aliases {
+        if alias == server_alias {
+            return Some(alias.clone());
+        }
+        if server_alias.starts_with(alias.as_str()) {
+            prefixes.push(alias);
+        }
+    }
+    match prefixes.as_slice() {
+        [alias] => Some((*alias).clone()),
+        _ => None,
+    }
+}
+
+/// The model to serve instead of `model` when the node doesn't serve it,
+/// if fallback is enabled and a configured fallback is available there.
+fn fallback_model_for(config: &Config, node: &NodeConfig, state: &DaemonState, model: &str) -> Option<String> {
+    if !config.model_fallback.enabled {
+        return None;
+    }
+    let states = state.nodes.lock().unwrap();
+    let served = &states.get(&node.alias)?.models;
+    if served.iter().any(|m| m == model) {
+        return None;
+    }
+    node.fallback_model
+        .get(model)
+        .into_iter()
+        .chain(config.model_fallback.default_model.as_ref())
+        .find(|fallback| served.contains(fallback))
+        .cloned()
+}
+
+/// Picks the node that advertised `model`, preferring one that accepts
+/// requests over a node with capacity 0, and falling back to the first
+/// configured node when no registered node lists it.
+fn select_node<'a>(config: &'a Config, state: &DaemonState, model: &str) -> &'a NodeConfig {
+    let states = state.nodes.lock().unwrap();
+    let mut serving = config.nodes.iter().filter(|n| {
+        states
+            .get(&n.alias)
+            .map(|s| s.models.iter().any(|m| m == model))
+            .unwrap_or(false)
+    });
+    let first = serving.next();
+    first
+        .filter(|n| n.capacity > 0)
+        .or_else(|| serving.find(|n| n.capacity > 0))
+        .or(first)
+        .unwrap_or_else(|| config.nodes.first().unwrap())
+}
+
+/// Why the startup probe of `serverUrl` failed. `Misconfigured` means
+/// retrying can't help (bad URL, unknown host, certificate for another name);
+/// `Unreachable` may clear up on its own.
+enum ProbeError {
+    Misconfigured(String),
+    Unreachable(String),
+}
+
+/// Classifies a failed lookup of the server's host. Only an answer that the
+/// name doesn't exist counts as a misconfiguration; a resolver that can't be
+/// reached yet (no network at boot) is reported as unreachable. The standard
+/// library only exposes the resolver's message, so this matches on it.
+fn resolution_failure(host: &str, e: &std::io::Error) -> ProbeError {
+    const NO_SUCH_NAME: &[&str] = &["Name or service not known", "No address associated with hostname", "nodename nor servname provided"];
+    let message = format!("could not resolve {}: {}", host, e);
+    if NO_SUCH_NAME.iter().any(|m| message.contains(m)) {
+        ProbeError::Misconfigured(message)
+    } else {
+        ProbeError::Unreachable(message)
+    }
+}
+
+/// Checks once at startup that `serverUrl` can be reached: the URL parses,
+/// its host resolves, a TCP connection opens and, for `wss://`, the TLS
+/// handshake completes. Nothing is sent over the connection. Blocking.
+fn probe_server(server_url: &str, tls: Option<Arc<rustls::ClientConfig>>) -> Result<(), ProbeError> {
+    use std::net::ToSocketAddrs;
+
+    let url = reqwest::Url::parse(server_url).map_err(|e| ProbeError::Misconfigured(format!("invalid URL: {}", e)))?;
+    if !matches!(url.scheme(), "ws" | "wss") {
+        return Err(ProbeError::Misconfigured(format!("scheme must be ws:// or wss:// (got {}://)", url.scheme())));
+    }
+    let host = url.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']').to_string();
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addrs: Vec<std::net::SocketAddr> = (host.as_str(), port)
+        .to_socket_addrs()
+        .map_err(|e| resolution_failure(&host, &e))?
+        .collect();
+
+    let mut last_error = format!("{} has no addresses", host);
+    let mut sock = None;
+    for addr in &addrs {
+        match std::net::TcpStream::connect_timeout(addr, Duration::from_secs(10)) {
+            Ok(s) => {
+                sock = Some(s);
+                break;
+            }
+            Err(e) => last_error = format!("connect to {}: {}", addr, e),
+        }
+    }
+    let mut sock = sock.ok_or(ProbeError::Unreachable(last_error))?;
+    if url.scheme() == "ws" {
+        return Ok(());
+    }
+
+    let tls = match tls {
+        Some(tls) => tls,
+        None => Arc::new(build_tls_config(&TlsConfig::default()).map_err(ProbeError::Unreachable)?),
+    };
+    let server_name = rustls::pki_types::ServerName::try_from(host.clone())
+        .map_err(|e| ProbeError::Misconfigured(format!("invalid server name {}: {}", host, e)))?;
+    let mut conn = rustls::ClientConnection::new(tls, server_name).map_err(|e| ProbeError::Unreachable(e.to_string()))?;
+    sock.set_read_timeout(Some(Duration::from_secs(10))).map_err(|e| ProbeError::Unreachable(e.to_string()))?;
+    sock.set_write_timeout(Some(Duration::from_secs(10))).map_err(|e| ProbeError::Unreachable(e.to_string()))?;
+    while conn.is_handshaking() {
+        if let Err(e) = conn.complete_io(&mut sock) {
+            let message = format!("TLS handshake with {} failed: {}", host, e);
+            let bad_certificate = e.get_ref().and_then(|inner| inner.downcast_ref::<rustls::Error>())
+                .is_some_and(|inner| matches!(inner, rustls::Error::InvalidCertificate(_)));
+            return Err(if bad_certificate { ProbeError::Misconfigured(message) } else { ProbeError::Unreachable(message) });
+        }
+    }
+    Ok(())
+}
+
+/// Checks the server's leaf certificate against the pinned fingerprints.
+/// Runs before AUTH so the signature is never sent to an unpinned server.
+fn verify_server_certificate(
+    stream: &tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    pinning: &ServerPinningConfig,
+    server_url: &str,
+) -> Result<(), String> {
+    let leaf = match stream {
+        tokio_tungstenite::MaybeTlsStream::Rustls(tls) => tls.get_ref().1.peer_certificates().and_then(|certs| certs.first()),
+        _ => None,
+    };
+    let Some(leaf) = leaf else {
+        return Err(format!("SECURITY: no server certificate available from {} to check against serverPinning.certFingerprints - refusing to authenticate", server_url));
+    };
+    let fingerprint = hex::encode(Sha256::digest(leaf.as_ref()));
+    if !pinning.fingerprint_allowed(&fingerprint) {
+        return Err(format!("SECURITY: certificate of {} has fingerprint {} which is not in serverPinning.certFingerprints - refusing to authenticate", server_url, fingerprint));
+    }
+    info!("Server certificate matches pinned fingerprint {}", fingerprint);
+    Ok(())
+}
+
+/// How to treat an `AUTH_SUCCESS` on a connection.
+#[derive(Debug, PartialEq)]
+enum AuthOutcome {
+    /// The first on this connection: set up the session.
+    First,
+    /// A repeat for the operator the connection already authenticated as.
+    Repeat,
+    /// A repeat naming another operator, most likely a server bug. The
+    /// connection stays with the operator it first authenticated as.
+    OtherOperator { kept: String },
+}
+
+fn auth_outcome(authenticated_as: Option<&str>, operator_id: &str) -> AuthOutcome {
+    match authenticated_as {
+        None => AuthOutcome::First,
+        Some(first) if first == operator_id => AuthOutcome::Repeat,
+        Some(first) => AuthOutcome::OtherOperator { kept: first.to_string() },
+    }
+}
+
+async fn run_connection(
+    config: &Config,
+    max_threads: usize,
+    daemon: &Arc<DaemonState>,
+    http: &BackendClients,
+    tls: Option<&Arc<rustls::ClientConfig>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    info!("Connecting to PIN server: {}", config.server_url);
+    info!("Inference threads: {}", max_threads);
+
+    daemon.reset_nodes(config);
+    daemon.dedup.clear_in_flight();
+    let (ws_stream, _) = match tls {
+        Some(tls) => {
+            let connector = tokio_tungstenite::Connector::Rustls(tls.clone());
+            connect_async_tls_with_config(&config.server_url, None, false, Some(connector))
+                .await
+                .map_err(|e| match e {
+                    tokio_tungstenite::tungstenite::Error::Tls(e) => {
+                        format!("TLS handshake with {} failed within the configured tls restrictions: {}", config.server_url, e)
+                    }
+                    // rustls reports handshake failures through the IO error.
+                    tokio_tungstenite::tungstenite::Error::Io(e) if e.get_ref().is_some_and(|inner| inner.is::<rustls::Error>()) => {
+                        format!("TLS handshake with {} failed within the configured tls restrictions: {}", config.server_url, e)
+                    }
+                    e => e.to_string(),
+                })?
+        }
+        None => connect_async(&config.server_url).await?,
+    };
+    if let Some(pinning) = config.server_pinning.as_ref().filter(|p| !p.cert_fingerprints.is_empty()) {
+        verify_server_certificate(ws_stream.get_ref(), pinning, &config.server_url)?;
+    }
+    daemon.set_connection(ConnectionState::Connected);
+    let stats = daemon.begin_connection();
+    let (write, mut read) = ws_stream.split();
+    let mut write = WsWrite { inner: write, stats: stats.clone() };
+    
+    let semaphore = Arc::new(Semaphore::new(max_threads));
+    let interview_semaphore = Arc::new(Semaphore::new(config.max_concurrent_interviews));
+    let interviews_pending = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let (tx, mut rx) = mpsc::channel::<Outbound>(RESPONSE_CHANNEL_CAPACITY);
+    let mut reorder = ReorderBuffer::new(config);
+    let mut next_request_seq: u64 = 0;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .to_string();
+
+    let signature = compute_signature(&config.client_id, &timestamp, &config.api_secret);
+
+    let auth_msg = AuthMessage {
+        msg_type: "AUTH".to_string(),
+        client_id: config.client_id.clone(),
+        timestamp,
+        signature,
+    };
+
+    write
+        .send(Message::Text(serde_json::to_string(&auth_msg)?))
+        .await?;
+    info!("Sent AUTH message for {}", config.client_id);
+
+    let mut authenticated_as: Option<String> = None;
+    let mut paused_nodes: std::collections::HashSet<String> = std::collections::HashSet::new();
+    let mut supervisor_tick = tokio::time::interval(SUPERVISOR_CHECK_INTERVAL);
+    let supervision_enabled = config.nodes.iter().any(|n| n.backend_command.is_some());
+    let mut idle_reference = std::time::Instant::now();
+    let mut idle_tick = tokio::time::interval(IDLE_CHECK_INTERVAL);
+    let mut capacity_tick = tokio::time::interval(CAPACITY_CONTROL_INTERVAL);
+    let mut variant_report_tick = tokio::time::interval_at(tokio::time::Instant::now() + VARIANT_REPORT_INTERVAL, VARIANT_REPORT_INTERVAL);
+    let mut summary_tick = tokio::time::interval_at(tokio::time::Instant::now() + SUMMARY_INTERVAL, SUMMARY_INTERVAL);
+    let variants_enabled = config.nodes.iter().any(|n| !n.model_variants.is_empty());
+    let mut health_tick = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+    let auto_disable_enabled = config.nodes.iter().any(|n| n.auto_disable.is_some());
+    let mut model_tick = tokio::time::interval(MODEL_CHECK_INTERVAL);
+    let model_check_enabled = config.nodes.iter().any(|n| n.discovery_interval_secs.is_some() || n.warmup.is_some());
+    let capacity_control_enabled = config.nodes.iter().any(|n| n.capacity_control.is_some());
+    let mut wallet_retry_at: Option<tokio::time::Instant> = None;
+    let node_stats_interval = Duration::from_secs(config.node_stats.as_ref().map_or(60, |n| n.interval_secs));
+    let mut node_stats_tick = tokio::time::interval_at(tokio::time::Instant::now() + node_stats_interval, node_stats_interval);
+    let mut heartbeat_tick = tokio::time::interval_at(tokio::time::Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+
+    while RUNNING.load(Ordering::SeqCst) {
+        let reorder_deadline = reorder.deadline();
+        tokio::select! {
+            outbound = rx.recv() => {
+                if let Some(out) = outbound {
+                    for json in reorder.accept(out) {
+                        if let Err(e) = write.send(Message::Text(json)).await {
+                            error!("Failed to send response: {}", e);
+                        }
+                    }
+                }
+            }
+            _ = tokio::time::sleep_until(reorder_deadline.unwrap_or_else(tokio::time::Instant::now)), if reorder_deadline.is_some() => {
+                for json in reorder.expire() {
+                    if let Err(e) = write.send(Message::Text(json)).await {
+                        error!("Failed to send response: {}", e);
+                    }
+                }
+            }
+            msg = read.next() => {
+                if let Some(Ok(ref message)) = msg {
+                    stats.bytes_received.fetch_add(message.len() as u64, Ordering::SeqCst);
+                }
+                match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        match serde_json::from_str::<ServerMessage>(&text) {
+                            Ok(server_msg) => {
+                                if authenticated_as.is_none() && server_msg.requires_auth() {
+                                    // Nothing is registered yet, so work can't be served and
+                                    // acknowledgements refer to nothing we sent.
+                                    daemon.metrics.pre_auth_messages.fetch_add(1, Ordering::SeqCst);
+                                    match server_msg {
+                                        ServerMessage::INFERENCE_REQUEST { request_id, metadata, .. } => {
+                                            warn!("Received INFERENCE_REQUEST {} before AUTH_SUCCESS - rejecting", request_id);
+                                            stats.errors.fetch_add(1, Ordering::SeqCst);
+                                            let rejection = ClientMessage::inference_error(request_id, "not_ready: request received before authentication completed".to_string(), metadata);
+                                            send_json(&mut write, &rejection).await?;
+                                        }
+                                        other => warn!("Ignoring {} received before AUTH_SUCCESS", other.name()),
+                                    }
+                                    continue;
+                                }
+
+                                match server_msg {
+                                    ServerMessage::AUTH_SUCCESS { operator_id, node_id: _, message } => {
+                                        let outcome = auth_outcome(authenticated_as.as_deref(), &operator_id);
+                                        if outcome != AuthOutcome::First {
+                                            if let AuthOutcome::OtherOperator { ref kept } = outcome {
+                                                warn!("Duplicate AUTH_SUCCESS names operator {} but this connection authenticated as {} - keeping {}", operator_id, kept, kept);
+                                            }
+                                            // Already registered on this connection: resend cached
+                                            // registrations for nodes the server never acknowledged
+                                            // instead of re-running discovery for everything.
+                                            let pending = daemon.unacknowledged_registrations(config, &paused_nodes);
+                                            if pending.is_empty() {
+                                                info!("Duplicate AUTH_SUCCESS for {} ignored - all nodes already registered", operator_id);
+                                            } else {
+                                                info!("Duplicate AUTH_SUCCESS for {} - re-sending registration for {} unacknowledged node(s)", operator_id, pending.len());
+                                                for registration in pending {
+                                                    send_json(&mut write, &registration).await?;
+                                                }
+                                            }
+                                            continue;
+                                        }
+                                        authenticated_as = Some(operator_id.clone());
+
+                                        daemon.set_connection(ConnectionState::Authenticated);
+                                        idle_reference = std::time::Instant::now();
+                                        info!("Authenticated! Operator: {}", operator_id);
+                                        info!("{}", message);
+
+                                        // Update wallet address if configured
+                                        wallet_retry_at = None;
+                                        if let Some(ref payout_addr) = config.payout_address {
+                                            if !payout_addr.is_empty() {
+                                                daemon.start_wallet_update(true);
+                                                update_wallet(&mut write, payout_addr).await?;
+                                            }
+                                        }
+
+                                        // Register each configured node with the server
+                                        // Each node may have its own endpoint and API mode
+                                        for node_config in &config.nodes {
+                                            if daemon.backend_up(&node_config.alias) == Some(false) {
+                                                warn!("[BACKEND] {} backend is down - registering as paused", node_config.alias);
+                                                send_json(&mut write, &RegisterNodeMessage::new(node_config, vec![], 0)).await?;
+                                                paused_nodes.insert(node_config.alias.clone());
+                                                continue;
+                                            }
+                                            if daemon.node_disabled(&node_config.alias) {
+                                                warn!("[HEALTH] {} is auto-disabled - registering as paused", node_config.alias);
+                                                send_json(&mut write, &RegisterNodeMessage::new(node_config, vec![], 0)).await?;
+                                                paused_nodes.insert(node_config.alias.clone());
+                                                continue;
+                                            }
+                                            register_node(&mut write, &http.node(&node_config.alias).http, node_config, daemon).await?;
+                                        }
+                                        
+                                        info!("Registered {} node(s) with PIN network", config.nodes.len());
+                                    }
+                                    ServerMessage::REGISTER_NODE_ACK { node_id, alias, models, created, message } => {
+                                        daemon.mark_registered(&alias);
+                                        let status = if created { "registered" } else { "updated" };
+                                        info!("[NODE] {} {} (ID: {}) with {} models", status.to_uppercase(), alias, node_id, models.len());
+                                        info!("[NODE] {}", message);
+                                    }
+                                    ServerMessage::REGISTER_NODE_REJECTED { alias, reason } => {
+                                        // Only this node failed; keep the connection for the others.
+                                        daemon.mark_rejected(&alias, &reason);
+                                        error!("[NODE] REJECTED {}: {}", alias, reason);
+                                    }
+                                    ServerMessage::ERROR { message } => {
+                                        error!("Server error: {}", message);
+                                        return Err(message.into());
+                                    }
+                                    ServerMessage::PING => {
+                                        let pong = ClientMessage {
+                                            msg_type: "PONG".to_string(),
+                                            request_id: None,
+                                            result: None,
+                                            error: None,
+                                            code: None,
+                                            models: None,
+                                            metadata: None,
+                                        };
+                                        let _ = write.send(Message::Text(serde_json::to_string(&pong)?)).await;
+                                    }
+                                    ServerMessage::HEARTBEAT_ACK | ServerMessage::MODEL_LIST_ACK => {}
+                                    ServerMessage::UPDATE_WALLET_ACK { success, message } => {
+                                        let policy = &config.wallet;
+                                        let attempts = daemon.wallet_status().attempts;
+                                        if success {
+                                            info!("[WALLET] {}", message);
+                                            daemon.set_wallet_status("accepted", message);
+                                            wallet_retry_at = None;
+                                        } else if attempts <= policy.retries {
+                                            warn!("[WALLET] Failed (attempt {} of {}): {} - retrying in {}s", attempts, policy.retries + 1, message, policy.retry_delay_secs);
+                                            daemon.set_wallet_status("retrying", message);
+                                            wallet_retry_at = Some(tokio::time::Instant::now() + Duration::from_secs(policy.retry_delay_secs));
+                                        } else {
+                                            error!("[WALLET] Payout address rejected after {} attempt(s): {} - earnings cannot be paid out until payoutAddress is fixed", attempts, message);
+                                            if policy.require_valid_wallet {
+                                                error!("[WALLET] requireValidWallet is set - refusing inference requests");
+                                            }
+                                            if let Some(ref url) = policy.webhook_url {
+                                                send_wallet_webhook(&http.shared.http.client, url, attempts, &message);
+                                            }
+                                            daemon.set_wallet_status("rejected", message);
+                                        }
+                                    }
+                                    ServerMessage::INTERVIEW_REQUEST { interview_id, node_id, model, prompts, timeout_ms } => {
+                                        let node_label = node_id.as_deref().unwrap_or("operator");
+                                        info!("[INTERVIEW] Received interview for {} - model {} ({} prompts)", 
+                                            node_label, model, prompts.len());
+                                        
+                                        // The backend, its client and the token cap all come from
+                                        // the same node, the first one if the label isn't an alias.
+                                        let interview_node = config.nodes.iter()
+                                            .find(|n| n.alias == node_label)
+                                            .unwrap_or_else(|| config.nodes.first().unwrap());
+                                        let (uri, mode) = (interview_node.backend_uri(), interview_node.api_mode.clone());
+                                        let mut prompts = prompts;
+                                        if let Some(cap) = interview_node.interview_max_tokens {
+                                            cap_interview_prompts(&mut prompts, cap);
+                                        }
+                                        
+                                        let ahead = interviews_pending.fetch_add(1, Ordering::SeqCst);
+                                        if ahead > 0 {
+                                            info!("[INTERVIEW] Interview {} for {} queued behind {} other interview(s)", interview_id, node_label, ahead);
+                                        }
+
+                                        // Interviews run off the connection loop so inference keeps
+                                        // flowing; the semaphore is FIFO, so queued interviews start
+                                        // in arrival order.
+                                        let sem = interview_semaphore.clone();
+                                        let pending = interviews_pending.clone();
+                                        let tx = tx.clone();
+                                        let daemon = daemon.clone();
+                                        let http = http.node(&interview_node.alias).http.clone();
+                                        let node_label = node_label.to_string();
+                                        tokio::spawn(async move {
+                                            let interview_result = {
+                                                let _permit = sem.acquire().await.expect("semaphore closed");
+                                                // The timeout starts once the interview is running, not while queued.
+                                                let timeout = (timeout_ms > 0).then(|| Duration::from_millis(timeout_ms as u64));
+                                                execute_interview(&http, &uri, &interview_id, &model, prompts, &mode, timeout).await
+                                            };
+                                            pending.fetch_sub(1, Ordering::SeqCst);
+
+                                            match serde_json::to_string(&interview_result) {
+                                                Ok(json) => {
+                                                    if tx.send(Outbound::unordered(json)).await.is_err() {
+                                                        error!("[INTERVIEW] Failed to send result for {}: connection closed", node_label);
+                                                    } else {
+                                                        info!("[INTERVIEW] Result queued for send for {}", node_label);
+                                                    }
+                                                }
+                                                Err(e) => {
+                                                    daemon.metrics.serialization_failures.fetch_add(1, Ordering::SeqCst);
+                                                    error!("[INTERVIEW] Failed to serialize result for {}: {}", node_label, e);
+                                                }
+                                            }
+                                        });
+                                    }
+                                    ServerMessage::INTERVIEW_COMPLETE { interview_id: _, node_id, tier, accuracy, tokens_per_sec, reason } => {
+                                        let node_label = node_id.as_deref().unwrap_or("operator");
+                                        info!("=====================================");
+                                        info!("[INTERVIEW] Quality Tier Assigned for {}!", node_label);
+                                        info!("  Tier: {}", tier.to_uppercase());
+                                        info!("  Accuracy: {:.1}%", accuracy);
+                                        info!("  Speed: {:.1} tokens/sec", tokens_per_sec);
+                                        info!("  Reason: {}", reason);
+                                        info!("=====================================");
+                                        
+                                        if tier == "failed" {
+                                            error!("Node {} failed quality check - connection will be closed", node_label);
+                                        }
+                                    }
+                                    ServerMessage::INFERENCE_REQUEST { request_id, payload, metadata } => {
+                                        let count = TOTAL_REQUESTS.fetch_add(1, Ordering::SeqCst) + 1;
+                                        let mut timeline = daemon.profiler.as_ref().map(|_| RequestTimeline::new());
+                                        daemon.touch_last_request();
+                                        stats.requests.fetch_add(1, Ordering::SeqCst);
+
+                                        match daemon.dedup.begin(&request_id, &config.dedup) {
+                                            DedupOutcome::New => {}
+                                            DedupOutcome::InFlight => {
+                                                warn!("[#{}] Duplicate request {} is still running - ignoring redelivery", count, request_id);
+                                                daemon.metrics.deduped_in_flight.fetch_add(1, Ordering::SeqCst);
+                                                continue;
+                                            }
+                                            DedupOutcome::Memoized(json) => {
+                                                info!("[#{}] Duplicate request {} answered from memo", count, request_id);
+                                                daemon.metrics.memo_hits.fetch_add(1, Ordering::SeqCst);
+                                                write.send(Message::Text(with_metadata(json, &metadata))).await?;
+                                                continue;
+                                            }
+                                        }
+
+                                        // Taken before admission so rejections are released in
+                                        // request order too.
+                                        let seq = next_request_seq;
+                                        next_request_seq += 1;
+
+                                        let node = select_node(config, daemon, &payload.model);
+                                        let options = payload.generation_options();
+                                        let admission = if node.capacity == 0 {
+                                            Err(format!("no_capacity: node {} is configured with capacity 0 and does not accept requests", node.alias))
+                                        } else if let Err(e) = check_acceptance(node, &payload) {
+                                            Metrics::inc(&daemon.metrics.not_accepted, &node.alias);
+                                            Err(e)
+                                        } else if daemon.node_disabled(&node.alias) {
+                                            Err(format!("node_disabled: node {} is temporarily disabled after a high failure rate", node.alias))
+                                        } else if config.wallet.require_valid_wallet && daemon.wallet_status().status == "rejected" {
+                                            Err("wallet_rejected: the server rejected this operator's payout address".to_string())
+                                        } else if let Some(reason) = daemon.overloaded() {
+                                            daemon.metrics.overload_rejections.fetch_add(1, Ordering::SeqCst);
+                                            Err(format!("overloaded: {}", reason))
+                                        } else {
+                                            check_backend_support(node, &options)
+                                        };
+                                        if let Err(e) = admission {
+                                            warn!("[#{}] Rejected {}: {}", count, request_id, e);
+                                            stats.errors.fetch_add(1, Ordering::SeqCst);
+                                            daemon.dedup.finish(&request_id, None, &config.dedup);
+                                            let rejection = ClientMessage::inference_error(request_id, e, metadata.clone());
+                                            for json in reorder.accept(Outbound { seq: Some(seq), json: serde_json::to_string(&rejection)? }) {
+                                                write.send(Message::Text(json)).await?;
+                                            }
+                                            continue;
+                                        }
+
+                                        if let Some(&rpm) = config.model_rate_limits.get(&payload.model) {
+                                            if !daemon.rate_limiter.try_acquire(&payload.model, rpm) {
+                                                warn!("[#{}] Rate limited: {} ({}) exceeds {} requests/min", count, request_id, payload.model, rpm);
+                                                Metrics::inc(&daemon.metrics.rate_limited, &payload.model);
+                                                stats.errors.fetch_add(1, Ordering::SeqCst);
+                                                daemon.dedup.finish(&request_id, None, &config.dedup);
+                                                let rejection = ClientMessage::inference_error(request_id, format!("rate_limited: model {} is limited to {} requests/min on this node", payload.model, rpm), metadata.clone());
+                                                for json in reorder.accept(Outbound { seq: Some(seq), json: serde_json::to_string(&rejection)? }) {
+                                                    write.send(Message::Text(json)).await?;
+                                                }
+                                                continue;
+                                            }
+                                        }
+
+                                        let uri = node.backend_uri();
+                                        let mode = node.api_mode.clone();
+                                        let alias = node.alias.clone();
+                                        let latency_window = node.capacity_control.as_ref()
+                                            .map(|cc| cc.window)
+                                            .unwrap_or(DEFAULT_LATENCY_WINDOW);
+                                        let mut model = payload.model.clone();
+                                        let fallback_from = fallback_model_for(config, node, daemon, &model).map(|fallback| {
+                                            info!("[#{}] Model {} is not available on {} - serving fallback {}", count, model, alias, fallback);
+                                            Metrics::inc(&daemon.metrics.model_fallbacks, &alias);
+                                            std::mem::replace(&mut model, fallback)
+                                        });
+                                        if let Some(variants) = node.model_variants.get(&model) {
+                                            let available: Vec<ModelVariant> = {
+                                                let states = daemon.nodes.lock().unwrap();
+                                                let discovered = states.get(&alias).map(|s| s.models.as_slice()).unwrap_or(&[]);
+                                                variants.iter().filter(|v| v.weight > 0 && discovered.contains(&v.model)).cloned().collect()
+                                            };
+                                            let candidates = if available.is_empty() { variants.as_slice() } else { available.as_slice() };
+                                            let variant = choose_variant(candidates, &request_id, config.variant_seed).to_string();
+                                            daemon.record_variant(&model, &variant);
+                                            info!("[#{}] Model {} mapped to variant {}", count, model, variant);
+                                            model = variant;
+                                        }
+                                        let mut messages = payload.messages;
+                                        if let Some(system_prompt) = system_prompt_for(node, &payload.model, &model) {
+                                            add_system_prompt(&mut messages, system_prompt);
+                                        }
+                                        let stream = payload.stream && config.stream_responses;
+                                        let stream_limits = node.stream.clone();
+                                        
+                                        info!("[#{}] Inference request: {} ({}) via {} [queued]", count, request_id, model, mode);
+                                        
+                                        daemon.start_request(&alias);
+                                        let sem = semaphore.clone();
+                                        let tx = tx.clone();
+                                        let daemon = daemon.clone();
+                                        let backend = http.node(&node.alias).clone();
+                                        let http = backend.http.clone();
+                                        let max_task_lifetime = Duration::from_secs(config.max_task_lifetime_secs);
+                                        let dedup_key = request_id.clone();
+                                        let dedup_limits = config.dedup.clone();
+                                        let compression = config.response_compression.clone();
+                                        let empty_policy = config.empty_response_policy.clone();
+                                        let coalesce = config.coalesce.clone();
+                                        let node_stats = config.node_stats.is_some();
+                                        let stats = stats.clone();
+                                        let auto_disable = node.auto_disable.clone();
+                                        let requested_model = payload.model.clone();
+                                        let prompt_hook = node.pre_prompt_hook.clone();
+                                        let prompt_template = prompt_template_for(node, &payload.model, &model)
+                                            .map(|t| (t.clone(), template_context(node, &payload.model)));
+                                        let reasoning_config = node.strip_reasoning.clone();
+                                        let include_reasoning = payload.include_reasoning;
+                                        if let Some(t) = timeline.as_mut() {
+                                            t.mark("admit");
+                                        }
+                                        
+                                        tokio::spawn(async move {
+                                            // Taken before the hook so hooks are bounded by the thread
+                                            // pool too, and before the lifetime starts so time spent
+                                            // queued doesn't count against it.
+                                            let mut permit = Some(sem.acquire().await.expect("semaphore closed"));
+                                            if let Some(t) = timeline.as_mut() {
+                                                t.mark("queue");
+                                            }
+                                            let work = async {
+                                                let mut messages = messages;
+                                                if let Some(ref hook) = prompt_hook {
+                                                    let input = PromptHookInput { request_id: &request_id, node: &alias, model: &requested_model, messages: &messages };
+                                                    match run_prompt_hook(hook, &input).await {
+                                                        Ok(rewritten) => messages = rewritten,
+                                                        Err(e) => {
+                                                            let error = match e {
+                                                                PromptHookError::Rejected(reason) => {
+                                                                    warn!("[#{}] Prompt hook rejected {}: {}", count, request_id, reason);
+                                                                    Some(format!("rejected_by_hook: {}", reason))
+                                                                }
+                                                                PromptHookError::Failed(e) => {
+                                                                    Metrics::inc(&daemon.metrics.prompt_hook_failures, &alias);
+                                                                    if hook.on_failure == "reject" {
+                                                                        error!("[#{}] Prompt hook failed for {}: {} - rejecting", count, request_id, e);
+                                                                        Some("hook_failed: prompt preprocessing failed".to_string())
+                                                                    } else {
+                                                                        warn!("[#{}] Prompt hook failed for {}: {} - using the original prompt", count, request_id, e);
+                                                                        None
+                                                                    }
+                                                                }
+                                                            };
+                                                            if let Some(error) = error {
+                                                                return ClientMessage::inference_error(request_id.clone(), error, metadata.clone());
+                                                            }
+                                                        }
+                                                    }
+                                                    if let Some(t) = timeline.as_mut() {
+                                                        t.mark("hook");
+                                                    }
+                                                }
+                                                if let Some((ref template, ref context)) = prompt_template {
+                                                    messages = apply_prompt_template(template, &messages, context);
+                                                    debug!("[#{}] Rendered prompt template for {} ({} chars)", count, model, messages[0].content.len());
+                                                }
+                                                let audit_messages = daemon.audit.as_ref().map(|_| messages.clone());
+                                                let mut forwarder = stream.then(|| {
+                                                    ChunkForwarder::new(request_id.clone(), metadata.clone(), tx.clone(), stream_limits.clone())
+                                                        .with_reasoning_filter(reasoning_config.as_ref())
+                                                });
+                                                // Streams go to a single request, so only whole responses are shared.
+                                                let flight = (coalesce.enabled && !stream).then(|| {
+                                                    daemon.coalescer.join(coalesce_key(&alias, &requested_model, &model, &messages, &options, include_reasoning))
+                                                });
+                                                let waited = std::time::Instant::now();
+                                                let (leader, shared) = match flight {
+                                                    Some(Flight::Leader(leader)) => (Some(leader), None),
+                                                    Some(Flight::Follower(rx)) => {
+                                                        info!("[#{}] {} is identical to a running request - waiting for its result", count, request_id);
+                                                        // A follower doesn't use the backend, so it frees its thread.
+                                                        permit = None;
+                                                        (None, rx.await.ok())
+                                                    }
+                                                    None => (None, None),
+                                                };
+                                                let (result, started) = match shared {
+                                                    Some(result) => {
+                                                        daemon.metrics.coalesced_requests.fetch_add(1, Ordering::SeqCst);
+                                                        let result = result.map(|mut resp| {
+                                                            resp.coalesced = Some(true);
+                                                            if coalesce.usage == "first" {
+                                                                resp.usage = resp.usage.map(|_| OpenAIUsage { prompt_tokens: 0, completion_tokens: 0, total_tokens: 0 });
+                                                            }
+                                                            resp
+                                                        });
+                                                        (result, waited)
+                                                    }
+                                                    None => {
+                                                        // The leader failed without a result, so this request runs itself.
+                                                        if permit.is_none() {
+                                                            permit = Some(sem.acquire().await.expect("semaphore closed"));
+                                                        }
+                                                        let slot = backend.acquire().await;
+
+                                                        info!("[#{}] Starting inference for {}", count, request_id);
+                                                        let started = std::time::Instant::now();
+                                                        let mut retry_messages = (empty_policy == "retry").then(|| messages.clone());
+                                                        // Reasoning is stripped before the empty check, so a
+                                                        // completion that is all reasoning counts as empty.
+                                                        let mut stripped = None;
+                                                        let mut strip = |result: Result<OpenAIResponse, String>| {
+                                                            result.map(|mut resp| {
+                                                                stripped = reasoning_config.as_ref().and_then(|c| strip_reasoning(&mut resp, c));
+                                                                resp
+                                                            })
+                                                        };
+                                                        let mut result = match forwarder.as_mut() {
+                                                            Some(forwarder) => chat_completion_stream(&http, &uri, &model, messages, &options, &mode, forwarder).await,
+                                                            None => strip(chat_completion(&http, &uri, &model, messages, &options, &mode).await),
+                                                        };
+                                                        if result.as_ref().is_ok_and(|r| r.is_empty()) {
+                                                            daemon.metrics.inc_empty_response(&alias);
+                                                            // Chunks already sent can't be taken back, so such a stream isn't retried.
+                                                            let streamed = forwarder.as_ref().is_some_and(|f| f.index > 0);
+                                                            if let Some(messages) = retry_messages.take().filter(|_| !streamed) {
+                                                                warn!("[#{}] Backend {} returned an empty completion for {} - retrying once", count, alias, request_id);
+                                                                result = match forwarder.as_mut() {
+                                                                    Some(forwarder) => chat_completion_stream(&http, &uri, &model, messages, &options, &mode, forwarder).await,
+                                                                    None => strip(chat_completion(&http, &uri, &model, messages, &options, &mode).await),
+                                                                };
+                                                                if result.as_ref().is_ok_and(|r| r.is_empty()) {
+                                                                    daemon.metrics.inc_empty_response(&alias);
+                                                                }
+                                                            }
+                                                        }
+                                                        // A backend that turns connections away while it is serving
+                                                        // others is most likely at its connection limit.
+                                                        let others = slot.others_in_flight();
+                                                        drop(slot);
+                                                        let result = match result {
+                                                            Err(e) if others > 0 && e.starts_with("backend_unreachable:") => {
+                                                                warn!("[#{}] Backend {} refused a connection with {} other request(s) in flight - consider connectionPool.maxConnections: {}", count, alias, others, e);
+                                                                Err(format!("connection_limit: backend of node {} refused a new connection while serving {} other request(s)", alias, others))
+                                                            }
+                                                            Ok(mut resp) if resp.is_empty() => {
+                                                                if empty_policy == "flag" {
+                                                                    warn!("[#{}] Backend {} returned an empty completion for {} - forwarding it flagged", count, alias, request_id);
+                                                                    resp.empty_response = Some(true);
+                                                                    Ok(resp)
+                                                                } else {
+                                                                    warn!("[#{}] Backend {} returned an empty completion for {} - reporting an error", count, alias, request_id);
+                                                                    Err("empty_response: backend returned an empty completion".to_string())
+                                                                }
+                                                            }
+                                                            other => other,
+                                                        };
+                                                        let stripped = forwarder.as_mut().and_then(|f| f.take_reasoning()).or(stripped);
+                                                        let token_timings = forwarder.as_ref().and_then(|f| f.token_timings());
+                                                        let chunks = forwarder.as_ref().map(|f| f.index);
+                                                        if let Some(t) = token_timings {
+                                                            debug!("[#{}] Inter-token latency over {} gaps: mean {}ms, p95 {}ms, max {}ms", count, t.gaps, t.mean_ms, t.p95_ms, t.max_ms);
+                                                        }
+                                                        let result: FlightResult = result.map(|mut resp| {
+                                                            resp.fallback_from = fallback_from;
+                                                            resp.token_timings = token_timings;
+                                                            resp.chunks = chunks;
+                                                            if let (Some(config), Some(reasoning)) = (&reasoning_config, stripped) {
+                                                                apply_stripped_reasoning(&mut resp, config, reasoning, include_reasoning);
+                                                            }
+                                                            resp
+                                                        });
+                                                        if let Some(leader) = leader {
+                                                            leader.finish(&result);
+                                                        }
+                                                        (result, started)
+                                                    }
+                                                };
+                                                if let Some(t) = timeline.as_mut() {
+                                                    // For streams, the wait for the first token is split from generation.
+                                                    match forwarder.as_ref().and_then(|f| f.first_token) {
+                                                        Some(first_token) => {
+                                                            t.mark_at("backend", first_token);
+                                                            t.mark("generate");
+                                                        }
+                                                        None => t.mark("backend"),
+                                                    }
+                                                }
+                                                if let (Some(audit), Some(messages)) = (&daemon.audit, audit_messages) {
+                                                    let latency_ms = started.elapsed().as_millis() as u64;
+                                                    audit.append(&AuditRecord::new(&request_id, &alias, &requested_model, &model, messages, &options, &result, latency_ms));
+                                                }
+
+                                                match result {
+                                                    Ok(openai_resp) => {
+                                                        let latency_ms = started.elapsed().as_millis() as u64;
+                                                        record_latency(&daemon, &alias, &model, latency_ms, latency_window);
+                                                        let usage = openai_resp.usage.as_ref();
+                                                        let prompt_tokens = usage.map(|u| u.prompt_tokens).unwrap_or(0);
+                                                        let completion_tokens = usage.map(|u| u.completion_tokens).unwrap_or(0);
+                                                        if node_stats {
+                                                            daemon.add_period_usage(&alias, prompt_tokens, completion_tokens, latency_ms);
+                                                        }
+                                                    
+                                                        let response = ClientMessage::inference_response(request_id.clone(), &openai_resp, metadata.clone(), &daemon.metrics);
+                                                        if response.error.is_none() {
+                                                            info!("[#{}] Completed successfully ({}+{} tokens)", count, prompt_tokens, completion_tokens);
+                                                            stats.tokens.fetch_add((prompt_tokens + completion_tokens) as u64, Ordering::SeqCst);
+                                                        }
+                                                        response
+                                                    }
+                                                    Err(e) => {
+                                                        error!("[#{}] Failed: {}", count, e);
+                                                        ClientMessage::inference_error(request_id.clone(), e, metadata.clone())
+                                                    }
+                                                }
+                                            };
+
+                                            let response = match tokio::time::timeout(max_task_lifetime, work).await {
+                                                Ok(response) => response,
+                                                Err(_) => {
+                                                    error!("[#{}] Task for {} exceeded max lifetime of {}s and was aborted - check backend {}",
+                                                        count, request_id, max_task_lifetime.as_secs(), uri);
+                                                    ClientMessage::inference_error(request_id, "Inference task exceeded maximum lifetime".to_string(), metadata.clone())
+                                                }
+                                            };
+                                            drop(permit);
+
+                                            let mut json = match serde_json::to_string(&response) {
+                                                Ok(json) => json,
+                                                Err(e) => {
+                                                    // Still answer the request so the server doesn't wait on it
+                                                    // until its own timeout.
+                                                    daemon.metrics.serialization_failures.fetch_add(1, Ordering::SeqCst);
+                                                    error!("[#{}] Failed to encode {} for {}: {}", count, response.msg_type, response.request_id.as_deref().unwrap_or("-"), e);
+                                                    serde_json::json!({
+                                                        "type": "INFERENCE_ERROR",
+                                                        "request_id": response.request_id,
+                                                        "error": format!("Failed to serialize response: {}", e),
+                                                        "metadata": response.metadata,
+                                                    })
+                                                    .to_string()
+                                                }
+                                            };
+                                            if let Some(compressed) = compress_response(&response, &compression) {
+                                                let saved = json.len().saturating_sub(compressed.len());
+                                                daemon.metrics.compressed_responses.fetch_add(1, Ordering::SeqCst);
+                                                daemon.metrics.compression_saved_bytes.fetch_add(saved as u64, Ordering::SeqCst);
+                                                info!("[#{}] Compressed response {} -> {} bytes", count, json.len(), compressed.len());
+                                                json = compressed;
+                                            }
+                                            let succeeded = response.msg_type == "INFERENCE_RESPONSE";
+                                            if !succeeded {
+                                                stats.errors.fetch_add(1, Ordering::SeqCst);
+                                            }
+                                            daemon.dedup.finish(&dedup_key, succeeded.then_some(json.as_str()), &dedup_limits);
+                                            daemon.finish_request(&alias, !succeeded, node_stats);
+                                            if let Some(ref ad) = auto_disable {
+                                                if let Some(rate) = daemon.record_outcome(&alias, !succeeded, ad) {
+                                                    error!("[HEALTH] Node {} disabled for {}s: {:.0}% of recent requests failed (threshold {:.0}%)",
+                                                        alias, ad.cooldown_secs, rate * 100.0, ad.failure_rate * 100.0);
+                                                    if let Some(ref url) = ad.webhook_url {
+                                                        send_node_webhook(&http.client, url, "node_disabled", &alias, Some(rate));
+                                                    }
+                                                }
+                                            }
+                                            if let Some(t) = timeline.as_mut() {
+                                                t.mark("serialize");
+                                            }
+                                            let _ = tx.send(Outbound { seq: Some(seq), json }).await;
+                                            info!("[#{}] Response queued for send", count);
+                                            if let (Some(profiler), Some(t)) = (&daemon.profiler, timeline.as_mut()) {
+                                                t.mark("enqueue");
+                                                profiler.record(count, &dedup_key, t);
+                                            }
+                                        });
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                warn!("Failed to parse server message: {} - {}", e, text);
+                            }
+                        }
+                    }
+                    Some(Ok(Message::Close(frame))) => {
+                        info!("Server closed connection");
+                        stats.set_end_reason(match frame {
+                            Some(frame) => {
+                                *stats.close_code.lock().unwrap() = Some(u16::from(frame.code));
+                                format!("server closed connection ({} {})", u16::from(frame.code), frame.reason)
+                            }
+                            None => "server closed connection".to_string(),
+                        });
+                        break;
+                    }
+                    Some(Err(e)) => {
+                        error!("WebSocket error: {}", e);
+                        stats.set_end_reason(format!("websocket error: {}", e));
+                        break;
+                    }
+                    None => {
+                        info!("WebSocket stream ended");
+                        stats.set_end_reason("stream ended");
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            _ = supervisor_tick.tick(), if supervision_enabled && daemon.connection() == ConnectionState::Authenticated => {
+                for node_config in &config.nodes {
+                    match daemon.backend_up(&node_config.alias) {
+                        Some(false) if !paused_nodes.contains(&node_config.alias) => {
+                            warn!("[BACKEND] {} backend is down - pausing registration", node_config.alias);
+                            send_json(&mut write, &RegisterNodeMessage::new(node_config, vec![], 0)).await?;
+                            paused_nodes.insert(node_config.alias.clone());
+                        }
+                        Some(true) if paused_nodes.contains(&node_config.alias) && !daemon.node_disabled(&node_config.alias) => {
+                            info!("[BACKEND] {} backend is back - resuming registration", node_config.alias);
+                            register_node(&mut write, &http.node(&node_config.alias).http, node_config, daemon).await?;
+                            paused_nodes.remove(&node_config.alias);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ = idle_tick.tick(), if config.idle_warning_secs.is_some() && daemon.is_ready() => {
+                let window = Duration::from_secs(config.idle_warning_secs.unwrap_or_default());
+                let idle_for = match daemon.last_request() {
+                    Some(last) if last > idle_reference => last.elapsed(),
+                    _ => idle_reference.elapsed(),
+                };
+
+                if idle_for >= window {
+                    warn!("[IDLE] No inference requests for {}s while registered and healthy - the server may have dropped this node from routing", idle_for.as_secs());
+                    idle_reference = std::time::Instant::now();
+                    if config.idle_reregister {
+                        info!("[IDLE] Re-registering nodes");
+                        for node_config in &config.nodes {
+                            if !paused_nodes.contains(&node_config.alias) {
+                                register_node(&mut write, &http.node(&node_config.alias).http, node_config, daemon).await?;
+                            }
+                        }
+                    }
+                }
+            }
+            _ = health_tick.tick(), if auto_disable_enabled && daemon.connection() == ConnectionState::Authenticated => {
+                for node_config in &config.nodes {
+                    let Some(ref ad) = node_config.auto_disable else { continue };
+                    let alias = &node_config.alias;
+                    let disabled = daemon.node_disabled(alias);
+                    if disabled && !paused_nodes.contains(alias) {
+                        warn!("[HEALTH] Deregistering auto-disabled node {}", alias);
+                        send_json(&mut write, &RegisterNodeMessage::new(node_config, vec![], 0)).await?;
+                        paused_nodes.insert(alias.clone());
+                    } else if !disabled && paused_nodes.contains(alias) && daemon.backend_up(alias) != Some(false) {
+                        daemon.enable_node(alias);
+                        info!("[HEALTH] Re-enabling node {}", alias);
+                        register_node(&mut write, &http.node(&node_config.alias).http, node_config, daemon).await?;
+                        paused_nodes.remove(alias);
+                        if let Some(ref url) = ad.webhook_url {
+                            send_node_webhook(&http.shared.http.client, url, "node_enabled", alias, None);
+                        }
+                    }
+                }
+            }
+            _ = model_tick.tick(), if model_check_enabled && daemon.connection() == ConnectionState::Authenticated => {
+                for node_config in &config.nodes {
+                    if paused_nodes.contains(&node_config.alias) {
+                        continue;
+                    }
+                    if let Some(models) = daemon.take_finished_warmup(&node_config.alias) {
+                        info!("[WARMUP] Registering {} now that its warmup has finished", node_config.alias);
+                        send_node_registration(&mut write, node_config, daemon, models, true).await?;
+                        continue;
+                    }
+                    let Some((added, removed)) = daemon.model_changes(node_config) else { continue };
+                    if !added.is_empty() {
+                        info!("[MODELS] {} now serves: {:?}", node_config.alias, added);
+                    }
+                    if !removed.is_empty() {
+                        info!("[MODELS] {} no longer serves: {:?}", node_config.alias, removed);
+                    }
+                    let catalog = daemon.backend_catalog(&node_config.alias).unwrap_or_default();
+                    info!("[MODELS] Re-registering {} with its {}-model catalog", node_config.alias, catalog.len());
+                    register_node_models(&mut write, &http.node(&node_config.alias).http, node_config, daemon, Ok(catalog)).await?;
+                }
+            }
+            _ = tokio::time::sleep_until(wallet_retry_at.unwrap_or_else(tokio::time::Instant::now)), if wallet_retry_at.is_some() => {
+                wallet_retry_at = None;
+                if let Some(ref payout_addr) = config.payout_address {
+                    let attempt = daemon.start_wallet_update(false);
+                    info!("[WALLET] Retrying payout wallet update (attempt {})", attempt);
+                    update_wallet(&mut write, payout_addr).await?;
+                }
+            }
+            _ = node_stats_tick.tick(), if config.node_stats.is_some() && daemon.connection() == ConnectionState::Authenticated => {
+                let report = NodeStatsMessage {
+                    msg_type: "NODE_STATS".to_string(),
+                    interval_secs: node_stats_interval.as_secs(),
+                    nodes: daemon.take_period_stats(config),
+                };
+                debug!("[STATS] Sending NODE_STATS for {} node(s)", report.nodes.len());
+                send_json(&mut write, &report).await?;
+            }
+            _ = summary_tick.tick() => {
+                info!("[SUMMARY] {}", daemon.summary());
+            }
+            _ = variant_report_tick.tick(), if variants_enabled => {
+                for (canonical, variants) in daemon.variant_split() {
+                    let split: Vec<String> = variants
+                        .iter()
+                        .map(|(variant, count, pct)| format!("{}: {} ({:.1}%)", variant, count, pct))
+                        .collect();
+                    info!("[VARIANTS] {} -> {}", canonical, split.join(", "));
+                }
+            }
+            _ = capacity_tick.tick(), if capacity_control_enabled => {
+                for node_config in &config.nodes {
+                    let Some(ref cc) = node_config.capacity_control else { continue };
+                    if paused_nodes.contains(&node_config.alias) {
+                        continue;
+                    }
+                    let update = {
+                        let mut states = daemon.nodes.lock().unwrap();
+                        let state = states.get_mut(&node_config.alias).unwrap();
+                        match next_capacity(node_config, cc, state) {
+                            Some(next) if !state.models.is_empty() => {
+                                let previous = state.effective_capacity;
+                                state.effective_capacity = next;
+                                Some((previous, next, state.models.clone()))
+                            }
+                            _ => None,
+                        }
+                    };
+
+                    if let Some((previous, next, models)) = update {
+                        info!("[CAPACITY] {} capacity {} -> {} (p{} latency threshold {}ms/{}ms)",
+                            node_config.alias, previous, next, cc.percentile, cc.latency_low_ms, cc.latency_high_ms);
+                        let register_msg = RegisterNodeMessage::new(node_config, models, next).with_warmup(daemon.warmup(&node_config.alias));
+                        if let Err(e) = write.send(Message::Text(serde_json::to_string(&register_msg)?)).await {
+                            error!("[CAPACITY] Failed to update capacity for {}: {}", node_config.alias, e);
+                        }
+                    }
+                }
+            }
+            _ = heartbeat_tick.tick() => {
+                let report = config.report_uptime;
+                let heartbeat = HeartbeatMessage {
+                    msg_type: "HEARTBEAT".to_string(),
+                    config_fingerprint: CONFIG_FINGERPRINT.get().cloned(),
+                    uptime_secs: report.then(|| daemon.uptime_secs()),
+                    connection_uptime_secs: report.then(|| stats.started.elapsed().as_secs()),
+                    restart_reason: report.then(|| daemon.restart.reason.clone()),
+                    nodes: config.heartbeat_telemetry.then(|| daemon.take_heartbeat_telemetry(config)),
+                };
+                if write.send(Message::Text(serde_json::to_string(&heartbeat)?)).await.is_err() {
+                    warn!("Failed to send heartbeat");
+                    stats.set_end_reason("heartbeat send failed");
+                    break;
+                }
+            }
+            _ = wait_for_shutdown() => {}
+        }
+    }
+
+    if !RUNNING.load(Ordering::SeqCst) && daemon.connection() == ConnectionState::Authenticated {
+        if let Some(ref going_away) = config.going_away {
+            let nodes: Vec<String> = daemon
+                .registered_aliases(config)
+                .into_iter()
+                .filter(|alias| !paused_nodes.contains(alias))
+                .collect();
+            info!("[SHUTDOWN] Telling the server {} node(s) are going away ({})", nodes.len(), going_away.reason);
+            let msg = GoingAwayMessage {
+                msg_type: "GOING_AWAY".to_string(),
+                nodes,
+                reason: going_away.reason.clone(),
+                expected_return_secs: going_away.expected_return_secs,
+            };
+            if let Err(e) = send_json(&mut write, &msg).await {
+                warn!("[SHUTDOWN] Failed to send GOING_AWAY: {}", e);
+            } else {
+                let frame = CloseFrame { code: CloseCode::Away, reason: going_away.reason.clone().into() };
+                let _ = write.send(Message::Close(Some(frame))).await;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+struct BenchOptions {
+    node: Option<String>,
+    model: Option<String>,
+    concurrency: usize,
+    duration: Duration,
+    prompt_size: usize,
+}
+
+#[derive(Default)]
+struct BenchStats {
+    latencies_ms: Vec<u64>,
+    completion_tokens: u64,
+    errors: u64,
+    last_error: Option<String>,
+}
+
+/// Fires `concurrency` back-to-back request loops at one node for the given
+/// duration, without connecting to the PIN server.
+async fn run_bench(config: &Config, http: &BackendClients, opts: BenchOptions) -> Result<(), String> {
+    let node = match opts.node {
+        Some(ref alias) => config
+            .nodes
+            .iter()
+            .find(|n| &n.alias == alias)
+            .ok_or_else(|| format!("No node with alias '{}'", alias))?,
+        None => config.nodes.first().ok_or("No nodes configured")?,
+    };
+    let http = &http.node(&node.alias).http;
+
+    let model = match opts.model.clone().or_else(|| node.interview_model.clone()) {
+        Some(m) => m,
+        None => get_models(http, &node.backend_uri(), &node.api_mode)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("No models found on {}", node.inference_uri))?,
+    };
+
+    let concurrency = opts.concurrency.max(1);
+    let filler = "The quick brown fox jumps over the lazy dog. ";
+    let words_per_filler = filler.split_whitespace().count();
+    let prompt = format!(
+        "Summarize the following text in one paragraph.\n\n{}",
+        filler.repeat(opts.prompt_size.div_ceil(words_per_filler))
+    );
+
+    println!();
+    println!("    Benchmarking {} ({}) model {}", node.alias, node.inference_uri, model);
+    println!("    Concurrency: {} | Duration: {}s | Prompt: ~{} words", concurrency, opts.duration.as_secs(), opts.prompt_size);
+    println!();
+
+    let stats = Arc::new(std::sync::Mutex::new(BenchStats::default()));
+    let deadline = tokio::time::Instant::now() + opts.duration;
+    let started = std::time::Instant::now();
+    let mut workers = tokio::task::JoinSet::new();
+
+    for _ in 0..concurrency {
+        let http = http.clone();
+        let uri = node.backend_uri();
+        let mode = node.api_mode.clone();
+        let model = model.clone();
+        let prompt = prompt.clone();
+        let stats = stats.clone();
+
+        workers.spawn(async move {
+            while tokio::time::Instant::now() < deadline {
+                let messages = vec![ChatMessage {
+                    role: "user".to_string(),
+                    content: prompt.clone(),
+                }];
+                let request_start = std::time::Instant::now();
+                let result = chat_completion(&http, &uri, &model, messages, &GenerationOptions::default(), &mode).await;
+                let elapsed = request_start.elapsed().as_millis() as u64;
+
+                let mut stats = stats.lock().unwrap();
+                match result {
+                    Ok(resp) => {
+                        stats.latencies_ms.push(elapsed);
+                        stats.completion_tokens += resp.usage.map(|u| u.completion_tokens as u64).unwrap_or(0);
+                    }
+                    Err(e) => {
+                        stats.errors += 1;
+                        stats.last_error = Some(e);
+                    }
+                }
+            }
+        });
+    }
+
+    while workers.join_next().await.is_some() {}
+    let wall = started.elapsed().as_secs_f64();
+
+    let mut stats = stats.lock().unwrap();
+    stats.latencies_ms.sort_unstable();
+    let ok = stats.latencies_ms.len();
+    let p = |pct: f64| percentile(&stats.latencies_ms, pct).unwrap_or(0);
+
+    println!("    Requests:    {} ok, {} failed in {:.1}s", ok, stats.errors, wall);
+    println!("    Throughput:  {:.2} req/s, {:.1} tokens/s", ok as f64 / wall, stats.completion_tokens as f64 / wall);
+    println!("    Latency:     p50 {}ms | p90 {}ms | p99 {}ms", p(50.0), p(90.0), p(99.0));
+    if let Some(ref e) = stats.last_error {
+        println!("    Last error:  {}", e);
+    }
+    println!();
+
+    if ok == 0 {
+        return Err("No requests succeeded".to_string());
+    }
+    Ok(())
+}
+
+struct ReplayOptions {
+    log: Option<PathBuf>,
+    node: Option<String>,
+    model: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+}
+
+fn parse_replay_time(value: &Option<String>, flag: &str) -> Result<Option<chrono::DateTime<chrono::FixedOffset>>, String> {
+    value
+        .as_deref()
+        .map(|v| chrono::DateTime::parse_from_rfc3339(v).map_err(|e| format!("Invalid --{} '{}': {}", flag, v, e)))
+        .transpose()
+}
+
+/// Describes how two responses differ, or `None` when they are identical.
+fn describe_content_diff(old: &str, new: &str) -> Option<String> {
+    if old == new {
+        return None;
+    }
+    let at = old.chars().zip(new.chars()).take_while(|(a, b)| a == b).count();
+    let snippet = |s: &str| s.chars().skip(at).take(40).collect::<String>();
+    Some(format!(
+        "differs at char {} ({} -> {} chars): {:?} vs {:?}",
+        at,
+        old.chars().count(),
+        new.chars().count(),
+        snippet(old),
+        snippet(new)
+    ))
+}
+
+/// Re-issues logged requests one at a time against the local backends (the
+/// PIN server is never contacted) and reports content, token and latency
+/// changes against the logged results.
+async fn run_replay(config: &Config, http: &BackendClients, opts: ReplayOptions) -> Result<(), String> {
+    let path = opts
+        .log
+        .or_else(|| config.audit_log.clone())
+        .ok_or("No audit log given (use --log or set auditLog)")?;
+    let since = parse_replay_time(&opts.since, "since")?;
+    let until = parse_replay_time(&opts.until, "until")?;
+    let forced_node = match opts.node {
+        Some(ref alias) => Some(
+            config
+                .nodes
+                .iter()
+                .find(|n| &n.alias == alias)
+                .ok_or_else(|| format!("No node with alias '{}'", alias))?,
+        ),
+        None => None,
+    };
+
+    let contents = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let mut records = Vec::new();
+    for (line_no, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: AuditRecord = serde_json::from_str(line).map_err(|e| format!("{}:{}: {}", path.display(), line_no + 1, e))?;
+        if opts.model.as_ref().is_some_and(|m| m != &record.model && m != &record.backend_model) {
+            continue;
+        }
+        if since.is_some() || until.is_some() {
+            let logged = chrono::DateTime::parse_from_rfc3339(&record.timestamp)
+                .map_err(|e| format!("{}:{}: bad timestamp: {}", path.display(), line_no + 1, e))?;
+            if since.is_some_and(|t| logged < t) || until.is_some_and(|t| logged >= t) {
+                continue;
+            }
+        }
+        records.push(record);
+    }
+
+    if records.is_empty() {
+        return Err(format!("No matching requests in {}", path.display()));
+    }
+
+    println!();
+    println!("    Replaying {} request(s) from {}", records.len(), path.display());
+    println!();
+
+    let (mut identical, mut changed, mut failed) = (0, 0, 0);
+    for record in records {
+        let node = forced_node
+            .or_else(|| config.nodes.iter().find(|n| n.alias == record.node))
+            .ok_or_else(|| format!("Request {} was served by unknown node '{}' (use --node)", record.request_id, record.node))?;
+
+        let started = std::time::Instant::now();
+        let result = chat_completion(&http.node(&node.alias).http, &node.backend_uri(), &record.backend_model, record.messages, &record.options, &node.api_mode).await;
+        let latency_ms = started.elapsed().as_millis() as u64;
+
+        println!("    {} {} via {}", record.request_id, record.backend_model, node.alias);
+        match result {
+            Ok(resp) => {
+                let content = resp.choices.first().map(|c| c.message.content.as_str()).unwrap_or("");
+                let completion_tokens = resp.usage.as_ref().map(|u| u.completion_tokens).unwrap_or(0);
+                match (&record.content, &record.error) {
+                    (Some(old), _) => match describe_content_diff(old, content) {
+                        None => {
+                            identical += 1;
+                            println!("      content:  identical");
+                        }
+                        Some(diff) => {
+                            changed += 1;
+                            println!("      content:  {}", diff);
+                        }
+                    },
+                    (None, error) => {
+                        changed += 1;
+                        println!("      content:  now succeeds (logged error: {})", error.as_deref().unwrap_or("none"));
+                    }
+                }
+                println!("      tokens:   {} -> {}", record.completion_tokens, completion_tokens);
+            }
+            Err(e) => {
+                failed += 1;
+                println!("      error:    {}", e);
+            }
+        }
+        println!("      latency:  {}ms -> {}ms", record.latency_ms, latency_ms);
+    }
+
+    println!();
+    println!("    Identical: {} | Changed: {} | Failed: {}", identical, changed, failed);
+    println!();
+    Ok(())
+}
+
+/// Recursively overlays `overlay` onto `base`. Objects are merged key by key;
+/// anything else (including arrays such as `nodes`) is replaced wholesale.
+fn merge_json(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_json(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Applies the named profile on top of the base config. The profile is taken
+/// from the config's `profiles` section, or else from a `<stem>.<name>.json`
+/// file next to the config (e.g. `config.prod.json`).
+fn apply_profile(config: &mut serde_json::Value, config_path: &std::path::Path, profile: &str) -> Result<(), String> {
+    let inline = config
+        .as_object_mut()
+        .and_then(|c| c.remove("profiles"))
+        .and_then(|mut profiles| profiles.as_object_mut().and_then(|p| p.remove(profile)));
+
+    let overlay = match inline {
+        Some(overlay) => overlay,
+        None => {
+            let stem = config_path.file_stem().and_then(|s| s.to_str()).unwrap_or("config");
+            let profile_path = config_path.with_file_name(format!("{}.{}.json", stem, profile));
+            let contents = std::fs::read_to_string(&profile_path).map_err(|_| {
+                format!("Config profile '{}' not found in \"profiles\" or at {:?}", profile, profile_path)
+            })?;
+            serde_json::from_str(&contents)
+                .map_err(|e| format!("Failed to parse profile {:?}: {}", profile_path, e))?
+        }
+    };
+
+    if !overlay.is_object() {
+        return Err(format!("Config profile '{}' must be a JSON object", profile));
+    }
+    merge_json(config, overlay);
+    Ok(())
 }
 
-async fn chat_completion_ollama(
-    base_url: &str,
-    model: &str,
-    messages: Vec<ChatMessage>,
-) -> Result<OpenAIResponse, String> {
-    let client = reqwest::Client::new();
-    let url = format!("{}/api/chat", base_url.trim_end_matches('/'));
+/// Minimal HTTP/1.1 admin endpoint. Only serves small GET requests, so a
+/// hand-rolled parser is enough and avoids pulling in a server framework.
+async fn run_admin_server(listener: tokio::net::TcpListener, daemon: Arc<DaemonState>) {
+    loop {
+        let (socket, _) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("[ADMIN] Accept failed: {}", e);
+                continue;
+            }
+        };
+        let daemon = daemon.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_admin_request(socket, &daemon).await {
+                warn!("[ADMIN] Request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_admin_request(mut socket: tokio::net::TcpStream, daemon: &DaemonState) -> std::io::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut buf = [0u8; 1024];
+    let n = tokio::time::timeout(Duration::from_secs(5), socket.read(&mut buf))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "read timeout"))??;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or("GET");
+    let path = parts.next().unwrap_or("/");
+
+    let (status, body) = admin_route(method, path, daemon);
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        _ => "Service Unavailable",
+    };
+    let content_type = if path == "/metrics" { "text/plain; version=0.0.4" } else { "application/json" };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, reason, content_type, body.len(), body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+fn admin_route(method: &str, path: &str, daemon: &DaemonState) -> (u16, String) {
+    // Manual re-enable of an auto-disabled node; the connection loop
+    // re-registers it on its next health check.
+    if let Some(alias) = path.strip_prefix("/nodes/").and_then(|rest| rest.strip_suffix("/enable")) {
+        if method != "POST" {
+            return (405, serde_json::json!({ "error": "use POST" }).to_string());
+        }
+        if !daemon.nodes.lock().unwrap().contains_key(alias) {
+            return (404, serde_json::json!({ "error": format!("unknown node {}", alias) }).to_string());
+        }
+        if !daemon.enable_node(alias) {
+            return (409, serde_json::json!({ "error": format!("node {} is not disabled", alias) }).to_string());
+        }
+        info!("[HEALTH] Node {} re-enabled via admin endpoint", alias);
+        return (200, serde_json::json!({ "enabled": alias }).to_string());
+    }
+
+    match path {
+        // Liveness: answering at all means the runtime is alive.
+        "/healthz" => (200, serde_json::json!({ "status": "ok" }).to_string()),
+        // Readiness: authenticated with at least one registered node whose
+        // backend answered model discovery.
+        "/readyz" => {
+            let ready = daemon.is_ready();
+            let body = serde_json::json!({
+                "ready": ready,
+                "connection": daemon.connection().as_str(),
+            });
+            (if ready { 200 } else { 503 }, body.to_string())
+        }
+        "/status" => (200, daemon.status_json().to_string()),
+        "/metrics" => (200, daemon.metrics.render(&[("response_memo", daemon.dedup.memo_stats())])),
+        _ => (404, serde_json::json!({ "error": "not found" }).to_string()),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(&args.log_level)),
+        )
+        .init();
+
+    println!();
+    println!("     █████╗ ██╗ █████╗ ███████╗    ██████╗ ██╗███╗   ██╗");
+    println!("    ██╔══██╗██║██╔══██╗██╔════╝    ██╔══██╗██║████╗  ██║");
+    println!("    ███████║██║███████║███████╗    ██████╔╝██║██╔██╗ ██║");
+    println!("    ██╔══██║██║██╔══██║╚════██║    ██╔═══╝ ██║██║╚██╗██║");
+    println!("    ██║  ██║██║██║  ██║███████║    ██║     ██║██║ ╚████║");
+    println!("    ╚═╝  ╚═╝╚═╝╚═╝  ╚═╝╚══════╝    ╚═╝     ╚═╝╚═╝  ╚═══╝");
+    println!();
+    println!("    PIN Client Daemon v2.1.0 - https://AiAssist.net");
+    println!();
+
+    let config_path = &args.config;
+    info!("Loading config from: {:?}", config_path);
+
+    let config_str = match std::fs::read_to_string(config_path) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to read config file {:?}: {}", config_path, e);
+            error!("Create config.json with: clientId, apiSecret, nodes");
+            error!("  Each node requires: alias, inferenceUri, apiMode, region, capacity");
+            std::process::exit(1);
+        }
+    };
+
+    let mut config_value: serde_json::Value = match serde_json::from_str(&config_str) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to parse config: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(ref profile) = args.profile {
+        if let Err(e) = apply_profile(&mut config_value, config_path, profile) {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        info!("Using config profile: {}", profile);
+    }
+
+    let config: Config = match serde_json::from_value(config_value) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Failed to parse config: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    info!("Operator ID: {}", config.client_id);
+    info!("Nodes configured: {}", config.nodes.len());
+    for node in &config.nodes {
+        info!("  - {} | {} | {} | capacity: {}", 
+            node.alias, node.inference_uri, node.api_mode, node.capacity);
+        if node.capacity == 0 {
+            warn!("    {} has capacity 0: it registers with the server but rejects every request", node.alias);
+        }
+        if !node.fallback_model.is_empty() && !config.model_fallback.enabled {
+            warn!("    {} has fallbackModel entries, but modelFallback.enabled is false: they are ignored", node.alias);
+        }
+        if let Some(max) = node.connection_pool.max_connections.filter(|&max| max < node.capacity as usize) {
+            warn!("    {} allows {} backend connection(s) for capacity {}: extra requests wait for a free connection", node.alias, max, node.capacity);
+        }
+    }
+    
+    if let Err(e) = config.validate() {
+        error!("Invalid config: {}", e);
+        std::process::exit(1);
+    }
+
+    let fingerprint = CONFIG_FINGERPRINT.get_or_init(|| config_fingerprint(&config));
+    info!("Config fingerprint: {} (v{})", fingerprint, env!("CARGO_PKG_VERSION"));
+
+    let tls = match config.tls.as_ref().map(build_tls_config).transpose() {
+        Ok(tls) => tls.map(Arc::new),
+        Err(e) => {
+            error!("Invalid TLS settings: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Certificate pinning reads the peer certificate from rustls, so the server
+    // connection uses it even when no tls restrictions are configured.
+    let server_tls = match (&tls, &config.server_pinning) {
+        (None, Some(pinning)) if !pinning.cert_fingerprints.is_empty() => match build_tls_config(&TlsConfig::default()) {
+            Ok(tls) => Some(Arc::new(tls)),
+            Err(e) => {
+                error!("Invalid TLS settings: {}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => tls.clone(),
+    };
+
+    if let Some(command) = args.command {
+        let http = match build_http_client(&config, tls.as_deref()) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Failed to build HTTP client: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let result = match command {
+            Command::Bench { node, model, concurrency, duration, prompt_size } => {
+                let opts = BenchOptions { node, model, concurrency, duration: Duration::from_secs(duration), prompt_size };
+                run_bench(&config, &http, opts).await.map_err(|e| format!("Benchmark failed: {}", e))
+            }
+            Command::Replay { log, node, model, since, until } => {
+                let opts = ReplayOptions { log, node, model, since, until };
+                run_replay(&config, &http, opts).await.map_err(|e| format!("Replay failed: {}", e))
+            }
+        };
+        if let Err(e) = result {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // With the `termination` feature this also catches SIGTERM and SIGHUP,
+    // which is what docker stop, systemd and Kubernetes send.
+    ctrlc::set_handler(move || {
+        info!("Shutdown signal received");
+        RUNNING.store(false, Ordering::SeqCst);
+    })
+    .expect("Error setting signal handler");
+
+    info!("Concurrent inference threads: {}", args.threads);
+
+    // Runs before anything is started, so --fail-fast has nothing to clean up.
+    let probe_url = config.server_url.clone();
+    let probe_tls = server_tls.clone();
+    match tokio::task::spawn_blocking(move || probe_server(&probe_url, probe_tls)).await {
+        Ok(Ok(())) => info!("[STARTUP] PIN server {} is reachable", config.server_url),
+        Ok(Err(ProbeError::Misconfigured(e))) => {
+            error!("=====================================");
+            error!("[STARTUP] serverUrl {} looks misconfigured: {}", config.server_url, e);
+            error!("=====================================");
+            if args.fail_fast {
+                error!("[STARTUP] Exiting (--fail-fast)");
+                std::process::exit(1);
+            }
+            warn!("[STARTUP] Retrying anyway - start with --fail-fast to exit instead");
+        }
+        Ok(Err(ProbeError::Unreachable(e))) => warn!("[STARTUP] PIN server {} is not reachable yet: {} - will keep retrying", config.server_url, e),
+        Err(e) => warn!("[STARTUP] Could not check PIN server {}: {}", config.server_url, e),
+    }
+
+    let mut daemon = DaemonState::new(&config);
+    if let Some(ref path) = config.audit_log {
+        match AuditLog::open(path) {
+            Ok(audit) => {
+                info!("Audit log: {}", path.display());
+                daemon.audit = Some(audit);
+            }
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(ref trace_path) = args.profile_requests {
+        match RequestProfiler::new(trace_path.as_deref()) {
+            Ok(profiler) => {
+                info!("Request profiling enabled{}", trace_path.as_ref().map(|p| format!(", trace: {}", p.display())).unwrap_or_default());
+                daemon.profiler = Some(profiler);
+            }
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let http = match build_http_client(&config, tls.as_deref()) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Failed to build HTTP client: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let admin_listener = match config.admin_port {
+        Some(port) => {
+            let addr = format!("{}:{}", config.admin_bind, port);
+            match tokio::net::TcpListener::bind(&addr).await {
+                Ok(listener) => {
+                    info!("[ADMIN] Listening on http://{}", addr);
+                    Some(listener)
+                }
+                Err(e) => {
+                    error!("[ADMIN] Failed to bind {}: {}", addr, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    // Opened last among the fallible steps: an exit after this point would
+    // leave the run marked as started and be reported as a crash next time.
+    if let Some(ref path) = config.state_file {
+        match StateFile::open(path, daemon.started_at.to_rfc3339()) {
+            Ok((state_file, restart)) => {
+                match restart.reason.as_str() {
+                    "crash" => warn!("[RESTART] The previous run (started {}) did not shut down cleanly - {} unclean exit(s) in a row",
+                        restart.previous_started_at.as_deref().unwrap_or("unknown"), restart.consecutive_crashes),
+                    "graceful_restart" => info!("[RESTART] The previous run shut down cleanly at {}", restart.previous_stopped_at.as_deref().unwrap_or("unknown")),
+                    _ => info!("[RESTART] First start with state file {}", path.display()),
+                }
+                daemon.state_file = Some(state_file);
+                daemon.restart = restart;
+            }
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let daemon = Arc::new(daemon);
 
-    let request = OllamaChatRequest {
-        model: model.to_string(),
-        messages,
-        stream: Some(false),
-    };
+    if let Some(listener) = admin_listener {
+        tokio::spawn(run_admin_server(listener, daemon.clone()));
+    }
 
-    let response = client
-        .post(&url)
-        .json(&request)
-        .timeout(Duration::from_secs(120))
-        .send()
-        .await
-        .map_err(|e| format!("Ollama request failed: {}", e))?;
+    if let Some(ref check) = config.backend_cert_check {
+        tokio::spawn(monitor_backend_certs(config.nodes.clone(), check.clone(), daemon.clone()));
+    }
 
-    if !response.status().is_success() {
-        let status = response.status();
-        let body = response.text().await.unwrap_or_default();
-        return Err(format!("Ollama error {}: {}", status, body));
+    if let Some(ref limits) = config.load_shedding {
+        tokio::spawn(monitor_system_load(limits.clone(), daemon.clone()));
     }
 
-    let ollama_resp: OllamaChatResponse = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse Ollama response: {}", e))?;
+    for node in config.nodes.iter().filter(|n| n.discovery_interval_secs.is_some()) {
+        tokio::spawn(discover_models(node.clone(), http.node(&node.alias).http.clone(), daemon.clone()));
+    }
 
-    let prompt_tokens = ollama_resp.prompt_eval_count.unwrap_or(0);
-    let completion_tokens = ollama_resp.eval_count.unwrap_or(0);
+    let supervisors: Vec<_> = config
+        .nodes
+        .iter()
+        .filter(|n| n.backend_command.is_some())
+        .map(|n| tokio::spawn(supervise_backend(n.clone(), http.node(&n.alias).http.clone(), daemon.clone())))
+        .collect();
 
-    Ok(OpenAIResponse {
-        model: ollama_resp.model,
-        choices: vec![OpenAIChoice {
-            index: 0,
-            message: ollama_resp.message,
-            finish_reason: Some("stop".to_string()),
-        }],
-        usage: Some(OpenAIUsage {
-            prompt_tokens,
-            completion_tokens,
-            total_tokens: prompt_tokens + completion_tokens,
-        }),
-    })
+    if config.startup_delay_secs > 0 {
+        info!("[STARTUP] Waiting {}s before connecting", config.startup_delay_secs);
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(config.startup_delay_secs)) => {}
+            _ = wait_for_shutdown() => {}
+        }
+    }
+    if let Some(ref wait) = config.wait_for_backend {
+        if RUNNING.load(Ordering::SeqCst) {
+            wait_for_backends(&config, &http, wait).await;
+        }
+    }
+
+    let mut stopped_by_server = false;
+    while RUNNING.load(Ordering::SeqCst) {
+        let result = run_connection(&config, args.threads, &daemon, &http, server_tls.as_ref()).await;
+        daemon.set_connection(ConnectionState::Disconnected);
+        let summary = daemon.end_connection(result.as_ref().err().map(|e| e.to_string()));
+        if let Some(ref c) = summary {
+            info!("[CONNECTION] Ended after {}s: {} requests ({} errors), {} tokens, {} bytes sent, {} bytes received - {}",
+                c.duration_secs, c.requests, c.errors, c.tokens, c.bytes_sent, c.bytes_received, c.reason);
+        }
+        if let Err(e) = result {
+            error!("Connection error: {}", e);
+        }
+        if !RUNNING.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let close_code = summary.and_then(|c| c.close_code);
+        let reconnect_delay = match close_code.zip(config.close_codes.as_ref()).and_then(|(code, cc)| Some((code, cc.action(code)?))) {
+            Some((code, CloseAction::Stop)) => {
+                error!("[CONNECTION] Server closed with code {}, which is configured as terminal - shutting down", code);
+                stopped_by_server = true;
+                RUNNING.store(false, Ordering::SeqCst);
+                break;
+            }
+            Some((code, CloseAction::Reconnect(secs))) => {
+                info!("[CONNECTION] Server closed with code {} - reconnecting in {}s", code, secs);
+                secs
+            }
+            None => {
+                info!("Reconnecting in {}s...", config.reconnect_delay_secs);
+                config.reconnect_delay_secs
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(reconnect_delay)) => {}
+            _ = wait_for_shutdown() => {}
+        }
+    }
+
+    for supervisor in supervisors {
+        let _ = supervisor.await;
+    }
+
+    if let Some(ref state_file) = daemon.state_file {
+        state_file.mark_stopped();
+    }
+    info!("Shutdown complete. {}", daemon.summary());
+    if stopped_by_server {
+        std::process::exit(CLOSED_BY_SERVER_EXIT_CODE);
+    }
 }
 
-async fn chat_completion_openai(
-    base_url: &str,
-    model: &str,
-    messages: Vec<ChatMessage>,
-) -> Result<OpenAIResponse, String> {
-    let client = reqwest::Client::new();
-    let url = format!("{}/v1/chat/completions", base_url.trim_end_matches('/'));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-    let request = OpenAIChatRequest {
-        model: model.to_string(),
-        messages,
-        stream: Some(false),
-    };
+    /// A request the mock backend received.
+    struct Recorded {
+        head: String,
+        body: Vec<u8>,
+    }
 
-    let response = client
-        .post(&url)
-        .json(&request)
-        .timeout(Duration::from_secs(120))
-        .send()
-        .await
-        .map_err(|e| format!("OpenAI request failed: {}", e))?;
+    impl Recorded {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.head.lines().find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                key.eq_ignore_ascii_case(name).then(|| value.trim())
+            })
+        }
 
-    if !response.status().is_success() {
-        let status = response.status();
-        let body = response.text().await.unwrap_or_default();
-        return Err(format!("OpenAI error {}: {}", status, body));
+        fn json(&self) -> serde_json::Value {
+            serde_json::from_slice(&self.body).unwrap()
+        }
     }
 
-    response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse OpenAI response: {}", e))
-}
+    /// What the mock backend answers: a status, headers, and a body sent in
+    /// parts, each after its delay. The connection closes after the body.
+    struct Reply {
+        status: u16,
+        headers: Vec<(&'static str, String)>,
+        parts: Vec<(Duration, Vec<u8>)>,
+    }
 
-async fn chat_completion(
-    base_url: &str,
-    model: &str,
-    messages: Vec<ChatMessage>,
-    api_mode: &str,
-) -> Result<OpenAIResponse, String> {
-    match api_mode {
-        "openai" => chat_completion_openai(base_url, model, messages).await,
-        _ => chat_completion_ollama(base_url, model, messages).await,
+    impl Reply {
+        fn json(value: serde_json::Value) -> Self {
+            Reply { status: 200, headers: vec![("Content-Type", "application/json".to_string())], parts: vec![(Duration::ZERO, value.to_string().into_bytes())] }
+        }
     }
-}
 
-async fn run_interview_prompt(
-    base_url: &str,
-    model: &str,
-    prompt: &InterviewPrompt,
-    api_mode: &str,
-) -> PromptResult {
-    let start = std::time::Instant::now();
-    
-    let messages = vec![ChatMessage {
-        role: "user".to_string(),
-        content: prompt.prompt.clone(),
-    }];
-    
-    let result = chat_completion(base_url, model, messages, api_mode).await;
-    let total_ms = start.elapsed().as_millis() as u32;
-    
-    match result {
-        Ok(resp) => {
-            let content = resp.choices.first()
-                .map(|c| c.message.content.clone())
-                .unwrap_or_default();
-            let tokens = resp.usage.as_ref()
-                .map(|u| u.completion_tokens)
-                .unwrap_or(0);
-            
-            PromptResult {
-                prompt_id: prompt.id.clone(),
-                response: content,
-                ttft_ms: total_ms / 2,  // Approximate TTFT
-                total_ms,
-                tokens_generated: tokens,
-                error: None,
+    type Requests = Arc<std::sync::Mutex<Vec<Recorded>>>;
+
+    /// Serves HTTP/1.1 on a local port until the test ends, answering every
+    /// request with `reply` and recording what it received.
+    async fn mock_backend(reply: impl Fn(&Recorded) -> Reply + Send + Sync + 'static) -> (String, Requests) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests: Requests = Arc::default();
+        let recorded = requests.clone();
+        let reply = Arc::new(reply);
+        tokio::spawn(async move {
+            while let Ok((mut sock, _)) = listener.accept().await {
+                let recorded = recorded.clone();
+                let reply = reply.clone();
+                tokio::spawn(async move {
+                    let mut data = Vec::new();
+                    let mut buf = [0u8; 4096];
+                    let end = loop {
+                        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                            break end;
+                        }
+                        match sock.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => data.extend_from_slice(&buf[..n]),
+                        }
+                    };
+                    let head = String::from_utf8_lossy(&data[..end]).to_string();
+                    let mut request = Recorded { head, body: data[end + 4..].to_vec() };
+                    let length: usize = request.header("content-length").and_then(|v| v.parse().ok()).unwrap_or(0);
+                    while request.body.len() < length {
+                        match sock.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.body.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let reply = reply(&request);
+                    recorded.lock().unwrap().push(request);
+                    let mut head = format!("HTTP/1.1 {} Mock\r\nConnection: close\r\n", reply.status);
+                    for (name, value) in &reply.headers {
+                        head.push_str(&format!("{}: {}\r\n", name, value));
+                    }
+                    head.push_str("\r\n");
+                    if sock.write_all(head.as_bytes()).await.is_err() {
+                        return;
+                    }
+                    for (delay, part) in reply.parts {
+                        tokio::time::sleep(delay).await;
+                        if sock.write_all(&part).await.is_err() {
+                            return;
+                        }
+                    }
+                    let _ = sock.shutdown().await;
+                });
             }
+        });
+        (url, requests)
+    }
+
+    fn test_http() -> BackendHttp {
+        BackendHttp { client: reqwest::Client::new(), content_type: reqwest::header::HeaderValue::from_static(DEFAULT_CONTENT_TYPE) }
+    }
+
+    /// An OpenAI-style event stream of `events`, each sent `gap` after the last.
+    fn sse(events: &[serde_json::Value], gap: Duration) -> Reply {
+        let mut parts: Vec<(Duration, Vec<u8>)> = events.iter().map(|e| (gap, format!("data: {}\n\n", e).into_bytes())).collect();
+        parts.push((gap, b"data: [DONE]\n\n".to_vec()));
+        Reply { status: 200, headers: vec![("Content-Type", "text/event-stream".to_string())], parts }
+    }
+
+    fn delta(content: &str) -> serde_json::Value {
+        serde_json::json!({"model": "m", "choices": [{"delta": {"content": content}}]})
+    }
+
+    fn forwarder(request_id: &str, tx: &mpsc::Sender<Outbound>, limits: StreamConfig) -> ChunkForwarder {
+        ChunkForwarder::new(request_id.to_string(), None, tx.clone(), limits)
+    }
+
+    /// The `(request_id, index, content)` of every chunk sent so far.
+    fn drain_chunks(rx: &mut mpsc::Receiver<Outbound>) -> Vec<(String, u64, String)> {
+        let mut chunks = Vec::new();
+        while let Ok(out) = rx.try_recv() {
+            let message: serde_json::Value = serde_json::from_str(&out.json).unwrap();
+            assert_eq!(message["type"], "INFERENCE_CHUNK");
+            let result = &message["result"];
+            chunks.push((message["request_id"].as_str().unwrap().to_string(), result["index"].as_u64().unwrap(), result["content"].as_str().unwrap().to_string()));
         }
-        Err(e) => PromptResult {
-            prompt_id: prompt.id.clone(),
-            response: String::new(),
-            ttft_ms: 0,
-            total_ms,
-            tokens_generated: 0,
-            error: Some(e),
-        },
+        chunks
     }
-}
 
-async fn execute_interview(
-    base_url: &str,
-    interview_id: &str,
-    model: &str,
-    prompts: Vec<InterviewPrompt>,
-    api_mode: &str,
-) -> InterviewResult {
-    info!("[INTERVIEW] Starting interview {} with {} prompts on model {} ({})", 
-        interview_id, prompts.len(), model, api_mode);
-    
-    let mut results = Vec::new();
-    
-    for (i, prompt) in prompts.iter().enumerate() {
-        info!("[INTERVIEW] Running prompt {}/{}: {}", i + 1, prompts.len(), prompt.id);
-        let result = run_interview_prompt(base_url, model, prompt, api_mode).await;
-        
-        if result.error.is_some() {
-            warn!("[INTERVIEW] Prompt {} failed: {:?}", prompt.id, result.error);
-        } else {
-            info!("[INTERVIEW] Prompt {} completed: {} tokens in {}ms", 
-                prompt.id, result.tokens_generated, result.total_ms);
+    fn usage_stream() -> Vec<serde_json::Value> {
+        vec![
+            delta("Hel"),
+            delta("lo"),
+            serde_json::json!({"model": "m", "choices": [{"delta": {}, "finish_reason": "stop"}]}),
+            serde_json::json!({"model": "m", "choices": [], "usage": {"prompt_tokens": 4, "completion_tokens": 2, "total_tokens": 6}}),
+        ]
+    }
+
+    #[tokio::test]
+    async fn openai_stream_reads_the_usage_chunk() {
+        let (url, requests) = mock_backend(|_| sse(&usage_stream(), Duration::ZERO)).await;
+        let (tx, mut rx) = mpsc::channel(16);
+        let mut forwarder = forwarder("r1", &tx, StreamConfig::default());
+        let response = chat_completion_stream(&test_http(), &url, "m", vec![message("user", "hi")], &GenerationOptions::default(), "openai", &mut forwarder).await.unwrap();
+
+        assert_eq!(response.choices[0].message.content, "Hello");
+        assert_eq!(response.choices[0].finish_reason.as_deref(), Some("stop"));
+        let usage = response.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens, usage.total_tokens), (4, 2, 6));
+        assert_eq!(requests.lock().unwrap()[0].json()["stream_options"]["include_usage"], true);
+        assert_eq!(drain_chunks(&mut rx).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn openai_stream_leaves_out_stream_options_when_disabled() {
+        // Like a backend that rejects the field.
+        let (url, requests) = mock_backend(|request| {
+            if request.json().get("stream_options").is_some() {
+                Reply { status: 400, headers: Vec::new(), parts: vec![(Duration::ZERO, b"unknown field stream_options".to_vec())] }
+            } else {
+                sse(&usage_stream()[..3], Duration::ZERO)
+            }
+        })
+        .await;
+        let (tx, _rx) = mpsc::channel(16);
+        let limits = StreamConfig { include_usage: false, ..StreamConfig::default() };
+        let response = chat_completion_stream(&test_http(), &url, "m", vec![message("user", "hi")], &GenerationOptions::default(), "openai", &mut forwarder("r1", &tx, limits)).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "Hello");
+        assert!(response.usage.is_none());
+        assert!(requests.lock().unwrap()[0].json().get("stream_options").is_none());
+
+        let rejected = chat_completion_stream(&test_http(), &url, "m", vec![message("user", "hi")], &GenerationOptions::default(), "openai", &mut forwarder("r2", &tx, StreamConfig::default())).await;
+        assert!(rejected.unwrap_err().contains("400"));
+    }
+
+    #[tokio::test]
+    async fn stream_waits_for_a_throttled_sink() {
+        let deltas: Vec<_> = (0..20).map(|i| delta(&format!("t{} ", i))).collect();
+        let (url, _) = mock_backend(move |_| sse(&deltas, Duration::ZERO)).await;
+        let (tx, mut rx) = mpsc::channel(2);
+        let mut forwarder = forwarder("r1", &tx, StreamConfig::default());
+        let stream = tokio::spawn(async move {
+            chat_completion_stream(&test_http(), &url, "m", vec![message("user", "hi")], &GenerationOptions::default(), "openai", &mut forwarder).await
+        });
+
+        // Nobody is reading: the stream fills the channel and then waits.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!stream.is_finished());
+        assert_eq!(rx.len(), 2);
+
+        let mut indexes = Vec::new();
+        while indexes.len() < 20 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            let out = rx.recv().await.unwrap();
+            let chunk: serde_json::Value = serde_json::from_str(&out.json).unwrap();
+            indexes.push(chunk["result"]["index"].as_u64().unwrap());
+            assert!(rx.len() <= 2);
         }
-        
-        results.push(result);
+        assert_eq!(indexes, (0..20).collect::<Vec<u64>>());
+        let response = stream.await.unwrap().unwrap();
+        assert!(response.choices[0].message.content.starts_with("t0 t1 "));
+    }
+
+    #[tokio::test]
+    async fn interleaved_streams_keep_their_own_chunk_order() {
+        let words = |prefix: &str| -> Vec<serde_json::Value> { (0..5).map(|i| delta(&format!("{}{} ", prefix, i))).collect() };
+        let (a_words, b_words) = (words("a"), words("b"));
+        let (url_a, _) = mock_backend(move |_| sse(&a_words, Duration::from_millis(3))).await;
+        let (url_b, _) = mock_backend(move |_| sse(&b_words, Duration::from_millis(5))).await;
+        let (tx, mut rx) = mpsc::channel(64);
+        let (mut fa, mut fb) = (forwarder("ra", &tx, StreamConfig::default()), forwarder("rb", &tx, StreamConfig::default()));
+        let (http, options) = (test_http(), GenerationOptions::default());
+        let (a, b) = tokio::join!(
+            chat_completion_stream(&http, &url_a, "m", vec![message("user", "a")], &options, "openai", &mut fa),
+            chat_completion_stream(&http, &url_b, "m", vec![message("user", "b")], &options, "openai", &mut fb),
+        );
+        assert_eq!(a.unwrap().choices[0].message.content, "a0 a1 a2 a3 a4 ");
+        assert_eq!(b.unwrap().choices[0].message.content, "b0 b1 b2 b3 b4 ");
+
+        let chunks = drain_chunks(&mut rx);
+        assert_eq!(chunks.len(), 10);
+        assert!(chunks[..5].iter().any(|c| c.0 == "rb"), "chunks should interleave: {:?}", chunks);
+        for (id, prefix, forwarder) in [("ra", "a", &fa), ("rb", "b", &fb)] {
+            let own: Vec<_> = chunks.iter().filter(|c| c.0 == id).collect();
+            let indexes: Vec<u64> = own.iter().map(|c| c.1).collect();
+            assert_eq!(indexes, [0, 1, 2, 3, 4]);
+            assert!(own.iter().enumerate().all(|(i, c)| c.2 == format!("{}{} ", prefix, i)));
+            // `chunks` in the final response is taken from the forwarder's index.
+            assert_eq!(forwarder.index as usize, own.len());
+        }
+    }
+
+    fn openai_completion(content: &str) -> serde_json::Value {
+        serde_json::json!({
+            "model": "m",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": content}, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 3, "completion_tokens": 2, "total_tokens": 5},
+        })
+    }
+
+    fn ollama_completion(content: &str) -> serde_json::Value {
+        serde_json::json!({"model": "m", "message": {"role": "assistant", "content": content}, "done": true, "prompt_eval_count": 3, "eval_count": 2})
+    }
+
+    fn interview_prompt(max_tokens: u32) -> InterviewPrompt {
+        InterviewPrompt { id: "p1".to_string(), prompt: "Count to three.".to_string(), max_tokens }
+    }
+
+    #[tokio::test]
+    async fn interview_max_tokens_reach_the_backend() {
+        let (url, requests) = mock_backend(|_| Reply::json(openai_completion("1 2 3"))).await;
+        let result = run_interview_prompt(&test_http(), &url, "m", &interview_prompt(7), "openai").await;
+        assert_eq!(result.error, None);
+        assert_eq!(requests.lock().unwrap()[0].json()["max_tokens"], 7);
+
+        let (url, requests) = mock_backend(|_| Reply::json(ollama_completion("1 2 3"))).await;
+        let result = run_interview_prompt(&test_http(), &url, "m", &interview_prompt(7), "ollama").await;
+        assert_eq!(result.error, None);
+        assert_eq!(requests.lock().unwrap()[0].json()["options"]["num_predict"], 7);
+    }
+
+    #[tokio::test]
+    async fn interview_without_a_limit_sends_none() {
+        let (url, requests) = mock_backend(|_| Reply::json(openai_completion("1 2 3"))).await;
+        run_interview_prompt(&test_http(), &url, "m", &interview_prompt(0), "openai").await;
+        assert!(requests.lock().unwrap()[0].json().get("max_tokens").is_none());
+    }
+
+    #[tokio::test]
+    async fn content_type_is_sent_on_posts_only() {
+        // Like a strict backend that rejects bodies without a charset.
+        let (url, requests) = mock_backend(|request| match request.header("content-type") {
+            Some(value) if value.contains("charset") => Reply::json(openai_completion("ok")),
+            Some(_) => Reply { status: 415, headers: Vec::new(), parts: vec![(Duration::ZERO, b"charset required".to_vec())] },
+            None => Reply::json(serde_json::json!({"data": [{"id": "m"}]})),
+        })
+        .await;
+        let mut node = test_node("a", &url);
+        node["contentType"] = "application/json; charset=utf-8".into();
+        let config = test_config(serde_json::json!([node]));
+        let clients = build_http_client(&config, None).unwrap();
+        let http = &clients.node("a").http;
+
+        let models = get_models(http, &url, "openai").await.unwrap();
+        assert_eq!(models, ["m"]);
+        let response = chat_completion(http, &url, "m", vec![message("user", "hi")], &GenerationOptions::default(), "openai").await.unwrap();
+        assert_eq!(response.choices[0].message.content, "ok");
+
+        {
+            let requests = requests.lock().unwrap();
+            assert_eq!(requests[0].header("content-type"), None);
+            assert_eq!(requests[1].header("content-type"), Some("application/json; charset=utf-8"));
+        }
+
+        let plain = chat_completion(&test_http(), &url, "m", vec![message("user", "hi")], &GenerationOptions::default(), "openai").await;
+        assert!(plain.unwrap_err().contains("415"));
+    }
+
+    #[test]
+    fn interview_max_tokens_caps_prompts() {
+        let mut prompts = vec![interview_prompt(0), interview_prompt(50), interview_prompt(500)];
+        cap_interview_prompts(&mut prompts, 100);
+        let limits: Vec<u32> = prompts.iter().map(|p| p.max_tokens).collect();
+        assert_eq!(limits, [100, 50, 100]);
+    }
+
+    #[test]
+    fn configured_alias_accepts_only_unambiguous_prefixes() {
+        let aliases = ["gpu".to_string(), "gpu2".to_string(), "cpu".to_string()];
+        assert_eq!(configured_alias(aliases.iter(), "gpu2").as_deref(), Some("gpu2"));
+        assert_eq!(configured_alias(aliases.iter(), "gpu").as_deref(), Some("gpu"));
+        assert_eq!(configured_alias(aliases.iter(), "cpu-1").as_deref(), Some("cpu"));
+        assert_eq!(configured_alias(aliases.iter(), "gpu2-1"), None);
+        assert_eq!(configured_alias(aliases.iter(), "tpu"), None);
+    }
+
+    fn response_with(contents: &[&str]) -> OpenAIResponse {
+        let choices: Vec<_> = contents
+            .iter()
+            .enumerate()
+            .map(|(i, c)| serde_json::json!({"index": i, "message": {"role": "assistant", "content": c}, "finish_reason": "stop"}))
+            .collect();
+        serde_json::from_value(serde_json::json!({"choices": choices, "model": "m"})).unwrap()
+    }
+
+    #[test]
+    fn empty_response_checks_every_choice() {
+        assert!(response_with(&[]).is_empty());
+        assert!(response_with(&[" ", "\n"]).is_empty());
+        assert!(!response_with(&["", "answer"]).is_empty());
+        assert!(!response_with(&["answer"]).is_empty());
+    }
+
+    fn test_config(nodes: serde_json::Value) -> Config {
+        serde_json::from_value(serde_json::json!({
+            "clientId": "op_test",
+            "apiSecret": "secret",
+            "serverUrl": "ws://127.0.0.1:1/ws",
+            "nodes": nodes,
+        }))
+        .unwrap()
+    }
+
+    fn test_node(alias: &str, uri: &str) -> serde_json::Value {
+        serde_json::json!({"alias": alias, "inferenceUri": uri, "apiMode": "openai", "region": "us-east", "capacity": 2})
+    }
+
+    fn advertise(daemon: &DaemonState, alias: &str, models: &[&str]) {
+        daemon.nodes.lock().unwrap().get_mut(alias).unwrap().models = models.iter().map(|m| m.to_string()).collect();
+    }
+
+    #[test]
+    fn select_node_skips_nodes_with_capacity_zero() {
+        let mut zero = test_node("zero", "http://127.0.0.1:1");
+        zero["capacity"] = 0.into();
+        let config = test_config(serde_json::json!([zero, test_node("one", "http://127.0.0.1:2")]));
+        let daemon = DaemonState::new(&config);
+        advertise(&daemon, "zero", &["m", "only-zero"]);
+        advertise(&daemon, "one", &["m"]);
+        assert_eq!(select_node(&config, &daemon, "m").alias, "one");
+        assert_eq!(select_node(&config, &daemon, "only-zero").alias, "zero");
+        assert_eq!(select_node(&config, &daemon, "unknown").alias, "zero");
+    }
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage { role: role.to_string(), content: content.to_string() }
+    }
+
+    #[test]
+    fn system_prompt_merges_into_the_client_system_message() {
+        let mut messages = vec![message("system", "Answer in French."), message("user", "hi")];
+        add_system_prompt(&mut messages, "You are llama.".to_string());
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content, "You are llama.\n\nAnswer in French.");
+
+        let mut messages = vec![message("user", "hi")];
+        add_system_prompt(&mut messages, "You are llama.".to_string());
+        assert_eq!(messages.len(), 2);
+        assert_eq!((messages[0].role.as_str(), messages[0].content.as_str()), ("system", "You are llama."));
+    }
+
+    #[test]
+    fn repeated_auth_success_keeps_the_first_operator() {
+        assert_eq!(auth_outcome(None, "op_a"), AuthOutcome::First);
+        assert_eq!(auth_outcome(Some("op_a"), "op_a"), AuthOutcome::Repeat);
+        assert_eq!(auth_outcome(Some("op_a"), "op_b"), AuthOutcome::OtherOperator { kept: "op_a".to_string() });
+    }
+
+    #[test]
+    fn bounded_cache_evicts_the_least_recently_used() {
+        let now = std::time::Instant::now();
+        let ttl = Duration::from_secs(60);
+        let mut cache = BoundedCache::default();
+        cache.insert("a", 1, ttl, 2, now);
+        cache.insert("b", 2, ttl, 2, now);
+        assert_eq!(cache.get("a", now), Some(&1));
+        cache.insert("c", 3, ttl, 2, now);
+        assert_eq!(cache.get("b", now), None);
+        assert_eq!(cache.get("a", now), Some(&1));
+        assert_eq!(cache.get("c", now), Some(&3));
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.evicted, stats.expired), (2, 1, 0));
+
+        // Replacing a key doesn't count as an eviction.
+        cache.insert("c", 4, ttl, 2, now);
+        assert_eq!(cache.get("c", now), Some(&4));
+        assert_eq!(cache.stats().evicted, 1);
+    }
+
+    #[test]
+    fn bounded_cache_drops_expired_entries_when_touched() {
+        let now = std::time::Instant::now();
+        let mut cache = BoundedCache::default();
+        cache.insert("short", 1, Duration::from_secs(10), 10, now);
+        cache.insert("long", 2, Duration::from_secs(100), 10, now);
+        assert_eq!(cache.get("short", now + Duration::from_secs(9)), Some(&1));
+        // Nothing is removed until the cache is used again.
+        assert_eq!(cache.stats().entries, 2);
+        assert_eq!(cache.get("long", now + Duration::from_secs(10)), Some(&2));
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.evicted, stats.expired), (1, 0, 1));
+        assert_eq!(cache.get("short", now + Duration::from_secs(10)), None);
+        // Reading doesn't extend an entry's lifetime.
+        assert_eq!(cache.get("long", now + Duration::from_secs(100)), None);
+        assert_eq!(cache.stats().expired, 2);
     }
-    
-    info!("[INTERVIEW] Interview {} complete with {} results", interview_id, results.len());
-    
-    InterviewResult {
-        msg_type: "INTERVIEW_RESULT".to_string(),
-        interview_id: interview_id.to_string(),
-        model: model.to_string(),
-        results,
+
+    #[test]
+    fn render_template_substitutes_known_variables() {
+        let vars = [("model", "llama"), ("node", "gpu")];
+        assert_eq!(render_template("{{model}} on {{ node }}", &vars), "llama on gpu");
+        assert_eq!(render_template("{{other}} stays", &vars), "{{other}} stays");
+        assert_eq!(render_template("{{model}}", &[("model", "{{node}}")]), "{{node}}");
     }
-}
 
-async fn run_connection(config: &Config, max_threads: usize) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    info!("Connecting to PIN server: {}", config.server_url);
-    info!("Inference threads: {}", max_threads);
+    #[test]
+    fn render_template_keeps_unclosed_braces() {
+        let vars = [("model", "llama")];
+        assert_eq!(render_template("You are {{model}}. Use {{ braces", &vars), "You are llama. Use {{ braces");
+        assert_eq!(render_template("{{", &vars), "{{");
+    }
 
-    let (ws_stream, _) = connect_async(&config.server_url).await?;
-    let (mut write, mut read) = ws_stream.split();
-    
-    let semaphore = Arc::new(Semaphore::new(max_threads));
-    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    #[test]
+    fn error_code_is_the_upper_cased_category() {
+        assert_eq!(error_code("not_accepted: node a does not serve model m").as_deref(), Some("NOT_ACCEPTED"));
+        assert_eq!(error_code("rate_limited: 10 requests per minute").as_deref(), Some("RATE_LIMITED"));
+        assert_eq!(error_code("Backend error: 500 Internal Server Error"), None);
+        assert_eq!(error_code("model not found"), None);
+        assert_eq!(error_code(": empty"), None);
+    }
 
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
-        .to_string();
+    #[test]
+    fn declined_models_are_not_advertised() {
+        let mut node = test_node("a", "http://127.0.0.1:1");
+        node["accept"] = serde_json::json!({"models": ["m1"]});
+        let config = test_config(serde_json::json!([node]));
+        let node = &config.nodes[0];
+        assert!(node.accepts_model("m1"));
+        assert!(!node.accepts_model("m2"));
 
-    let signature = compute_signature(&config.client_id, &timestamp, &config.api_secret);
+        let daemon = DaemonState::new(&config);
+        {
+            let mut nodes = daemon.nodes.lock().unwrap();
+            let state = nodes.get_mut("a").unwrap();
+            state.registered = true;
+            state.backend_models = vec!["m1".to_string(), "m2".to_string()];
+        }
+        assert_eq!(daemon.model_changes(node), Some((vec!["m1".to_string()], vec![])));
+        advertise(&daemon, "a", &["m1", "m2"]);
+        assert_eq!(daemon.model_changes(node), Some((vec![], vec!["m2".to_string()])));
 
-    let auth_msg = AuthMessage {
-        msg_type: "AUTH".to_string(),
-        client_id: config.client_id.clone(),
-        timestamp,
-        signature,
-    };
+        let payload: InferencePayload = serde_json::from_value(serde_json::json!({"model": "m2", "messages": []})).unwrap();
+        let error = check_acceptance(node, &payload).unwrap_err();
+        assert_eq!(ClientMessage::inference_error("r".to_string(), error, None).code.as_deref(), Some("NOT_ACCEPTED"));
+    }
 
-    write
-        .send(Message::Text(serde_json::to_string(&auth_msg)?))
-        .await?;
-    info!("Sent AUTH message for {}", config.client_id);
+    #[test]
+    fn prompt_template_renders_messages_into_one_prompt() {
+        let template = PromptTemplate {
+            template: "<s>{{system}}</s>{{messages}}[{{model}}@{{node}}]".to_string(),
+            message: default_prompt_message_format(),
+        };
+        let messages = [message("system", "Be brief."), message("user", "hi"), message("system", "No emoji."), message("assistant", "{{model}}")];
+        let context = [("model", "llama".to_string()), ("node", "gpu".to_string())];
+        let rendered = apply_prompt_template(&template, &messages, &context);
+        assert_eq!(rendered.len(), 1);
+        assert_eq!(rendered[0].role, "user");
+        // Message contents are substituted as-is, not expanded again.
+        assert_eq!(rendered[0].content, "<s>Be brief.\n\nNo emoji.</s>user: hi\nassistant: {{model}}\n[llama@gpu]");
+    }
 
-    let mut node_endpoints: std::collections::HashMap<String, (String, String)> = std::collections::HashMap::new();
-    for node in &config.nodes {
-        node_endpoints.insert(node.alias.clone(), (node.inference_uri.clone(), node.api_mode.clone()));
+    #[test]
+    fn validate_rejects_unknown_prompt_template_variables() {
+        let with_templates = |templates: serde_json::Value| {
+            let mut node = test_node("a", "http://127.0.0.1:1");
+            node["promptTemplates"] = templates;
+            test_config(serde_json::json!([node])).validate()
+        };
+        assert!(with_templates(serde_json::json!({"m": {"template": "{{system}} {{messages}} {{date}}"}})).is_ok());
+        let error = with_templates(serde_json::json!({"m": {"template": "{{messages}} {{user}}"}})).unwrap_err();
+        assert!(error.contains("unknown variable {{user}} in promptTemplates.m.template"), "{}", error);
+        let error = with_templates(serde_json::json!({"m": {"template": "{{messages}}", "message": "{{model}}: {{content}}"}})).unwrap_err();
+        assert!(error.contains("unknown variable {{model}} in promptTemplates.m.message"), "{}", error);
     }
 
-    while RUNNING.load(Ordering::SeqCst) {
-        tokio::select! {
-            response_json = rx.recv() => {
-                if let Some(json) = response_json {
-                    if let Err(e) = write.send(Message::Text(json)).await {
-                        error!("Failed to send response: {}", e);
-                    }
-                }
+    #[tokio::test]
+    async fn backend_client_decodes_gzip_responses() {
+        // Compresses only for clients that ask, like most servers.
+        let (url, requests) = mock_backend(|request| {
+            let body = openai_completion("Hello").to_string().into_bytes();
+            if !request.header("accept-encoding").is_some_and(|v| v.contains("gzip")) {
+                return Reply::json(openai_completion("Hello"));
             }
-            msg = read.next() => {
-                match msg {
-                    Some(Ok(Message::Text(text))) => {
-                        match serde_json::from_str::<ServerMessage>(&text) {
-                            Ok(server_msg) => {
-                                match server_msg {
-                                    ServerMessage::AUTH_SUCCESS { operator_id, node_id: _, message } => {
-                                        info!("Authenticated! Operator: {}", operator_id);
-                                        info!("{}", message);
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            std::io::Write::write_all(&mut encoder, &body).unwrap();
+            let headers = vec![("Content-Type", "application/json".to_string()), ("Content-Encoding", "gzip".to_string())];
+            Reply { status: 200, headers, parts: vec![(Duration::ZERO, encoder.finish().unwrap())] }
+        })
+        .await;
+        let mut config = test_config(serde_json::json!([test_node("a", &url)]));
+        for decompression in [true, false] {
+            config.backend_decompression = decompression;
+            let clients = build_http_client(&config, None).unwrap();
+            let response = chat_completion(&clients.node("a").http, &url, "m", vec![message("user", "hi")], &GenerationOptions::default(), "openai").await.unwrap();
+            assert_eq!(response.choices[0].message.content, "Hello");
+            let asked = requests.lock().unwrap().last().unwrap().header("accept-encoding").is_some_and(|v| v.contains("gzip"));
+            assert_eq!(asked, decompression);
+        }
+    }
 
-                                        // Update wallet address if configured
-                                        if let Some(ref payout_addr) = config.payout_address {
-                                            if !payout_addr.is_empty() {
-                                                info!("Updating payout wallet: {}...{}", &payout_addr[..6.min(payout_addr.len())], &payout_addr[payout_addr.len().saturating_sub(4)..]);
-                                                let wallet_msg = UpdateWalletMessage {
-                                                    msg_type: "UPDATE_WALLET".to_string(),
-                                                    payout_address: payout_addr.clone(),
-                                                };
-                                                if let Err(e) = write.send(Message::Text(serde_json::to_string(&wallet_msg)?)).await {
-                                                    error!("Failed to update wallet: {}", e);
-                                                }
-                                            }
-                                        }
+    #[tokio::test]
+    async fn awkward_backend_content_survives_the_round_trip() {
+        let content = "quote \" backslash \\ nul \u{0} bell \u{7} \u{2028} 😀 </script>".repeat(2000);
+        let reply = openai_completion(&content);
+        let (url, _) = mock_backend(move |_| Reply::json(reply.clone())).await;
+        let response = chat_completion(&test_http(), &url, "m", vec![message("user", "hi")], &GenerationOptions::default(), "openai").await.unwrap();
+        let metrics = Metrics::default();
+        let message = ClientMessage::inference_response("r1".to_string(), &response, None, &metrics);
+        let sent: serde_json::Value = serde_json::from_str(&serde_json::to_string(&message).unwrap()).unwrap();
+        assert_eq!(sent["type"], "INFERENCE_RESPONSE");
+        assert_eq!(sent["result"]["choices"][0]["message"]["content"], content.as_str());
+        assert_eq!(metrics.serialization_failures.load(Ordering::SeqCst), 0);
+    }
 
-                                        // Register each configured node with the server
-                                        // Each node may have its own endpoint and API mode
-                                        for node_config in &config.nodes {
-                                            info!("Registering node: {} (region: {}, capacity: {}, endpoint: {}, mode: {})", 
-                                                node_config.alias, node_config.region, node_config.capacity, 
-                                                node_config.inference_uri, node_config.api_mode);
-                                            
-                                            let models = match get_models(&node_config.inference_uri, &node_config.api_mode).await {
-                                                Ok(m) => m,
-                                                Err(e) => {
-                                                    error!("Failed to get models for {} ({}): {}", node_config.alias, node_config.api_mode, e);
-                                                    vec![]
-                                                }
-                                            };
-                                            
-                                            if models.is_empty() {
-                                                warn!("No models found for node {} - check endpoint {}", node_config.alias, node_config.inference_uri);
-                                            } else {
-                                                info!("Node {} has {} models: {:?}", node_config.alias, models.len(), models);
-                                            }
-                                            
-                                            let register_msg = RegisterNodeMessage {
-                                                msg_type: "REGISTER_NODE".to_string(),
-                                                alias: node_config.alias.clone(),
-                                                models: models.clone(),
-                                                capacity: node_config.capacity,
-                                                region: node_config.region.clone(),
-                                                price_per_thousand_tokens: node_config.price_per_thousand_tokens,
-                                                interview_model: node_config.interview_model.clone(),
-                                            };
-                                            
-                                            if let Err(e) = write.send(Message::Text(serde_json::to_string(&register_msg)?)).await {
-                                                error!("Failed to register node {}: {}", node_config.alias, e);
-                                            }
-                                        }
-                                        
-                                        info!("Registered {} node(s) with PIN network", config.nodes.len());
-                                    }
-                                    ServerMessage::REGISTER_NODE_ACK { node_id, alias, models, created, message } => {
-                                        let status = if created { "registered" } else { "updated" };
-                                        info!("[NODE] {} {} (ID: {}) with {} models", status.to_uppercase(), alias, node_id, models.len());
-                                        info!("[NODE] {}", message);
-                                    }
-                                    ServerMessage::ERROR { message } => {
-                                        error!("Server error: {}", message);
-                                        return Err(message.into());
-                                    }
-                                    ServerMessage::PING => {
-                                        let pong = ClientMessage {
-                                            msg_type: "PONG".to_string(),
-                                            request_id: None,
-                                            result: None,
-                                            error: None,
-                                            models: None,
-                                        };
-                                        let _ = write.send(Message::Text(serde_json::to_string(&pong)?)).await;
-                                    }
-                                    ServerMessage::HEARTBEAT_ACK | ServerMessage::MODEL_LIST_ACK => {}
-                                    ServerMessage::UPDATE_WALLET_ACK { success, message } => {
-                                        if success {
-                                            info!("[WALLET] {}", message);
-                                        } else {
-                                            warn!("[WALLET] Failed: {}", message);
-                                        }
-                                    }
-                                    ServerMessage::INTERVIEW_REQUEST { interview_id, node_id, model, prompts, timeout_ms: _ } => {
-                                        let node_label = node_id.as_deref().unwrap_or("operator");
-                                        info!("[INTERVIEW] Received interview for {} - model {} ({} prompts)", 
-                                            node_label, model, prompts.len());
-                                        
-                                        let (uri, mode) = match node_endpoints.get(node_label) {
-                                            Some((u, m)) => (u.clone(), m.clone()),
-                                            None => {
-                                                let first = config.nodes.first().unwrap();
-                                                (first.inference_uri.clone(), first.api_mode.clone())
-                                            }
-                                        };
-                                        
-                                        let interview_result = execute_interview(&uri, &interview_id, &model, prompts, &mode).await;
-                                        
-                                        if let Err(e) = write.send(Message::Text(serde_json::to_string(&interview_result)?)).await {
-                                            error!("[INTERVIEW] Failed to send result: {}", e);
-                                        } else {
-                                            info!("[INTERVIEW] Result sent to server for {}", node_label);
-                                        }
-                                    }
-                                    ServerMessage::INTERVIEW_COMPLETE { interview_id: _, node_id, tier, accuracy, tokens_per_sec, reason } => {
-                                        let node_label = node_id.as_deref().unwrap_or("operator");
-                                        info!("=====================================");
-                                        info!("[INTERVIEW] Quality Tier Assigned for {}!", node_label);
-                                        info!("  Tier: {}", tier.to_uppercase());
-                                        info!("  Accuracy: {:.1}%", accuracy);
-                                        info!("  Speed: {:.1} tokens/sec", tokens_per_sec);
-                                        info!("  Reason: {}", reason);
-                                        info!("=====================================");
-                                        
-                                        if tier == "failed" {
-                                            error!("Node {} failed quality check - connection will be closed", node_label);
-                                        }
-                                    }
-                                    ServerMessage::INFERENCE_REQUEST { request_id, payload } => {
-                                        let count = TOTAL_REQUESTS.fetch_add(1, Ordering::SeqCst) + 1;
-                                        
-                                        let first_node = config.nodes.first().unwrap();
-                                        let uri = first_node.inference_uri.clone();
-                                        let mode = first_node.api_mode.clone();
-                                        let model = payload.model.clone();
-                                        let messages = payload.messages;
-                                        
-                                        info!("[#{}] Inference request: {} ({}) via {} [queued]", count, request_id, model, mode);
-                                        
-                                        let sem = semaphore.clone();
-                                        let tx = tx.clone();
-                                        
-                                        tokio::spawn(async move {
-                                            let _permit = sem.acquire().await.expect("semaphore closed");
-                                            
-                                            info!("[#{}] Starting inference for {}", count, request_id);
-                                            let result = chat_completion(&uri, &model, messages, &mode).await;
-
-                                            let response = match result {
-                                                Ok(openai_resp) => {
-                                                    let usage = openai_resp.usage.as_ref();
-                                                    let prompt_tokens = usage.map(|u| u.prompt_tokens).unwrap_or(0);
-                                                    let completion_tokens = usage.map(|u| u.completion_tokens).unwrap_or(0);
-                                                    
-                                                    info!("[#{}] Completed successfully ({}+{} tokens)", count, prompt_tokens, completion_tokens);
-                                                    ClientMessage {
-                                                        msg_type: "INFERENCE_RESPONSE".to_string(),
-                                                        request_id: Some(request_id),
-                                                        result: Some(serde_json::to_value(openai_resp).unwrap()),
-                                                        error: None,
-                                                        models: None,
-                                                    }
-                                                }
-                                                Err(e) => {
-                                                    error!("[#{}] Failed: {}", count, e);
-                                                    ClientMessage {
-                                                        msg_type: "INFERENCE_ERROR".to_string(),
-                                                        request_id: Some(request_id),
-                                                        result: None,
-                                                        error: Some(e),
-                                                        models: None,
-                                                    }
-                                                }
-                                            };
+    #[test]
+    fn unencodable_result_is_answered_with_an_error() {
+        // JSON object keys must be strings.
+        let result: std::collections::HashMap<(u8, u8), u8> = [((1, 2), 3)].into();
+        let metrics = Metrics::default();
+        let message = ClientMessage::inference_response("r1".to_string(), &result, Some(serde_json::json!({"trace": "t"})), &metrics);
+        assert_eq!(message.msg_type, "INFERENCE_ERROR");
+        assert!(message.error.unwrap().starts_with("Failed to serialize response"));
+        assert_eq!(message.metadata, Some(serde_json::json!({"trace": "t"})));
+        assert_eq!(metrics.serialization_failures.load(Ordering::SeqCst), 1);
+    }
 
-                                            if let Ok(json) = serde_json::to_string(&response) {
-                                                let _ = tx.send(json);
-                                                info!("[#{}] Response queued for send", count);
-                                            }
-                                        });
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                warn!("Failed to parse server message: {} - {}", e, text);
-                            }
-                        }
-                    }
-                    Some(Ok(Message::Close(_))) => {
-                        info!("Server closed connection");
-                        break;
-                    }
-                    Some(Err(e)) => {
-                        error!("WebSocket error: {}", e);
-                        break;
-                    }
-                    None => {
-                        info!("WebSocket stream ended");
-                        break;
-                    }
-                    _ => {}
-                }
-            }
-            _ = tokio::time::sleep(Duration::from_secs(30)) => {
-                let heartbeat = ClientMessage {
-                    msg_type: "HEARTBEAT".to_string(),
-                    request_id: None,
-                    result: None,
-                    error: None,
-                    models: None,
-                };
-                if write.send(Message::Text(serde_json::to_string(&heartbeat)?)).await.is_err() {
-                    warn!("Failed to send heartbeat");
-                    break;
-                }
+    type ServerSocket = tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>;
+
+    /// Runs `run_connection` for `config` against a local WebSocket server,
+    /// returning the server side of the connection once the daemon has
+    /// sent its AUTH.
+    async fn connect_daemon(mut config: Config, daemon: Arc<DaemonState>) -> (ServerSocket, tokio::task::JoinHandle<()>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        config.server_url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let connection = tokio::spawn(async move {
+            let http = build_http_client(&config, None).unwrap();
+            let _ = run_connection(&config, 2, &daemon, &http, None).await;
+        });
+        let (sock, _) = listener.accept().await.unwrap();
+        let mut server = tokio_tungstenite::accept_async(sock).await.unwrap();
+        assert_eq!(next_message(&mut server).await["type"], "AUTH");
+        (server, connection)
+    }
+
+    async fn next_message(server: &mut ServerSocket) -> serde_json::Value {
+        loop {
+            let message = tokio::time::timeout(Duration::from_secs(5), server.next()).await.expect("no message from the daemon");
+            if let Message::Text(text) = message.unwrap().unwrap() {
+                return serde_json::from_str(&text).unwrap();
             }
         }
     }
 
-    Ok(())
-}
+    async fn send_message(server: &mut ServerSocket, message: serde_json::Value) {
+        server.send(Message::Text(message.to_string())).await.unwrap();
+    }
 
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
+    #[tokio::test]
+    async fn inference_request_before_auth_is_rejected() {
+        let config = test_config(serde_json::json!([test_node("a", "http://127.0.0.1:1")]));
+        let daemon = Arc::new(DaemonState::new(&config));
+        let (mut server, connection) = connect_daemon(config, daemon.clone()).await;
+        send_message(&mut server, serde_json::json!({
+            "type": "INFERENCE_REQUEST",
+            "request_id": "r1",
+            "payload": {"model": "m", "messages": [{"role": "user", "content": "hi"}]},
+            "metadata": {"trace": "t"},
+        }))
+        .await;
 
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(&args.log_level)),
-        )
-        .init();
+        let reply = next_message(&mut server).await;
+        assert_eq!(reply["type"], "INFERENCE_ERROR");
+        assert_eq!(reply["request_id"], "r1");
+        assert_eq!(reply["code"], "NOT_READY");
+        assert_eq!(reply["metadata"], serde_json::json!({"trace": "t"}));
+        assert_eq!(daemon.metrics.pre_auth_messages.load(Ordering::SeqCst), 1);
+        connection.abort();
+    }
 
-    println!();
-    println!("     █████╗ ██╗ █████╗ ███████╗    ██████╗ ██╗███╗   ██╗");
-    println!("    ██╔══██╗██║██╔══██╗██╔════╝    ██╔══██╗██║████╗  ██║");
-    println!("    ███████║██║███████║███████╗    ██████╔╝██║██╔██╗ ██║");
-    println!("    ██╔══██║██║██╔══██║╚════██║    ██╔═══╝ ██║██║╚██╗██║");
-    println!("    ██║  ██║██║██║  ██║███████║    ██║     ██║██║ ╚████║");
-    println!("    ╚═╝  ╚═╝╚═╝╚═╝  ╚═╝╚══════╝    ╚═╝     ╚═╝╚═╝  ╚═══╝");
-    println!();
-    println!("    PIN Client Daemon v2.1.0 - https://AiAssist.net");
-    println!();
+    #[tokio::test]
+    async fn ping_is_answered_while_an_interview_runs() {
+        let (url, requests) = mock_backend(|request| {
+            if request.head.starts_with("GET") {
+                return Reply::json(serde_json::json!({"data": [{"id": "m"}]}));
+            }
+            let mut reply = Reply::json(openai_completion("1 2 3"));
+            reply.parts[0].0 = Duration::from_secs(1);
+            reply
+        })
+        .await;
+        let config = test_config(serde_json::json!([test_node("a", &url)]));
+        let (mut server, connection) = connect_daemon(config.clone(), Arc::new(DaemonState::new(&config))).await;
+        send_message(&mut server, serde_json::json!({"type": "AUTH_SUCCESS", "operator_id": "op", "node_id": null, "message": "welcome"})).await;
+        assert_eq!(next_message(&mut server).await["type"], "REGISTER_NODE");
+        send_message(&mut server, serde_json::json!({
+            "type": "INTERVIEW_REQUEST",
+            "interview_id": "i1",
+            "node_id": "a",
+            "model": "m",
+            "prompts": [interview_prompt(16)],
+            "timeout_ms": 0,
+        }))
+        .await;
+        // Wait until the interview is waiting on the backend.
+        while !requests.lock().unwrap().iter().any(|r| r.head.starts_with("POST")) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        send_message(&mut server, serde_json::json!({"type": "PING"})).await;
 
-    let config_path = &args.config;
-    info!("Loading config from: {:?}", config_path);
+        let started = std::time::Instant::now();
+        assert_eq!(next_message(&mut server).await["type"], "PONG");
+        assert!(started.elapsed() < Duration::from_millis(500));
+        let result = next_message(&mut server).await;
+        assert_eq!((result["type"].as_str(), result["interview_id"].as_str()), (Some("INTERVIEW_RESULT"), Some("i1")));
+        assert_eq!(result["results"][0]["response"], "1 2 3");
+        connection.abort();
+    }
 
-    let config_str = match std::fs::read_to_string(config_path) {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Failed to read config file {:?}: {}", config_path, e);
-            error!("Create config.json with: clientId, apiSecret, nodes");
-            error!("  Each node requires: alias, inferenceUri, apiMode, region, capacity");
-            std::process::exit(1);
+    #[test]
+    fn validate_bounds_capacity_and_price() {
+        let validate = |capacity: u32, price: f64| {
+            let mut config = test_config(serde_json::json!([test_node("a", "http://127.0.0.1:1")]));
+            config.nodes[0].capacity = capacity;
+            config.nodes[0].price_per_thousand_tokens = price;
+            config.validate()
+        };
+        assert!(validate(0, 0.0).is_ok());
+        assert!(validate(MAX_NODE_CAPACITY, 12.5).is_ok());
+        let error = validate(MAX_NODE_CAPACITY + 1, 0.0).unwrap_err();
+        assert!(error.contains("capacity 1025 is above the maximum of 1024"), "{}", error);
+        for price in [-0.01, f64::NAN, f64::INFINITY] {
+            let error = validate(1, price).unwrap_err();
+            assert!(error.contains("pricePerThousandTokens must be a finite, non-negative number"), "{}", error);
         }
-    };
+    }
 
-    let config: Config = match serde_json::from_str(&config_str) {
-        Ok(c) => c,
-        Err(e) => {
-            error!("Failed to parse config: {}", e);
-            std::process::exit(1);
+    #[tokio::test]
+    async fn requests_to_a_zero_capacity_node_are_rejected_at_once() {
+        let (url, requests) = mock_backend(|_| Reply::json(serde_json::json!({"data": [{"id": "m"}]}))).await;
+        let mut node = test_node("a", &url);
+        node["capacity"] = 0.into();
+        let config = test_config(serde_json::json!([node]));
+        let (mut server, connection) = connect_daemon(config.clone(), Arc::new(DaemonState::new(&config))).await;
+        send_message(&mut server, serde_json::json!({"type": "AUTH_SUCCESS", "operator_id": "op", "node_id": null, "message": "welcome"})).await;
+        let registration = next_message(&mut server).await;
+        assert_eq!((registration["type"].as_str(), registration["capacity"].as_u64()), (Some("REGISTER_NODE"), Some(0)));
+
+        let started = std::time::Instant::now();
+        for id in ["r1", "r2"] {
+            send_message(&mut server, serde_json::json!({
+                "type": "INFERENCE_REQUEST",
+                "request_id": id,
+                "payload": {"model": "m", "messages": [{"role": "user", "content": "hi"}]},
+            }))
+            .await;
         }
-    };
+        for id in ["r1", "r2"] {
+            let reply = next_message(&mut server).await;
+            assert_eq!((reply["request_id"].as_str(), reply["code"].as_str()), (Some(id), Some("NO_CAPACITY")));
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        // Only model discovery reached the backend.
+        assert!(requests.lock().unwrap().iter().all(|r| r.head.starts_with("GET")));
+        connection.abort();
+    }
 
-    info!("Operator ID: {}", config.client_id);
-    info!("Nodes configured: {}", config.nodes.len());
-    for node in &config.nodes {
-        info!("  - {} | {} | {} | capacity: {}", 
-            node.alias, node.inference_uri, node.api_mode, node.capacity);
+    #[tokio::test]
+    async fn identical_concurrent_requests_share_one_backend_call() {
+        let (url, requests) = mock_backend(|request| {
+            if request.head.starts_with("GET") {
+                return Reply::json(serde_json::json!({"data": [{"id": "m"}]}));
+            }
+            let mut reply = Reply::json(openai_completion("shared"));
+            reply.parts[0].0 = Duration::from_millis(300);
+            reply
+        })
+        .await;
+        let mut config = test_config(serde_json::json!([test_node("a", &url)]));
+        config.coalesce.enabled = true;
+        let (mut server, connection) = connect_daemon(config.clone(), Arc::new(DaemonState::new(&config))).await;
+        send_message(&mut server, serde_json::json!({"type": "AUTH_SUCCESS", "operator_id": "op", "node_id": null, "message": "welcome"})).await;
+        assert_eq!(next_message(&mut server).await["type"], "REGISTER_NODE");
+        for id in ["r1", "r2"] {
+            send_message(&mut server, serde_json::json!({
+                "type": "INFERENCE_REQUEST",
+                "request_id": id,
+                "payload": {"model": "m", "messages": [{"role": "user", "content": "hi"}], "temperature": 0.2},
+            }))
+            .await;
+        }
+
+        let mut replies = [next_message(&mut server).await, next_message(&mut server).await];
+        replies.sort_by_key(|r| r["request_id"].as_str().unwrap().to_string());
+        for (reply, id) in replies.iter().zip(["r1", "r2"]) {
+            assert_eq!((reply["type"].as_str(), reply["request_id"].as_str()), (Some("INFERENCE_RESPONSE"), Some(id)));
+            assert_eq!(reply["result"]["choices"][0]["message"]["content"], "shared");
+        }
+        assert_eq!(replies.iter().filter(|r| r["result"]["coalesced"] == true).count(), 1);
+        assert_eq!(requests.lock().unwrap().iter().filter(|r| r.head.starts_with("POST")).count(), 1);
+        connection.abort();
     }
-    
-    if config.nodes.is_empty() {
-        error!("No nodes configured! Add at least one node to the 'nodes' array.");
-        std::process::exit(1);
+
+    #[test]
+    fn only_unanswered_registrations_are_resent() {
+        let nodes = ["sent", "warming", "rejected", "acked"].map(|alias| test_node(alias, "http://127.0.0.1:1"));
+        let config = test_config(serde_json::json!(nodes));
+        let daemon = DaemonState::new(&config);
+        {
+            let mut states = daemon.nodes.lock().unwrap();
+            for alias in ["sent", "rejected", "acked"] {
+                states.get_mut(alias).unwrap().awaiting_ack = true;
+            }
+            states.get_mut("warming").unwrap().warming = true;
+        }
+        daemon.mark_rejected("rejected", "bad price");
+        daemon.mark_registered("acked");
+        let aliases = |paused: &std::collections::HashSet<String>| -> Vec<String> {
+            daemon.unacknowledged_registrations(&config, paused).into_iter().map(|r| r.alias).collect()
+        };
+        assert_eq!(aliases(&Default::default()), ["sent"]);
+        assert!(aliases(&["sent".to_string()].into()).is_empty());
     }
 
-    ctrlc::set_handler(move || {
-        info!("Shutdown signal received");
-        RUNNING.store(false, Ordering::SeqCst);
-    })
-    .expect("Error setting Ctrl-C handler");
+    /// Connects a daemon with a wallet and one node, and reads what the
+    /// first `AUTH_SUCCESS` makes it send.
+    async fn authenticated_with_wallet() -> (ServerSocket, tokio::task::JoinHandle<()>) {
+        let (url, _) = mock_backend(|_| Reply::json(serde_json::json!({"data": [{"id": "m"}]}))).await;
+        let mut config = test_config(serde_json::json!([test_node("a", &url)]));
+        config.payout_address = Some("0xabc".to_string());
+        let (mut server, connection) = connect_daemon(config.clone(), Arc::new(DaemonState::new(&config))).await;
+        send_message(&mut server, serde_json::json!({"type": "AUTH_SUCCESS", "operator_id": "op", "node_id": null, "message": "welcome"})).await;
+        assert_eq!(next_message(&mut server).await["type"], "UPDATE_WALLET");
+        assert_eq!(next_message(&mut server).await["type"], "REGISTER_NODE");
+        (server, connection)
+    }
 
-    info!("Concurrent inference threads: {}", args.threads);
-    
-    while RUNNING.load(Ordering::SeqCst) {
-        match run_connection(&config, args.threads).await {
-            Ok(_) => {
-                if RUNNING.load(Ordering::SeqCst) {
-                    info!("Reconnecting in {}s...", config.reconnect_delay_secs);
-                    tokio::time::sleep(Duration::from_secs(config.reconnect_delay_secs)).await;
-                }
-            }
-            Err(e) => {
-                error!("Connection error: {}", e);
-                if RUNNING.load(Ordering::SeqCst) {
-                    info!("Reconnecting in {}s...", config.reconnect_delay_secs);
-                    tokio::time::sleep(Duration::from_secs(config.reconnect_delay_secs)).await;
-                }
+    async fn repeat_auth_and_ping(server: &mut ServerSocket) {
+        send_message(server, serde_json::json!({"type": "AUTH_SUCCESS", "operator_id": "op", "node_id": null, "message": "welcome"})).await;
+        send_message(server, serde_json::json!({"type": "PING"})).await;
+    }
+
+    #[tokio::test]
+    async fn repeated_auth_success_resends_nothing_once_acknowledged() {
+        let (mut server, connection) = authenticated_with_wallet().await;
+        send_message(&mut server, serde_json::json!({"type": "REGISTER_NODE_ACK", "node_id": "n1", "alias": "a", "models": ["m"], "created": true, "message": "ok"})).await;
+        repeat_auth_and_ping(&mut server).await;
+        assert_eq!(next_message(&mut server).await["type"], "PONG");
+        connection.abort();
+    }
+
+    #[tokio::test]
+    async fn repeated_auth_success_resends_an_unanswered_registration_once() {
+        let (mut server, connection) = authenticated_with_wallet().await;
+        repeat_auth_and_ping(&mut server).await;
+        let resent = next_message(&mut server).await;
+        assert_eq!((resent["type"].as_str(), resent["alias"].as_str()), (Some("REGISTER_NODE"), Some("a")));
+        assert_eq!(next_message(&mut server).await["type"], "PONG");
+        send_message(&mut server, serde_json::json!({"type": "PING"})).await;
+        assert_eq!(next_message(&mut server).await["type"], "PONG");
+        connection.abort();
+    }
+
+    #[tokio::test]
+    async fn ordered_responses_hold_rejections_behind_earlier_requests() {
+        let (url, _) = mock_backend(|request| {
+            if request.head.starts_with("GET") {
+                return Reply::json(serde_json::json!({"data": [{"id": "m"}]}));
             }
+            let mut reply = Reply::json(openai_completion("slow"));
+            reply.parts[0].0 = Duration::from_millis(300);
+            reply
+        })
+        .await;
+        let mut node = test_node("a", &url);
+        node["accept"] = serde_json::json!({"models": ["m"]});
+        let mut config = test_config(serde_json::json!([node]));
+        config.ordered_responses = true;
+        let (mut server, connection) = connect_daemon(config.clone(), Arc::new(DaemonState::new(&config))).await;
+        send_message(&mut server, serde_json::json!({"type": "AUTH_SUCCESS", "operator_id": "op", "node_id": null, "message": "welcome"})).await;
+        assert_eq!(next_message(&mut server).await["type"], "REGISTER_NODE");
+        for (id, model) in [("r1", "m"), ("r2", "other")] {
+            send_message(&mut server, serde_json::json!({
+                "type": "INFERENCE_REQUEST",
+                "request_id": id,
+                "payload": {"model": model, "messages": [{"role": "user", "content": "hi"}]},
+            }))
+            .await;
         }
-    }
 
-    info!("Shutdown complete. Total requests: {}", TOTAL_REQUESTS.load(Ordering::SeqCst));
+        let first = next_message(&mut server).await;
+        assert_eq!((first["type"].as_str(), first["request_id"].as_str()), (Some("INFERENCE_RESPONSE"), Some("r1")));
+        let second = next_message(&mut server).await;
+        assert_eq!((second["request_id"].as_str(), second["code"].as_str()), (Some("r2"), Some("NOT_ACCEPTED")));
+        connection.abort();
+    }
 }